@@ -0,0 +1,250 @@
+use nih_plug::nih_debug_assert;
+
+use crate::consts::{MAX_EG_LEVEL, MIN_EG_LEVEL, SHUTDOWN_TIME_MSEC};
+use crate::linear_eg::{EGParameters, EnvelopeGenerator, EnvelopeState};
+
+/// Represents an analog style envelope generator. Every segment follows the charge or discharge
+/// curve of an RC circuit, like the envelope generators of analog synths do.
+/// It is modeled on the `AnalogEG` in the book "Designing Software Synthesizer Plugins in C++:
+/// 2nd Edition" by Will Pirkle.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AnalogEG {
+    state: EnvelopeState,
+    output_value: f32,
+    // The one pole filter of the current segment
+    coefficient: f32,
+    offset: f32,
+    shutdown_increment: f32,
+}
+
+/// Converts the curvature of a segment into the target overshoot (TCO) of the RC curve.
+/// The segment heads towards a target that is `tco` past its end level, and stops when it reaches
+/// the end level. A large overshoot only uses the start of the curve, which is nearly straight,
+/// while a small one uses almost all of it.
+///
+/// Parameters:
+/// - `curve` = the curvature, from 0 (almost straight) to 1 (strongly curved)
+fn calc_tco(curve: f32) -> f32 {
+    10.0_f32.powf(6.0f32.mul_add(-curve.clamp(0.0, 1.0), 2.0))
+}
+
+/// Calculates the coefficient of the one pole filter that traverses the full output range in
+/// `time_ms` milliseconds.
+fn calc_coefficient(time_ms: f32, tco: f32, sample_rate: f32) -> f32 {
+    let samples = sample_rate * time_ms / 1000.0;
+    // do a zero check
+    if samples <= 0.0 {
+        return 0.0;
+    }
+    (-((1.0 + tco) / tco).ln() / samples).exp()
+}
+
+impl AnalogEG {
+    fn start_attack(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        let tco = calc_tco(parameters.attack_curve);
+        self.coefficient = calc_coefficient(parameters.attack_time_msec, tco, sample_rate);
+        self.offset = (MAX_EG_LEVEL + tco) * (1.0 - self.coefficient);
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn start_decay(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        let tco = calc_tco(parameters.decay_curve);
+        self.coefficient = calc_coefficient(parameters.decay_time_msec, tco, sample_rate);
+        self.offset = (parameters.sustain_level - tco) * (1.0 - self.coefficient);
+        self.state = EnvelopeState::Decay;
+    }
+
+    fn start_release(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        let tco = calc_tco(parameters.release_curve);
+        self.coefficient = calc_coefficient(parameters.release_time_msec, tco, sample_rate);
+        self.offset = (MIN_EG_LEVEL - tco) * (1.0 - self.coefficient);
+        self.state = EnvelopeState::Release;
+    }
+
+    fn next_value(&self) -> f32 {
+        self.output_value.mul_add(self.coefficient, self.offset)
+    }
+}
+
+impl EnvelopeGenerator for AnalogEG {
+    /// Creates a new instance of the analog envelope generator.
+    fn new() -> Self {
+        Self {
+            state: EnvelopeState::Off,
+            output_value: 0.0,
+            coefficient: 0.0,
+            offset: 0.0,
+            shutdown_increment: 0.0,
+        }
+    }
+
+    /// Resets the analog envelope generator with the given parameters.
+    fn reset(&mut self, parameters: &EGParameters) {
+        self.output_value = parameters.start_level;
+        self.state = EnvelopeState::Off;
+    }
+
+    /// Updates the analog envelope generator with the given parameters.
+    fn update(&mut self, _parameters: &EGParameters) {
+        // The coefficients are calculated at the start of every segment.
+    }
+
+    /// Renders the output of the analog envelope generator into `output`, one value for every
+    /// sample.
+    fn render(&mut self, parameters: &EGParameters, output: &mut [f32], sample_rate: f32) {
+        for output_value in output {
+            match self.state {
+                EnvelopeState::Off => {
+                    self.output_value = parameters.start_level;
+                }
+                EnvelopeState::Attack => {
+                    self.output_value = self.next_value();
+                    if self.output_value >= MAX_EG_LEVEL {
+                        self.output_value = MAX_EG_LEVEL;
+                        self.start_decay(parameters, sample_rate);
+                    }
+                }
+                EnvelopeState::Decay => {
+                    self.output_value = self.next_value();
+                    if self.output_value <= parameters.sustain_level {
+                        self.output_value = parameters.sustain_level;
+                        self.state = EnvelopeState::Sustain;
+                    }
+                }
+                EnvelopeState::Sustain => {
+                    self.output_value = parameters.sustain_level;
+                }
+                EnvelopeState::Release => {
+                    self.output_value = self.next_value();
+                    if self.output_value <= MIN_EG_LEVEL {
+                        self.output_value = MIN_EG_LEVEL;
+                        self.state = EnvelopeState::Off;
+                    }
+                }
+                EnvelopeState::Shutdown => {
+                    self.output_value += self.shutdown_increment;
+                    if self.output_value <= MIN_EG_LEVEL {
+                        self.output_value = MIN_EG_LEVEL;
+                        self.state = EnvelopeState::Off;
+                    }
+                }
+            }
+            *output_value = self.output_value;
+        }
+    }
+
+    /// Notifies the analog envelope generator that a note has been turned off. The release starts
+    /// from the current output, wherever the envelope is.
+    fn note_off(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        if self.output_value > MIN_EG_LEVEL {
+            self.start_release(parameters, sample_rate);
+        } else {
+            self.state = EnvelopeState::Off;
+        }
+    }
+
+    /// Notifies the analog envelope generator that a note has been turned on.
+    fn note_on(&mut self, parameters: &EGParameters, _note: u8, sample_rate: f32) {
+        self.output_value = parameters.start_level;
+        self.start_attack(parameters, sample_rate);
+    }
+
+    fn shutdown(&mut self, _parameters: &EGParameters, sample_rate: f32) {
+        self.shutdown_increment = -(1000.0 * self.output_value) / SHUTDOWN_TIME_MSEC / sample_rate;
+        nih_debug_assert!(self.shutdown_increment <= 0.0);
+        self.state = EnvelopeState::Shutdown;
+    }
+
+    fn is_playing(&self) -> bool {
+        self.state != EnvelopeState::Off
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn parameters() -> EGParameters {
+        EGParameters {
+            attack_time_msec: 100.0,
+            decay_time_msec: 100.0,
+            sustain_level: 0.5,
+            release_time_msec: 100.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_calc_tco() {
+        assert_relative_eq!(calc_tco(0.0), 100.0);
+        assert_relative_eq!(calc_tco(0.5), 0.1, epsilon = 1e-6);
+        // The curvature is clamped
+        assert_relative_eq!(calc_tco(2.0), calc_tco(1.0));
+    }
+
+    #[test]
+    fn test_attack_reaches_peak_in_attack_time() {
+        let parameters = parameters();
+        let mut eg = AnalogEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        // 100 ms at 1000 Hz is 100 samples
+        let mut output = [0.0; 100];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(output.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(output[98] < MAX_EG_LEVEL);
+        assert_relative_eq!(output[99], MAX_EG_LEVEL, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn test_attack_curvature() {
+        // A curved attack rises faster at the start, so it is higher halfway through
+        let mut straight = parameters();
+        straight.attack_curve = 0.0;
+        let mut curved = parameters();
+        curved.attack_curve = 1.0;
+        let mut halfway = [0.0; 2];
+        for (parameters, halfway_value) in [straight, curved].iter().zip(&mut halfway) {
+            let mut eg = AnalogEG::new();
+            eg.note_on(parameters, 60, SAMPLE_RATE);
+            let mut output = [0.0; 50];
+            eg.render(parameters, &mut output, SAMPLE_RATE);
+            *halfway_value = output[49];
+        }
+        assert_relative_eq!(halfway[0], 0.5, epsilon = 0.01);
+        assert!(halfway[1] > 0.9);
+    }
+
+    #[test]
+    fn test_decay_and_release() {
+        let parameters = parameters();
+        let mut eg = AnalogEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        let mut output = [0.0; 300];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert_eq!(eg.state, EnvelopeState::Sustain);
+        assert_relative_eq!(output[299], 0.5);
+        eg.note_off(&parameters, SAMPLE_RATE);
+        assert_eq!(eg.state, EnvelopeState::Release);
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(!eg.is_playing());
+        assert_relative_eq!(output[299], MIN_EG_LEVEL);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let parameters = parameters();
+        let mut eg = AnalogEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        let mut output = [0.0; 10];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        eg.shutdown(&parameters, SAMPLE_RATE);
+        assert_eq!(eg.state, EnvelopeState::Shutdown);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let mut output = [0.0; (SHUTDOWN_TIME_MSEC * SAMPLE_RATE / 1000.0) as usize + 1];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(!eg.is_playing());
+    }
+}
@@ -1,5 +1,8 @@
 pub const MAX_EG_LEVEL: f32 = 1.0;
 pub const MIN_EG_LEVEL: f32 = 0.0;
 pub const SHUTDOWN_TIME_MSEC: f32 = 2.0;
-pub const TABLE_SIZE: usize = 1024;
+pub const MAX_TABLE_SIZE: usize = 16384;
+pub const MAX_CHANNELS: usize = 2;
 pub const MAX_VOICES: usize = 16;
+pub const MAX_UNISON: usize = 8;
+pub const NUM_OPERATORS: usize = 4;
@@ -0,0 +1,1329 @@
+use nih_plug::{nih_warn, prelude::SysExMessage};
+use std::fmt;
+
+use crate::consts::NUM_OPERATORS;
+use crate::dx_eg::scale_level;
+use crate::fm_algorithm::FmAlgorithm;
+use crate::fm_core::{coarse_ratio, VelocityCurve};
+use crate::fm_operator::feedback_depth;
+use crate::key_scaling::{KeyScaling, KeyScalingCurve};
+use crate::lfo::{LfoParameters, LfoScope, LfoWaveform};
+use crate::linear_eg::{EGMode, EGParameters};
+use crate::sysex_dump::SysExDump;
+use crate::voice_utils::{OperatorParameters, Parameters};
+
+/// The number of operators of a DX7 voice
+pub const DX7_OPERATORS: usize = 6;
+/// The number of voices in a bank
+pub const BANK_VOICES: usize = 32;
+/// The size of a 32 voice bank dump, including the system exclusive header, checksum and end byte
+pub const BANK_SIZE: usize = 4104;
+/// The size of the voice data of a bank, 32 voices in the packed format
+pub const BANK_DATA_SIZE: usize = BANK_VOICES * PACKED_VOICE_SIZE;
+/// The size of a voice in the unpacked format of a single voice dump
+pub const VOICE_SIZE: usize = 155;
+/// The size of a single voice dump, including the system exclusive header, checksum and end byte
+pub const VOICE_DUMP_SIZE: usize = 163;
+/// The size of an operator in the unpacked format of a single voice dump
+const UNPACKED_OPERATOR_SIZE: usize = 21;
+/// The length of the name of a voice
+const VOICE_NAME_LENGTH: usize = 10;
+/// The size of a voice in the packed format of a bank
+const PACKED_VOICE_SIZE: usize = 128;
+/// The size of an operator in the packed format of a bank
+const PACKED_OPERATOR_SIZE: usize = 17;
+/// The start of a 32 voice bank dump: system exclusive, Yamaha, channel 1, format 9, 4096 bytes
+const BANK_HEADER: [u8; 6] = [0xF0, 0x43, 0x00, 0x09, 0x20, 0x00];
+/// The start of a single voice dump: system exclusive, Yamaha, channel 1, format 0, 155 bytes
+const VOICE_HEADER: [u8; 6] = [0xF0, 0x43, 0x00, 0x00, 0x01, 0x1B];
+/// The size of a parameter change message
+const PARAMETER_CHANGE_SIZE: usize = 7;
+/// The parameter number that turns operators on and off, which is not part of a voice dump
+const OPERATOR_ON_OFF_PARAMETER: u16 = 155;
+/// How much an operator at output level 99 phase modulates another operator, in cycles. This is
+/// about 4π radians, the modulation index the DX7 is known for.
+const MAX_MODULATION_DEPTH: f32 = 2.0;
+/// How much a step of DX7 detune shifts an operator, in cents. The real step size shrinks as the
+/// pitch goes up; this is its size in the middle of the keyboard.
+const DETUNE_STEP_CENTS: f32 = 1.0;
+/// The step of the rate of the DX7 LFO, in Hz. The speed settings are converted to a number of
+/// steps the way the DX7 does, which runs from about 0.06 Hz at speed 0 to 24 Hz at speed 99.
+const LFO_RATE_STEP_HZ: f32 = 25_190_424.0 / 4_294_967_296.0;
+/// The time of the LFO delay and fade in, in milliseconds, is this divided by their steps. Their
+/// counters run to 2^31, half of the 2^32 of a cycle of the LFO.
+const LFO_DELAY_STEP_MSEC: f32 = 500.0 / LFO_RATE_STEP_HZ;
+/// The share of the pitch modulation depth that reaches the pitch at each pitch modulation
+/// sensitivity (0-7), out of 255.
+const PITCH_MOD_SENSITIVITY: [u8; 8] = [0, 10, 20, 33, 55, 92, 153, 255];
+/// The share of the amplitude modulation depth that reaches an operator at each amplitude
+/// modulation sensitivity (0-3), out of 255.
+const AMP_MOD_SENSITIVITY: [u8; 4] = [0, 66, 109, 255];
+/// The vibrato at the deepest pitch modulation and the highest sensitivity, in semitones
+const MAX_LFO_PITCH_DEPTH: f32 = 12.0;
+
+/// The reasons a bank can't be read.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Dx7Error {
+    /// The dump is not `BANK_SIZE` bytes long
+    InvalidLength(usize),
+    /// The dump does not start with the header of a Yamaha 32 voice bank
+    InvalidHeader,
+    /// The checksum of the voice data does not match the checksum in the dump
+    InvalidChecksum { expected: u8, found: u8 },
+    /// The dump does not end with the end of exclusive byte
+    MissingEnd,
+}
+
+impl fmt::Display for Dx7Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(length) => write!(
+                f,
+                "a DX7 bank is {BANK_SIZE} bytes long, but this dump is {length} bytes long"
+            ),
+            Self::InvalidHeader => write!(f, "the dump is not a DX7 32 voice bank"),
+            Self::InvalidChecksum { expected, found } => write!(
+                f,
+                "the checksum of the bank is {found:#04x}, but the voice data adds up to {expected:#04x}"
+            ),
+            Self::MissingEnd => write!(f, "the dump does not end with the end of exclusive byte"),
+        }
+    }
+}
+
+impl std::error::Error for Dx7Error {}
+
+/// The parts of a DX7 voice that don't map exactly onto the parameters of the synth.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Approximation {
+    /// Only four of the six operators fit, so an operator that can be heard was dropped.
+    /// Operators are numbered 1-6 like on the DX7.
+    DroppedOperator { operator: usize, output_level: u8 },
+    /// The feedback loop runs through several operators, so it is replaced with the self
+    /// modulation of one of them
+    FeedbackLoop { from: usize, to: usize },
+    /// A setting that the synth does not have yet. `operator` is `None` for voice settings.
+    Ignored {
+        operator: Option<usize>,
+        setting: &'static str,
+    },
+}
+
+impl fmt::Display for Approximation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DroppedOperator {
+                operator,
+                output_level,
+            } => write!(
+                f,
+                "operator {operator} (output level {output_level}) was dropped to fit four operators"
+            ),
+            Self::FeedbackLoop { from, to } => write!(
+                f,
+                "the feedback from operator {from} to operator {to} is approximated by self modulation of operator {to}"
+            ),
+            Self::Ignored {
+                operator: Some(operator),
+                setting,
+            } => write!(f, "the {setting} of operator {operator} is ignored"),
+            Self::Ignored {
+                operator: None,
+                setting,
+            } => write!(f, "the {setting} of the voice is ignored"),
+        }
+    }
+}
+
+/// The settings of one DX7 operator. All values are in the ranges of the DX7.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Dx7Operator {
+    pub rates: [u8; 4],
+    pub levels: [u8; 4],
+    pub level_scaling_breakpoint: u8,
+    pub level_scaling_left_depth: u8,
+    pub level_scaling_right_depth: u8,
+    pub level_scaling_left_curve: u8,
+    pub level_scaling_right_curve: u8,
+    pub rate_scaling: u8,
+    pub amp_mod_sensitivity: u8,
+    pub velocity_sensitivity: u8,
+    pub output_level: u8,
+    pub fixed_frequency: bool,
+    pub coarse: u8,
+    pub fine: u8,
+    /// 0-14, where 7 is in tune
+    pub detune: u8,
+}
+
+/// The settings of a DX7 voice. All values are in the ranges of the DX7.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Dx7Voice {
+    /// The name in ASCII, padded with spaces
+    pub name: [u8; VOICE_NAME_LENGTH],
+    /// Operators 1-6
+    pub operators: [Dx7Operator; DX7_OPERATORS],
+    pub pitch_eg_rates: [u8; 4],
+    pub pitch_eg_levels: [u8; 4],
+    /// 0-31 for algorithms 1-32
+    pub algorithm: u8,
+    pub feedback: u8,
+    pub oscillator_sync: bool,
+    pub lfo_speed: u8,
+    pub lfo_delay: u8,
+    pub lfo_pitch_mod_depth: u8,
+    pub lfo_amp_mod_depth: u8,
+    pub lfo_sync: bool,
+    pub lfo_waveform: u8,
+    pub pitch_mod_sensitivity: u8,
+    /// 0-48, where 24 is C3
+    pub transpose: u8,
+}
+
+/// The operator of the DX7's INIT VOICE, a sine at the pitch of the note
+impl Default for Dx7Operator {
+    fn default() -> Self {
+        Self {
+            rates: [99, 99, 99, 99],
+            levels: [99, 99, 99, 0],
+            level_scaling_breakpoint: 39,
+            level_scaling_left_depth: 0,
+            level_scaling_right_depth: 0,
+            level_scaling_left_curve: 0,
+            level_scaling_right_curve: 0,
+            rate_scaling: 0,
+            amp_mod_sensitivity: 0,
+            velocity_sensitivity: 0,
+            output_level: 0,
+            fixed_frequency: false,
+            coarse: 1,
+            fine: 0,
+            detune: 7,
+        }
+    }
+}
+
+/// The DX7's INIT VOICE, where only operator 1 is heard
+impl Default for Dx7Voice {
+    fn default() -> Self {
+        let mut operators = [Dx7Operator::default(); DX7_OPERATORS];
+        operators[0].output_level = 99;
+        Self {
+            name: *b"INIT VOICE",
+            operators,
+            pitch_eg_rates: [99, 99, 99, 99],
+            pitch_eg_levels: [50, 50, 50, 50],
+            algorithm: 0,
+            feedback: 0,
+            oscillator_sync: true,
+            lfo_speed: 35,
+            lfo_delay: 0,
+            lfo_pitch_mod_depth: 0,
+            lfo_amp_mod_depth: 0,
+            lfo_sync: true,
+            lfo_waveform: 0,
+            pitch_mod_sensitivity: 3,
+            transpose: 24,
+        }
+    }
+}
+
+/// The routing of a DX7 algorithm. Operators are numbered 1-6.
+struct Dx7Algorithm {
+    /// The (modulator, carrier) pairs. The modulator always has the higher number.
+    routes: &'static [(usize, usize)],
+    carriers: &'static [usize],
+    /// The (from, to) operators of the feedback loop
+    feedback: (usize, usize),
+}
+
+const fn routing(
+    routes: &'static [(usize, usize)],
+    carriers: &'static [usize],
+    feedback: (usize, usize),
+) -> Dx7Algorithm {
+    Dx7Algorithm {
+        routes,
+        carriers,
+        feedback,
+    }
+}
+
+/// The 32 algorithms of the DX7
+const DX7_ALGORITHMS: [Dx7Algorithm; 32] = [
+    routing(&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], (6, 6)),
+    routing(&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], (2, 2)),
+    routing(&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], (6, 6)),
+    routing(&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], (4, 6)),
+    routing(&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], (6, 6)),
+    routing(&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], (5, 6)),
+    routing(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (6, 6)),
+    routing(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (4, 4)),
+    routing(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (2, 2)),
+    routing(&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], (3, 3)),
+    routing(&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], (6, 6)),
+    routing(&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], (2, 2)),
+    routing(&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], (6, 6)),
+    routing(&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], (6, 6)),
+    routing(&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], (2, 2)),
+    routing(&[(2, 1), (3, 1), (4, 3), (5, 1), (6, 5)], &[1], (6, 6)),
+    routing(&[(2, 1), (3, 1), (4, 3), (5, 1), (6, 5)], &[1], (2, 2)),
+    routing(&[(2, 1), (3, 1), (4, 1), (5, 4), (6, 5)], &[1], (3, 3)),
+    routing(&[(2, 1), (3, 2), (6, 4), (6, 5)], &[1, 4, 5], (6, 6)),
+    routing(&[(3, 1), (3, 2), (5, 4), (6, 4)], &[1, 2, 4], (3, 3)),
+    routing(&[(3, 1), (3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], (3, 3)),
+    routing(&[(2, 1), (6, 3), (6, 4), (6, 5)], &[1, 3, 4, 5], (6, 6)),
+    routing(&[(3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], (6, 6)),
+    routing(&[(6, 3), (6, 4), (6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+    routing(&[(6, 4), (6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+    routing(&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], (6, 6)),
+    routing(&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], (3, 3)),
+    routing(&[(2, 1), (4, 3), (5, 4)], &[1, 3, 6], (5, 5)),
+    routing(&[(4, 3), (6, 5)], &[1, 2, 3, 5], (6, 6)),
+    routing(&[(4, 3), (5, 4)], &[1, 2, 3, 6], (5, 5)),
+    routing(&[(6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+    routing(&[], &[1, 2, 3, 4, 5, 6], (6, 6)),
+];
+
+/// Checks a DX7 bank dump in the packed 4104 byte format, and returns the data of its 32 voices.
+///
+/// # Errors
+/// Returns an error when the dump is not a valid 32 voice bank.
+fn bank_data(dump: &[u8]) -> Result<&[u8; BANK_DATA_SIZE], Dx7Error> {
+    if dump.len() != BANK_SIZE {
+        return Err(Dx7Error::InvalidLength(dump.len()));
+    }
+    // The channel nibble of the third byte can be anything
+    if dump[0] != BANK_HEADER[0]
+        || dump[1] != BANK_HEADER[1]
+        || dump[2] & 0xF0 != BANK_HEADER[2]
+        || dump[3..6] != BANK_HEADER[3..6]
+    {
+        return Err(Dx7Error::InvalidHeader);
+    }
+    if dump[BANK_SIZE - 1] != 0xF7 {
+        return Err(Dx7Error::MissingEnd);
+    }
+    let data: &[u8; BANK_DATA_SIZE] = dump[BANK_HEADER.len()..BANK_HEADER.len() + BANK_DATA_SIZE]
+        .try_into()
+        .map_err(|_| Dx7Error::InvalidLength(dump.len()))?;
+    let expected = checksum(data);
+    let found = dump[BANK_SIZE - 2];
+    if expected != found {
+        return Err(Dx7Error::InvalidChecksum { expected, found });
+    }
+    Ok(data)
+}
+
+/// Reads voice `program` (0-31) from the voice data of a bank. Returns `None` when there is no
+/// such voice, or when `data` is not the data of a whole bank.
+pub fn bank_voice(data: &[u8], program: u8) -> Option<Dx7Voice> {
+    if data.len() != BANK_DATA_SIZE {
+        return None;
+    }
+    data.chunks_exact(PACKED_VOICE_SIZE)
+        .nth(usize::from(program))
+        .map(unpack_voice)
+}
+
+/// The checksum of DX7 voice data: the two's complement of the sum of the bytes, in 7 bits.
+pub fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+    sum.wrapping_neg() & 0x7F
+}
+
+/// Reads a voice in the packed format of a bank. The operators are stored from 6 to 1.
+fn unpack_voice(data: &[u8]) -> Dx7Voice {
+    let mut operators = [Dx7Operator::default(); DX7_OPERATORS];
+    for (operator, packed) in operators
+        .iter_mut()
+        .rev()
+        .zip(data.chunks_exact(PACKED_OPERATOR_SIZE))
+    {
+        *operator = unpack_operator(packed);
+    }
+    let global = &data[DX7_OPERATORS * PACKED_OPERATOR_SIZE..];
+    Dx7Voice {
+        name: std::array::from_fn(|index| global[16 + index] & 0x7F),
+        operators,
+        pitch_eg_rates: [global[0], global[1], global[2], global[3]],
+        pitch_eg_levels: [global[4], global[5], global[6], global[7]],
+        algorithm: global[8] & 0x1F,
+        feedback: global[9] & 0x07,
+        oscillator_sync: global[9] & 0x08 != 0,
+        lfo_speed: global[10],
+        lfo_delay: global[11],
+        lfo_pitch_mod_depth: global[12],
+        lfo_amp_mod_depth: global[13],
+        lfo_sync: global[14] & 0x01 != 0,
+        lfo_waveform: (global[14] >> 1) & 0x07,
+        pitch_mod_sensitivity: (global[14] >> 4) & 0x07,
+        transpose: global[15],
+    }
+}
+
+fn unpack_operator(data: &[u8]) -> Dx7Operator {
+    Dx7Operator {
+        rates: [data[0], data[1], data[2], data[3]],
+        levels: [data[4], data[5], data[6], data[7]],
+        level_scaling_breakpoint: data[8],
+        level_scaling_left_depth: data[9],
+        level_scaling_right_depth: data[10],
+        level_scaling_left_curve: data[11] & 0x03,
+        level_scaling_right_curve: (data[11] >> 2) & 0x03,
+        rate_scaling: data[12] & 0x07,
+        detune: (data[12] >> 3) & 0x0F,
+        amp_mod_sensitivity: data[13] & 0x03,
+        velocity_sensitivity: (data[13] >> 2) & 0x07,
+        output_level: data[14],
+        fixed_frequency: data[15] & 0x01 != 0,
+        coarse: (data[15] >> 1) & 0x1F,
+        fine: data[16],
+    }
+}
+
+/// Converts a DX7 output level (0-99) to a linear gain, where 99 is 1.0.
+fn output_level_gain(output_level: u8) -> f32 {
+    if output_level == 0 {
+        return 0.0;
+    }
+    let steps_below_full = scale_level(99) - scale_level(i32::from(output_level));
+    // Every step is 0.75 dB
+    #[allow(clippy::cast_precision_loss)]
+    (-(steps_below_full as f32) / 8.0).exp2()
+}
+
+/// Converts a DX7 LFO speed (0-99) to a rate in Hz.
+#[allow(clippy::cast_precision_loss)]
+fn lfo_rate_hz(speed: u8) -> f32 {
+    let steps = if speed == 0 {
+        1
+    } else {
+        (165 * u32::from(speed.min(99))) >> 6
+    };
+    // The rate grows faster at the top of the range
+    let steps = steps
+        * if steps < 160 {
+            11
+        } else {
+            11 + ((steps - 160) >> 4)
+        };
+    steps as f32 * LFO_RATE_STEP_HZ
+}
+
+/// Converts a DX7 LFO delay (0-99) to the time before the LFO starts and the time it takes to
+/// fade in, in milliseconds.
+#[allow(clippy::cast_precision_loss)]
+fn lfo_delay_msec(delay: u8) -> (f32, f32) {
+    if delay == 0 {
+        return (0.0, 0.0);
+    }
+    let inverse = u32::from(99 - delay.min(99));
+    let delay_steps = (16 + (inverse & 15)) << (1 + (inverse >> 4));
+    let fade_in_steps = (delay_steps & 0xFF80).max(0x80);
+    (
+        LFO_DELAY_STEP_MSEC / delay_steps as f32,
+        LFO_DELAY_STEP_MSEC / fade_in_steps as f32,
+    )
+}
+
+impl Dx7Operator {
+    /// The keyboard level scaling of the operator. The breakpoints of the DX7 start at A-1.
+    fn key_scaling(&self) -> KeyScaling {
+        KeyScaling {
+            breakpoint: self.level_scaling_breakpoint.min(99) + 21,
+            left_depth: i32::from(self.level_scaling_left_depth),
+            right_depth: i32::from(self.level_scaling_right_depth),
+            left_curve: KeyScalingCurve::from_dx7(self.level_scaling_left_curve),
+            right_curve: KeyScalingCurve::from_dx7(self.level_scaling_right_curve),
+        }
+    }
+
+    /// The frequency of the operator in fixed mode
+    fn fixed_frequency_hz(&self) -> f32 {
+        10.0_f32.powf(f32::from(self.coarse & 0x03) + f32::from(self.fine) / 100.0)
+    }
+
+    fn eg_params(&self) -> EGParameters {
+        EGParameters {
+            mode: EGMode::Dx,
+            rates: self.rates.map(i32::from),
+            levels: self.levels.map(i32::from),
+            rate_scaling: i32::from(self.rate_scaling),
+            ..Default::default()
+        }
+    }
+}
+
+impl Dx7Voice {
+    /// The name of the voice, without the padding
+    pub fn name(&self) -> String {
+        self.name
+            .iter()
+            .map(|byte| char::from(*byte))
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    /// Reads a voice in the unpacked format of a single voice dump, where every setting has its
+    /// own byte. The operators are stored from 6 to 1.
+    pub fn from_unpacked(data: &[u8; VOICE_SIZE]) -> Self {
+        let mut operators = [Dx7Operator::default(); DX7_OPERATORS];
+        for (operator, data) in operators
+            .iter_mut()
+            .rev()
+            .zip(data.chunks_exact(UNPACKED_OPERATOR_SIZE))
+        {
+            *operator = Dx7Operator {
+                rates: [data[0], data[1], data[2], data[3]],
+                levels: [data[4], data[5], data[6], data[7]],
+                level_scaling_breakpoint: data[8],
+                level_scaling_left_depth: data[9],
+                level_scaling_right_depth: data[10],
+                level_scaling_left_curve: data[11] & 0x03,
+                level_scaling_right_curve: data[12] & 0x03,
+                rate_scaling: data[13] & 0x07,
+                amp_mod_sensitivity: data[14] & 0x03,
+                velocity_sensitivity: data[15] & 0x07,
+                output_level: data[16],
+                fixed_frequency: data[17] & 0x01 != 0,
+                coarse: data[18] & 0x1F,
+                fine: data[19],
+                detune: data[20] & 0x0F,
+            };
+        }
+        let global = &data[DX7_OPERATORS * UNPACKED_OPERATOR_SIZE..];
+        Self {
+            name: std::array::from_fn(|index| global[19 + index] & 0x7F),
+            operators,
+            pitch_eg_rates: [global[0], global[1], global[2], global[3]],
+            pitch_eg_levels: [global[4], global[5], global[6], global[7]],
+            algorithm: global[8] & 0x1F,
+            feedback: global[9] & 0x07,
+            oscillator_sync: global[10] & 0x01 != 0,
+            lfo_speed: global[11],
+            lfo_delay: global[12],
+            lfo_pitch_mod_depth: global[13],
+            lfo_amp_mod_depth: global[14],
+            lfo_sync: global[15] & 0x01 != 0,
+            lfo_waveform: global[16] & 0x07,
+            pitch_mod_sensitivity: global[17] & 0x07,
+            transpose: global[18],
+        }
+    }
+
+    /// Writes the voice in the unpacked format of a single voice dump.
+    pub fn to_unpacked(self) -> [u8; VOICE_SIZE] {
+        let mut data = [0; VOICE_SIZE];
+        for (operator, data) in self
+            .operators
+            .iter()
+            .rev()
+            .zip(data.chunks_exact_mut(UNPACKED_OPERATOR_SIZE))
+        {
+            data[..4].copy_from_slice(&operator.rates);
+            data[4..8].copy_from_slice(&operator.levels);
+            data[8..].copy_from_slice(&[
+                operator.level_scaling_breakpoint,
+                operator.level_scaling_left_depth,
+                operator.level_scaling_right_depth,
+                operator.level_scaling_left_curve,
+                operator.level_scaling_right_curve,
+                operator.rate_scaling,
+                operator.amp_mod_sensitivity,
+                operator.velocity_sensitivity,
+                operator.output_level,
+                u8::from(operator.fixed_frequency),
+                operator.coarse,
+                operator.fine,
+                operator.detune,
+            ]);
+        }
+        let global = &mut data[DX7_OPERATORS * UNPACKED_OPERATOR_SIZE..];
+        global[..4].copy_from_slice(&self.pitch_eg_rates);
+        global[4..8].copy_from_slice(&self.pitch_eg_levels);
+        global[8..19].copy_from_slice(&[
+            self.algorithm,
+            self.feedback,
+            u8::from(self.oscillator_sync),
+            self.lfo_speed,
+            self.lfo_delay,
+            self.lfo_pitch_mod_depth,
+            self.lfo_amp_mod_depth,
+            u8::from(self.lfo_sync),
+            self.lfo_waveform,
+            self.pitch_mod_sensitivity,
+            self.transpose,
+        ]);
+        global[19..].copy_from_slice(&self.name);
+        data
+    }
+
+    /// Changes one setting of the voice. Parameters are numbered like the bytes of a single voice
+    /// dump. The operator on/off switch (parameter 155) is not part of the voice, and is ignored.
+    pub fn set_parameter(&mut self, parameter: u16, value: u8) {
+        let mut data = self.to_unpacked();
+        if let Some(byte) = data.get_mut(usize::from(parameter)) {
+            *byte = value & 0x7F;
+            *self = Self::from_unpacked(&data);
+        }
+    }
+
+    /// Converts the voice into the parameters of the synth without allocating, so it can be done
+    /// on the audio thread. Everything that could not be converted exactly is passed to
+    /// `approximate`.
+    ///
+    /// The synth has four operators, so operators that are silent are dropped first, followed by
+    /// the quietest ones. The kept operators go into A to D from the highest DX7 operator number
+    /// down, which keeps every modulator ahead of its carriers. The routing is set up with the
+    /// `Additive` algorithm and the modulation matrix, so every DX7 algorithm can be represented.
+    pub fn to_parameters(self, mut approximate: impl FnMut(Approximation)) -> Parameters {
+        let algorithm = &DX7_ALGORITHMS[usize::from(self.algorithm.min(31))];
+        let is_carrier = |operator: usize| algorithm.carriers.contains(&operator);
+
+        // Choose the operators to keep, numbered 1-6. Silent operators sort last.
+        let mut by_importance: [usize; DX7_OPERATORS] = std::array::from_fn(|index| index + 1);
+        by_importance.sort_unstable_by_key(|operator| {
+            (
+                std::cmp::Reverse(self.operators[operator - 1].output_level),
+                !is_carrier(*operator),
+                *operator,
+            )
+        });
+        let num_audible = self
+            .operators
+            .iter()
+            .filter(|operator| operator.output_level > 0)
+            .count();
+        for &operator in &by_importance[NUM_OPERATORS.min(num_audible)..num_audible] {
+            approximate(Approximation::DroppedOperator {
+                operator,
+                output_level: self.operators[operator - 1].output_level,
+            });
+        }
+        let kept = &mut by_importance[..NUM_OPERATORS.min(num_audible)];
+        kept.sort_unstable_by(|a, b| b.cmp(a));
+        let kept = &*kept;
+        let slot = |operator: usize| kept.iter().position(|kept| *kept == operator);
+
+        let mut parameters = Parameters::default();
+        parameters.fm_params.algorithm = FmAlgorithm::Additive;
+        // The operator envelopes shape the sound, so the envelope of the voice only has to stay
+        // open. Its release is slow enough to outlast the operators, which end the voice.
+        parameters.eg_params = EGParameters {
+            mode: EGMode::Dx,
+            rates: [99, 99, 99, 0],
+            levels: [99, 99, 99, 0],
+            ..Default::default()
+        };
+        parameters.fm_params.operators = [OperatorParameters {
+            coarse: 1.0,
+            ..Default::default()
+        }; NUM_OPERATORS];
+
+        for (operator_params, &operator) in parameters.fm_params.operators.iter_mut().zip(kept) {
+            let dx7_operator = &self.operators[operator - 1];
+            let gain = output_level_gain(dx7_operator.output_level);
+            operator_params.coarse = coarse_ratio(i32::from(dx7_operator.coarse));
+            operator_params.fine = f32::from(dx7_operator.fine) / 100.0;
+            operator_params.detune = (f32::from(dx7_operator.detune) - 7.0) * DETUNE_STEP_CENTS;
+            operator_params.fixed_frequency = dx7_operator
+                .fixed_frequency
+                .then(|| dx7_operator.fixed_frequency_hz());
+            operator_params.index = gain * MAX_MODULATION_DEPTH;
+            operator_params.mix = if is_carrier(operator) { gain } else { 0.0 };
+            operator_params.velocity_sensitivity =
+                f32::from(dx7_operator.velocity_sensitivity) / 7.0;
+            operator_params.velocity_curve = VelocityCurve::Exponential;
+            operator_params.key_scaling = dx7_operator.key_scaling();
+            operator_params.eg_params = dx7_operator.eg_params();
+            operator_params.amp_mod_sensitivity = f32::from(
+                AMP_MOD_SENSITIVITY[usize::from(dx7_operator.amp_mod_sensitivity.min(3))],
+            ) / 255.0;
+        }
+
+        for &(modulator, carrier) in algorithm.routes {
+            if let (Some(modulator_slot), Some(carrier_slot)) = (slot(modulator), slot(carrier)) {
+                let operator_params = &mut parameters.fm_params.operators[modulator_slot];
+                operator_params.modulation[carrier_slot] = operator_params.index;
+            }
+        }
+
+        let (from, to) = algorithm.feedback;
+        if let (Some(_), Some(to_slot)) = (slot(from), slot(to)) {
+            parameters.fm_params.operators[to_slot].feedback =
+                feedback_depth(i32::from(self.feedback));
+            if from != to && self.feedback > 0 {
+                approximate(Approximation::FeedbackLoop { from, to });
+            }
+        }
+
+        // The LFO of the DX7 is shared by all the voices, so it goes into the first LFO
+        parameters.lfos[0] = self.lfo_parameters();
+
+        self.ignored_voice_settings(&mut approximate);
+        parameters
+    }
+
+    /// The LFO of the voice. The pitch modulation sensitivity of the voice is part of the depth
+    /// of the vibrato, while the amplitude modulation sensitivity belongs to every operator.
+    fn lfo_parameters(&self) -> LfoParameters {
+        let (delay_msec, fade_in_msec) = lfo_delay_msec(self.lfo_delay);
+        let pitch_sensitivity =
+            f32::from(PITCH_MOD_SENSITIVITY[usize::from(self.pitch_mod_sensitivity.min(7))])
+                / 255.0;
+        LfoParameters {
+            scope: LfoScope::Global,
+            waveform: LfoWaveform::from_dx7(self.lfo_waveform),
+            rate_hz: lfo_rate_hz(self.lfo_speed),
+            delay_msec,
+            fade_in_msec,
+            key_retrigger: self.lfo_sync,
+            pitch_depth: MAX_LFO_PITCH_DEPTH
+                * pitch_sensitivity
+                * f32::from(self.lfo_pitch_mod_depth.min(99))
+                / 99.0,
+            amp_depth: f32::from(self.lfo_amp_mod_depth.min(99)) / 99.0,
+            ..Default::default()
+        }
+    }
+
+    fn ignored_voice_settings(&self, approximate: &mut impl FnMut(Approximation)) {
+        let mut ignore = |setting| {
+            approximate(Approximation::Ignored {
+                operator: None,
+                setting,
+            });
+        };
+        if self.pitch_eg_levels.iter().any(|level| *level != 50) {
+            ignore("pitch envelope");
+        }
+        if self.transpose != 24 {
+            ignore("transpose");
+        }
+        // The oscillators always restart with the note, like they do with oscillator key sync
+        if !self.oscillator_sync {
+            ignore("free running oscillators (oscillator key sync off)");
+        }
+    }
+}
+
+/// The system exclusive messages of the DX7 that the synth understands. These let hardware
+/// editors and librarians drive the synth. The data of the dumps is kept out of the message, see
+/// `SysExDump`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Dx7SysExMessage {
+    /// A single voice, in the unpacked format
+    VoiceDump { channel: u8, data: SysExDump },
+    /// A bank of 32 voices, in the packed format
+    BankDump { channel: u8, data: SysExDump },
+    /// A change of one setting of the voice, numbered like the bytes of a voice dump
+    ParameterChange {
+        channel: u8,
+        parameter: u16,
+        value: u8,
+    },
+}
+
+impl Dx7SysExMessage {
+    /// Writes a voice or bank dump with `header` and `data` into `buffer`, and returns its size.
+    fn dump_to_buffer(buffer: &mut [u8], header: &[u8], channel: u8, data: &[u8]) -> usize {
+        let data_end = header.len() + data.len();
+        buffer[..header.len()].copy_from_slice(header);
+        buffer[2] |= channel & 0x0F;
+        buffer[header.len()..data_end].copy_from_slice(data);
+        buffer[data_end] = checksum(data);
+        buffer[data_end + 1] = 0xF7;
+        data_end + 2
+    }
+}
+
+impl SysExMessage for Dx7SysExMessage {
+    type Buffer = [u8; BANK_SIZE];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        // The channel is in the low nibble of the third byte, the high nibble is the message
+        let (&[0xF0, 0x43, status, ..], Some(&0xF7)) = (buffer, buffer.last()) else {
+            return None;
+        };
+        let channel = status & 0x0F;
+        match (status >> 4, buffer.len()) {
+            (0x0, VOICE_DUMP_SIZE) if buffer[3..6] == VOICE_HEADER[3..6] => {
+                let data = &buffer[VOICE_HEADER.len()..VOICE_HEADER.len() + VOICE_SIZE];
+                if checksum(data) != buffer[VOICE_DUMP_SIZE - 2] {
+                    return None;
+                }
+                SysExDump::store(data).map(|data| Self::VoiceDump { channel, data })
+            }
+            (0x0, _) if buffer.get(3) == Some(&BANK_HEADER[3]) => match bank_data(buffer) {
+                Ok(data) => SysExDump::store(data).map(|data| Self::BankDump { channel, data }),
+                Err(error) => {
+                    nih_warn!("Ignoring a DX7 bank: {error}");
+                    None
+                }
+            },
+            // The parameter group is in bits 2-6 of the fourth byte, voice parameters are group
+            // 0. Bits 0-1 are the high bits of the parameter number.
+            (0x1, PARAMETER_CHANGE_SIZE) if buffer[3] & 0x7C == 0 => {
+                let parameter = (u16::from(buffer[3] & 0x03) << 7) | u16::from(buffer[4]);
+                (parameter <= OPERATOR_ON_OFF_PARAMETER).then_some(Self::ParameterChange {
+                    channel,
+                    parameter,
+                    value: buffer[5],
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let mut buffer = [0; BANK_SIZE];
+        match self {
+            // A dump whose data is gone is not sent
+            Self::VoiceDump { channel, data } => {
+                let len = data
+                    .read(|data| Self::dump_to_buffer(&mut buffer, &VOICE_HEADER, channel, data))
+                    .unwrap_or(0);
+                (buffer, len)
+            }
+            Self::BankDump { channel, data } => {
+                let len = data
+                    .read(|data| Self::dump_to_buffer(&mut buffer, &BANK_HEADER, channel, data))
+                    .unwrap_or(0);
+                (buffer, len)
+            }
+            Self::ParameterChange {
+                channel,
+                parameter,
+                value,
+            } => {
+                #[allow(clippy::cast_possible_truncation)]
+                buffer[..PARAMETER_CHANGE_SIZE].copy_from_slice(&[
+                    0xF0,
+                    0x43,
+                    0x10 | (channel & 0x0F),
+                    (parameter >> 7) as u8 & 0x03,
+                    parameter as u8 & 0x7F,
+                    value & 0x7F,
+                    0xF7,
+                ]);
+                (buffer, PARAMETER_CHANGE_SIZE)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    /// The packed INIT VOICE of the DX7: only operator 1 is heard, as a sine at the note's pitch
+    #[rustfmt::skip]
+    const INIT_VOICE: [u8; PACKED_VOICE_SIZE] = [
+        // Operator 6
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 0, 2, 0,
+        // Operator 5
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 0, 2, 0,
+        // Operator 4
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 0, 2, 0,
+        // Operator 3
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 0, 2, 0,
+        // Operator 2
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 0, 2, 0,
+        // Operator 1
+        99, 99, 99, 99, 99, 99, 99, 0, 39, 0, 0, 0, 56, 0, 99, 2, 0,
+        // Pitch EG, algorithm, feedback, LFO and transpose
+        99, 99, 99, 99, 50, 50, 50, 50, 0, 8, 35, 0, 0, 0, 49, 24,
+        // Name
+        b'I', b'N', b'I', b'T', b' ', b'V', b'O', b'I', b'C', b'E',
+    ];
+
+    /// A voice packed by hand from the bank format in the DX7 manual, with different values in
+    /// the fields that share a byte. Operators 5 and 6 are silent.
+    #[rustfmt::skip]
+    const PACKED_VOICE: [u8; PACKED_VOICE_SIZE] = [
+        // Operator 6: rates, levels, breakpoint, depths, curves, detune and rate scaling,
+        // velocity and amp mod sensitivity, output level, coarse and fixed, fine
+        49, 99, 28, 68, 98, 98, 91, 0, 39, 54, 50, (1 << 2) | 2, (9 << 3) | 4, (3 << 2) | 2, 0,
+        1 << 1, 0,
+        // Operator 5
+        77, 36, 41, 71, 99, 98, 98, 0, 39, 0, 0, 0, (7 << 3) | 2, 2 << 2, 0, 1 << 1, 0,
+        // Operator 4: ratio 2 with fine 50, detune +3, output level 91
+        77, 36, 41, 71, 99, 98, 98, 0, 39, 0, 0, 0, (10 << 3) | 2, 2 << 2, 91, 2 << 1, 50,
+        // Operator 3: breakpoint C2, right depth 7, +LIN right curve, amp mod sensitivity 1
+        62, 51, 29, 71, 82, 95, 96, 0, 27, 0, 7, 3 << 2, (5 << 3) | 1, (7 << 2) | 1, 99, 1 << 1, 0,
+        // Operator 2: fixed at 10 Hz
+        72, 76, 99, 71, 99, 88, 96, 0, 39, 0, 14, 0, 7 << 3, 0, 86, (1 << 1) | 1, 0,
+        // Operator 1
+        62, 51, 29, 71, 82, 95, 96, 0, 39, 0, 0, 0, (7 << 3) | 2, 2 << 2, 99, 1 << 1, 0,
+        // Pitch EG, algorithm, oscillator sync and feedback 7, LFO speed, delay and depths,
+        // pitch mod sensitivity 3 with a square wave and no sync, transpose
+        84, 95, 95, 60, 50, 50, 50, 50, 4, 0x0F, 37, 20, 5, 0, (3 << 4) | (4 << 1), 24,
+        // Name
+        b'V', b'O', b'I', b'C', b'E', b' ', b'0', b'0', b' ', b' ',
+    ];
+
+    fn bank(voices: &[[u8; PACKED_VOICE_SIZE]]) -> Vec<u8> {
+        let mut dump = BANK_HEADER.to_vec();
+        for voice in voices.iter().cycle().take(BANK_VOICES) {
+            dump.extend_from_slice(voice);
+        }
+        dump.push(checksum(&dump[BANK_HEADER.len()..]));
+        dump.push(0xF7);
+        dump
+    }
+
+    /// The voices of a bank dump, read through its system exclusive message like the plugin does
+    fn parse_bank(dump: &[u8]) -> Vec<Dx7Voice> {
+        let Some(Dx7SysExMessage::BankDump { data, .. }) = Dx7SysExMessage::from_buffer(dump)
+        else {
+            return Vec::new();
+        };
+        data.read(|data| {
+            (0..32)
+                .filter_map(|program| bank_voice(data, program))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// A voice converted into the parameters of the synth, and everything that could not be
+    /// converted exactly
+    struct Patch {
+        parameters: Parameters,
+        approximations: Vec<Approximation>,
+    }
+
+    fn to_patch(voice: Dx7Voice) -> Patch {
+        let mut approximations = Vec::new();
+        let parameters = voice.to_parameters(|approximation| approximations.push(approximation));
+        Patch {
+            parameters,
+            approximations,
+        }
+    }
+
+    fn operator_bytes(voice: &mut [u8; PACKED_VOICE_SIZE], operator: usize) -> &mut [u8] {
+        let start = (DX7_OPERATORS - operator) * PACKED_OPERATOR_SIZE;
+        &mut voice[start..start + PACKED_OPERATOR_SIZE]
+    }
+
+    #[test]
+    fn test_parse_init_bank() {
+        let voices = parse_bank(&bank(&[INIT_VOICE]));
+        assert_eq!(voices.len(), BANK_VOICES);
+        let voice = &voices[0];
+        assert_eq!(voice.name(), "INIT VOICE");
+        assert_eq!(voice.algorithm, 0);
+        assert_eq!(voice.feedback, 0);
+        assert!(voice.oscillator_sync);
+        assert_eq!(voice.lfo_speed, 35);
+        assert!(voice.lfo_sync);
+        assert_eq!(voice.pitch_mod_sensitivity, 3);
+        assert_eq!(voice.transpose, 24);
+        assert_eq!(voice.operators[0].output_level, 99);
+        for operator in &voice.operators {
+            assert_eq!(operator.rates, [99, 99, 99, 99]);
+            assert_eq!(operator.levels, [99, 99, 99, 0]);
+            assert_eq!(operator.detune, 7);
+            assert_eq!(operator.coarse, 1);
+            assert!(!operator.fixed_frequency);
+        }
+    }
+
+    #[test]
+    fn test_unpack_bit_fields() {
+        let mut voice = INIT_VOICE;
+        let operator = operator_bytes(&mut voice, 3);
+        operator[11] = 0b1110; // right curve 3, left curve 2
+        operator[12] = (10 << 3) | 5; // detune 10, rate scaling 5
+        operator[13] = (6 << 2) | 1; // velocity sensitivity 6, amp mod sensitivity 1
+        operator[15] = (17 << 1) | 1; // coarse 17, fixed
+        voice[110] = 21; // algorithm 22
+        voice[111] = 6; // feedback 6, no oscillator sync
+        voice[116] = (5 << 4) | (4 << 1); // pitch mod sensitivity 5, square wave
+        let voices = parse_bank(&bank(&[voice]));
+        let operator = &voices[0].operators[2];
+        assert_eq!(operator.level_scaling_left_curve, 2);
+        assert_eq!(operator.level_scaling_right_curve, 3);
+        assert_eq!(operator.detune, 10);
+        assert_eq!(operator.rate_scaling, 5);
+        assert_eq!(operator.velocity_sensitivity, 6);
+        assert_eq!(operator.amp_mod_sensitivity, 1);
+        assert_eq!(operator.coarse, 17);
+        assert!(operator.fixed_frequency);
+        assert_eq!(voices[0].algorithm, 21);
+        assert_eq!(voices[0].feedback, 6);
+        assert!(!voices[0].oscillator_sync);
+        assert!(!voices[0].lfo_sync);
+        assert_eq!(voices[0].lfo_waveform, 4);
+        assert_eq!(voices[0].pitch_mod_sensitivity, 5);
+    }
+
+    #[test]
+    fn test_bank_dump() {
+        // Every voice is different: voice n is called VOICE n and plays algorithm n
+        let voices: Vec<_> = (0..32_u8)
+            .map(|index| {
+                let mut voice = PACKED_VOICE;
+                voice[110] = index;
+                voice[124] = b'0' + index / 10;
+                voice[125] = b'0' + index % 10;
+                voice
+            })
+            .collect();
+        let dump = bank(&voices);
+        assert_eq!(dump.len(), BANK_SIZE);
+        let message = Dx7SysExMessage::from_buffer(&dump);
+        assert!(matches!(
+            message,
+            Some(Dx7SysExMessage::BankDump { channel: 0, .. })
+        ));
+        // The bank goes back out unchanged
+        if let Some(message) = message {
+            let (buffer, length) = message.to_buffer();
+            assert_eq!(buffer[..length], dump);
+        }
+
+        let voices = parse_bank(&dump);
+        assert_eq!(voices.len(), BANK_VOICES);
+        for (index, voice) in voices.iter().enumerate() {
+            assert_eq!(usize::from(voice.algorithm), index);
+            assert_eq!(voice.name(), format!("VOICE {index:02}"));
+        }
+        let voice = voices[4];
+        assert_eq!(voice.pitch_eg_rates, [84, 95, 95, 60]);
+        assert_eq!(voice.feedback, 7);
+        assert!(voice.oscillator_sync);
+        assert_eq!(
+            (voice.lfo_speed, voice.lfo_delay, voice.lfo_pitch_mod_depth),
+            (37, 20, 5)
+        );
+        assert!(!voice.lfo_sync);
+        assert_eq!(voice.lfo_waveform, 4);
+        assert_eq!(voice.pitch_mod_sensitivity, 3);
+        let operator_6 = &voice.operators[5];
+        assert_eq!(operator_6.rates, [49, 99, 28, 68]);
+        assert_eq!(operator_6.levels, [98, 98, 91, 0]);
+        assert_eq!(
+            (
+                operator_6.level_scaling_left_depth,
+                operator_6.level_scaling_right_depth
+            ),
+            (54, 50)
+        );
+        assert_eq!(operator_6.level_scaling_left_curve, 2);
+        assert_eq!(operator_6.level_scaling_right_curve, 1);
+        assert_eq!(operator_6.detune, 9);
+        assert_eq!(operator_6.rate_scaling, 4);
+        assert_eq!(operator_6.velocity_sensitivity, 3);
+        assert_eq!(operator_6.amp_mod_sensitivity, 2);
+        let operator_3 = &voice.operators[2];
+        assert_eq!(operator_3.level_scaling_breakpoint, 27);
+        assert_eq!(operator_3.level_scaling_right_curve, 3);
+        assert_eq!(operator_3.velocity_sensitivity, 7);
+        assert!(voice.operators[1].fixed_frequency);
+
+        // Algorithm 5 is 2>1, 4>3 and 6>5. The silent operators 5 and 6 are dropped, so A to D
+        // are operators 4, 3, 2 and 1.
+        let patch = to_patch(voice);
+        assert!(patch.approximations.is_empty());
+        let operators = &patch.parameters.fm_params.operators;
+        assert_relative_eq!(operators[0].coarse, 2.0);
+        assert_relative_eq!(operators[0].fine, 0.5);
+        assert_relative_eq!(operators[0].detune, 3.0 * DETUNE_STEP_CENTS);
+        assert_relative_eq!(operators[0].modulation[1], 0.5 * MAX_MODULATION_DEPTH);
+        assert_relative_eq!(operators[0].mix, 0.0);
+        assert_relative_eq!(operators[1].mix, 1.0);
+        assert_relative_eq!(operators[1].velocity_sensitivity, 1.0);
+        assert_eq!(operators[1].key_scaling.breakpoint, 48);
+        assert_eq!(operators[2].fixed_frequency, Some(10.0));
+        assert_relative_eq!(
+            operators[2].modulation[3],
+            output_level_gain(86) * MAX_MODULATION_DEPTH
+        );
+        assert_relative_eq!(operators[3].mix, 1.0);
+        assert_eq!(operators[3].eg_params.rates, [62, 51, 29, 71]);
+        assert!(operators.iter().all(|operator| operator.feedback == 0.0));
+        assert_eq!(patch.parameters.lfos[0].waveform, LfoWaveform::from_dx7(4));
+        assert_relative_eq!(patch.parameters.lfos[0].rate_hz, lfo_rate_hz(37));
+    }
+
+    #[test]
+    fn test_invalid_banks() {
+        let dump = bank(&[INIT_VOICE]);
+        assert_eq!(bank_data(&dump[..100]), Err(Dx7Error::InvalidLength(100)));
+        let mut wrong_format = dump.clone();
+        wrong_format[3] = 0x00;
+        assert_eq!(bank_data(&wrong_format), Err(Dx7Error::InvalidHeader));
+        let mut missing_end = dump.clone();
+        missing_end[BANK_SIZE - 1] = 0x00;
+        assert_eq!(bank_data(&missing_end), Err(Dx7Error::MissingEnd));
+        let mut corrupted = dump.clone();
+        corrupted[100] += 1;
+        assert!(matches!(
+            bank_data(&corrupted),
+            Err(Dx7Error::InvalidChecksum { .. })
+        ));
+        // None of them gets to the plugin
+        for dump in [&dump[..100], &wrong_format, &missing_end, &corrupted] {
+            assert_eq!(Dx7SysExMessage::from_buffer(dump), None);
+        }
+        // Any channel is fine
+        let mut channel_16 = dump;
+        channel_16[2] = 0x0F;
+        assert!(bank_data(&channel_16).is_ok());
+        // A voice past the end of the bank, or a bank of the wrong size, has no voices
+        let data = bank_data(&channel_16)
+            .copied()
+            .unwrap_or([0; BANK_DATA_SIZE]);
+        assert_eq!(bank_voice(&data, 32), None);
+        assert_eq!(bank_voice(&data[..100], 0), None);
+    }
+
+    #[test]
+    fn test_init_voice_to_patch() {
+        let voices = parse_bank(&bank(&[INIT_VOICE]));
+        let patch = to_patch(voices[0]);
+        assert_eq!(voices[0].name(), "INIT VOICE");
+        assert!(patch.approximations.is_empty());
+        let operators = &patch.parameters.fm_params.operators;
+        // Operator 1 goes into A, as it is the only one that can be heard
+        assert_relative_eq!(operators[0].mix, 1.0);
+        assert_relative_eq!(operators[0].coarse, 1.0);
+        assert_relative_eq!(operators[0].fine, 0.0);
+        assert_relative_eq!(operators[0].detune, 0.0);
+        assert_eq!(operators[0].eg_params.mode, EGMode::Dx);
+        assert_eq!(operators[0].eg_params.levels, [99, 99, 99, 0]);
+        assert!(operators[1..].iter().all(|operator| operator.mix == 0.0));
+        assert!(operators
+            .iter()
+            .flat_map(|operator| operator.modulation)
+            .all(|depth| depth == 0.0));
+    }
+
+    #[test]
+    fn test_routes_and_dropped_operators() {
+        // Algorithm 1 with every operator at full level: 2>1 and 6>5>4>3, feedback on 6
+        let mut voice = INIT_VOICE;
+        for operator in 1..=DX7_OPERATORS {
+            operator_bytes(&mut voice, operator)[14] = 99;
+        }
+        voice[111] = 0x08 | 7; // oscillator sync and feedback 7
+        let voices = parse_bank(&bank(&[voice]));
+        let patch = to_patch(voices[0]);
+        // The carriers win over modulators at the same level, followed by the lowest numbers
+        assert_eq!(
+            patch.approximations,
+            [
+                Approximation::DroppedOperator {
+                    operator: 5,
+                    output_level: 99
+                },
+                Approximation::DroppedOperator {
+                    operator: 6,
+                    output_level: 99
+                },
+            ]
+        );
+        // A to D are operators 4, 3, 2 and 1
+        let operators = &patch.parameters.fm_params.operators;
+        assert_relative_eq!(operators[0].modulation[1], MAX_MODULATION_DEPTH);
+        assert_relative_eq!(operators[2].modulation[3], MAX_MODULATION_DEPTH);
+        assert_relative_eq!(operators[1].mix, 1.0);
+        assert_relative_eq!(operators[3].mix, 1.0);
+        assert_relative_eq!(operators[0].mix, 0.0);
+        // The feedback of operator 6 is dropped with it
+        assert!(operators.iter().all(|operator| operator.feedback == 0.0));
+    }
+
+    #[test]
+    fn test_feedback_loop_and_fixed_frequency() {
+        // Algorithm 4 feeds operator 4 back into operator 6
+        let mut voice = INIT_VOICE;
+        operator_bytes(&mut voice, 1)[14] = 0;
+        for operator in 4..=DX7_OPERATORS {
+            operator_bytes(&mut voice, operator)[14] = 99;
+        }
+        operator_bytes(&mut voice, 4)[15] = (2 << 1) | 1; // 100 Hz
+        voice[110] = 3;
+        voice[111] = 5;
+        let voices = parse_bank(&bank(&[voice]));
+        let patch = to_patch(voices[0]);
+        assert!(patch
+            .approximations
+            .contains(&Approximation::FeedbackLoop { from: 4, to: 6 }));
+        let operators = &patch.parameters.fm_params.operators;
+        // A is operator 6
+        assert_relative_eq!(operators[0].feedback, feedback_depth(5));
+        assert_eq!(operators[2].fixed_frequency, Some(100.0));
+        assert_eq!(operators[0].fixed_frequency, None);
+    }
+
+    #[test]
+    fn test_bank_voice_matches_its_single_voice_dump() {
+        // `PACKED_VOICE` in the unpacked format of a single voice dump, one setting per byte in
+        // the order of the parameter numbers of the DX7 manual
+        #[rustfmt::skip]
+        const UNPACKED_VOICE: [u8; VOICE_SIZE] = [
+            // Operator 6: rates, levels, breakpoint, depths, curves, rate scaling, amp mod and
+            // velocity sensitivity, output level, mode, coarse, fine, detune
+            49, 99, 28, 68, 98, 98, 91, 0, 39, 54, 50, 2, 1, 4, 2, 3, 0, 0, 1, 0, 9,
+            // Operator 5
+            77, 36, 41, 71, 99, 98, 98, 0, 39, 0, 0, 0, 0, 2, 0, 2, 0, 0, 1, 0, 7,
+            // Operator 4
+            77, 36, 41, 71, 99, 98, 98, 0, 39, 0, 0, 0, 0, 2, 0, 2, 91, 0, 2, 50, 10,
+            // Operator 3
+            62, 51, 29, 71, 82, 95, 96, 0, 27, 0, 7, 0, 3, 1, 1, 7, 99, 0, 1, 0, 5,
+            // Operator 2
+            72, 76, 99, 71, 99, 88, 96, 0, 39, 0, 14, 0, 0, 0, 0, 0, 86, 1, 1, 0, 7,
+            // Operator 1
+            62, 51, 29, 71, 82, 95, 96, 0, 39, 0, 0, 0, 0, 2, 0, 2, 99, 0, 1, 0, 7,
+            // Pitch EG, algorithm, feedback, oscillator sync, LFO speed, delay, depths, sync,
+            // wave and pitch mod sensitivity, transpose
+            84, 95, 95, 60, 50, 50, 50, 50, 4, 7, 1, 37, 20, 5, 0, 0, 4, 3, 24,
+            // Name
+            b'V', b'O', b'I', b'C', b'E', b' ', b'0', b'0', b' ', b' ',
+        ];
+        let voices = parse_bank(&bank(&[PACKED_VOICE]));
+        assert_eq!(voices[0], Dx7Voice::from_unpacked(&UNPACKED_VOICE));
+    }
+
+    #[test]
+    fn test_free_running_oscillators_are_reported() {
+        let voice = Dx7Voice {
+            oscillator_sync: false,
+            ..Dx7Voice::default()
+        };
+        assert_eq!(
+            to_patch(voice).approximations,
+            [Approximation::Ignored {
+                operator: None,
+                setting: "free running oscillators (oscillator key sync off)",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unpacked_round_trip() {
+        let voices = parse_bank(&bank(&[INIT_VOICE]));
+        assert_eq!(voices[0], Dx7Voice::default());
+        let mut voice = Dx7Voice::default();
+        voice.operators[3].detune = 12;
+        voice.operators[5].fixed_frequency = true;
+        voice.lfo_waveform = 5;
+        voice.name = *b"E.PIANO 1 ";
+        assert_eq!(Dx7Voice::from_unpacked(&voice.to_unpacked()), voice);
+        assert_eq!(voice.name(), "E.PIANO 1");
+    }
+
+    #[test]
+    fn test_set_parameter() {
+        let mut voice = Dx7Voice::default();
+        // Algorithm
+        voice.set_parameter(134, 21);
+        assert_eq!(voice.algorithm, 21);
+        // Output level of operator 6, which comes first
+        voice.set_parameter(16, 80);
+        assert_eq!(voice.operators[5].output_level, 80);
+        // Coarse frequency of operator 1
+        voice.set_parameter(5 * 21 + 18, 3);
+        assert_eq!(voice.operators[0].coarse, 3);
+        // The operator on/off switch leaves the voice alone
+        let before = voice;
+        voice.set_parameter(OPERATOR_ON_OFF_PARAMETER, 0);
+        assert_eq!(voice, before);
+    }
+
+    #[test]
+    fn test_sysex_messages_are_small() {
+        // Every note event is as large as the largest message
+        assert!(size_of::<Dx7SysExMessage>() <= 8);
+    }
+
+    #[test]
+    fn test_sysex_messages() {
+        let voice_dump = Dx7SysExMessage::VoiceDump {
+            channel: 3,
+            data: SysExDump::store(&Dx7Voice::default().to_unpacked())
+                .expect("the voice was not stored"),
+        };
+        let parameter_change = Dx7SysExMessage::ParameterChange {
+            channel: 0,
+            parameter: 134,
+            value: 4,
+        };
+        // The dumps are stored again when they are read, so the messages are compared as bytes
+        for message in [voice_dump, parameter_change] {
+            let (buffer, length) = message.to_buffer();
+            let read = Dx7SysExMessage::from_buffer(&buffer[..length]);
+            assert!(read.is_some_and(|read| read.to_buffer().0[..length] == buffer[..length]));
+        }
+        let (buffer, length) = parameter_change.to_buffer();
+        assert_eq!(buffer[..length], [0xF0, 0x43, 0x10, 0x01, 0x06, 0x04, 0xF7]);
+
+        // A corrupted voice dump is rejected
+        let (mut buffer, length) = voice_dump.to_buffer();
+        buffer[10] += 1;
+        assert_eq!(Dx7SysExMessage::from_buffer(&buffer[..length]), None);
+        // So are function parameters and messages from other manufacturers
+        assert_eq!(
+            Dx7SysExMessage::from_buffer(&[0xF0, 0x43, 0x10, 0x08, 0x40, 0x01, 0xF7]),
+            None
+        );
+        assert_eq!(
+            Dx7SysExMessage::from_buffer(&[0xF0, 0x41, 0x10, 0x00, 0x06, 0x04, 0xF7]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_output_level_gain() {
+        assert_relative_eq!(output_level_gain(99), 1.0);
+        assert_relative_eq!(output_level_gain(0), 0.0);
+        // 8 steps of 0.75 dB halve the gain
+        assert_relative_eq!(output_level_gain(91), 0.5);
+    }
+
+    #[test]
+    fn test_lfo_rate_and_delay() {
+        assert_relative_eq!(lfo_rate_hz(0), 0.0645, epsilon = 1e-3);
+        // The speed of the INIT VOICE
+        assert_relative_eq!(lfo_rate_hz(35), 5.81, epsilon = 0.01);
+        assert_relative_eq!(lfo_rate_hz(99), 23.93, epsilon = 0.01);
+        assert_eq!(lfo_delay_msec(0), (0.0, 0.0));
+        let (delay_msec, fade_in_msec) = lfo_delay_msec(99);
+        assert_relative_eq!(delay_msec, 2664.0, epsilon = 1.0);
+        assert_relative_eq!(fade_in_msec, 666.0, epsilon = 1.0);
+        // Longer delays take longer
+        assert!(lfo_delay_msec(50).0 < lfo_delay_msec(60).0);
+    }
+
+    #[test]
+    fn test_lfo_to_parameters() {
+        let mut voice = Dx7Voice {
+            lfo_waveform: 4,
+            lfo_pitch_mod_depth: 99,
+            pitch_mod_sensitivity: 7,
+            lfo_amp_mod_depth: 99,
+            lfo_sync: false,
+            ..Default::default()
+        };
+        voice.operators[0].amp_mod_sensitivity = 3;
+        let patch = to_patch(voice);
+        assert!(patch.approximations.is_empty());
+        let lfo = &patch.parameters.lfos[0];
+        assert_eq!(lfo.scope, LfoScope::Global);
+        assert_eq!(lfo.waveform, LfoWaveform::Sine);
+        assert!(!lfo.key_retrigger);
+        assert_relative_eq!(lfo.pitch_depth, MAX_LFO_PITCH_DEPTH);
+        assert_relative_eq!(lfo.amp_depth, 1.0);
+        // Operator 1 is in A
+        let operators = &patch.parameters.fm_params.operators;
+        assert_relative_eq!(operators[0].amp_mod_sensitivity, 1.0);
+        // The other LFO is left alone
+        assert_relative_eq!(patch.parameters.lfos[1].pitch_depth, 0.0);
+    }
+}
@@ -0,0 +1,309 @@
+use nih_plug::nih_debug_assert;
+
+use crate::consts::SHUTDOWN_TIME_MSEC;
+use crate::linear_eg::{EGParameters, EnvelopeGenerator};
+
+/// The level of the envelope, in octaves above silence, that gives a full output. This is where
+/// a DX7 level of 99 ends up.
+const FULL_LEVEL: f32 = 15.0;
+/// A rising segment jumps straight to this level, as the DX7 does, because the quiet start of
+/// the segment would otherwise take most of its time.
+const ATTACK_JUMP_LEVEL: f32 = 1716.0 / 256.0;
+/// The DX7 envelope rates are defined at this sample rate.
+const DX7_SAMPLE_RATE: f32 = 44100.0;
+
+/// The state of the DX7 envelope generator.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum DxState {
+    Off,
+    /// Heading towards the level of the stage, 0-3 for L1-L4
+    Stage(usize),
+    /// Holding at L3 until the note is turned off
+    Sustain,
+    /// Holding at an audible L4 after the release, which keeps sounding like on the DX7
+    Hold,
+    Shutdown,
+}
+
+/// Represents a DX7 style envelope generator with four rates and four levels. The first three
+/// stages run when a note is turned on, and the envelope holds at L3 until the note is turned
+/// off. R4 then takes it to L4, where it keeps playing until it is shut down if L4 can be heard.
+///
+/// Like the DX7, the envelope runs in the log domain, so every segment is linear in decibels.
+/// It is modeled on the envelope of Music Synthesizer for Android, which the Dexed DX7 emulation
+/// is based on.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DxEG {
+    state: DxState,
+    /// The current level in octaves above silence
+    level: f32,
+    target_level: f32,
+    rising: bool,
+    /// The change of the level every sample, in octaves
+    increment: f32,
+    /// The increase of the rates for the current note
+    rate_scaling: i32,
+    shutdown_decrement: f32,
+}
+
+/// Converts a DX7 output level (0-99) to the DX7's internal level, where each step is 0.75 dB.
+/// The low levels fall off faster than the rest.
+pub fn scale_level(level: i32) -> i32 {
+    const LOW_LEVELS: [i32; 20] = [
+        0, 5, 9, 13, 17, 20, 23, 25, 27, 29, 31, 33, 35, 37, 39, 41, 42, 43, 45, 46,
+    ];
+    let level = level.clamp(0, 99);
+    usize::try_from(level)
+        .ok()
+        .and_then(|index| LOW_LEVELS.get(index))
+        .copied()
+        .unwrap_or(28 + level)
+}
+
+/// Converts a DX7 envelope level (0-99) to octaves above silence.
+#[allow(clippy::cast_precision_loss)]
+fn level_to_octaves(level: i32) -> f32 {
+    ((scale_level(level) << 5) - 224).max(0) as f32 / 256.0
+}
+
+/// Calculates how much a DX7 rate (0-99) is increased for a note. The rates go up from note 21 to
+/// note 114, with the sensitivity (0-7) setting the depth.
+pub fn rate_scaling_delta(note: u8, sensitivity: i32) -> i32 {
+    let position = (i32::from(note) / 3 - 7).clamp(0, 31);
+    (sensitivity.clamp(0, 7) * position) >> 3
+}
+
+/// How much shorter the times of an envelope are for a note, with the DX7's rate scaling. Every
+/// four steps of rate scaling double the speed of the envelope.
+#[allow(clippy::cast_precision_loss)]
+pub fn rate_scaling_time_scale(note: u8, sensitivity: i32) -> f32 {
+    (-(rate_scaling_delta(note, sensitivity) as f32) / 4.0).exp2()
+}
+
+/// Converts a DX7 rate (0-99) to the change of the level every sample, in octaves.
+#[allow(clippy::cast_precision_loss)]
+fn rate_to_increment(rate: i32, rate_scaling: i32, sample_rate: f32) -> f32 {
+    let qrate = (((rate.clamp(0, 99) * 41) >> 6) + rate_scaling).min(63);
+    (4 + (qrate & 3)) as f32 * 2.0_f32.powi(2 + (qrate >> 2) - 24) * DX7_SAMPLE_RATE / sample_rate
+}
+
+impl DxEG {
+    /// Starts the segment towards the level of `stage`.
+    fn start_stage(&mut self, stage: usize, parameters: &EGParameters, sample_rate: f32) {
+        self.state = DxState::Stage(stage);
+        self.target_level = level_to_octaves(parameters.levels[stage]);
+        self.rising = self.target_level > self.level;
+        self.increment = rate_to_increment(parameters.rates[stage], self.rate_scaling, sample_rate);
+    }
+
+    /// Moves on once the level of `stage` is reached.
+    fn finish_stage(&mut self, stage: usize, parameters: &EGParameters, sample_rate: f32) {
+        self.level = self.target_level;
+        match stage {
+            2 => self.state = DxState::Sustain,
+            3 if self.output() > 0.0 => self.state = DxState::Hold,
+            3 => self.state = DxState::Off,
+            _ => self.start_stage(stage + 1, parameters, sample_rate),
+        }
+    }
+
+    /// The output gain for the current level
+    fn output(&self) -> f32 {
+        if self.level <= 0.0 {
+            0.0
+        } else {
+            (self.level - FULL_LEVEL).exp2()
+        }
+    }
+}
+
+impl EnvelopeGenerator for DxEG {
+    /// Creates a new instance of the DX7 envelope generator.
+    fn new() -> Self {
+        Self {
+            state: DxState::Off,
+            level: 0.0,
+            target_level: 0.0,
+            rising: false,
+            increment: 0.0,
+            rate_scaling: 0,
+            shutdown_decrement: 0.0,
+        }
+    }
+
+    /// Resets the DX7 envelope generator to L4, where a finished envelope rests.
+    fn reset(&mut self, parameters: &EGParameters) {
+        self.level = level_to_octaves(parameters.levels[3]);
+        self.state = DxState::Off;
+    }
+
+    /// Updates the DX7 envelope generator with the given parameters.
+    fn update(&mut self, _parameters: &EGParameters) {
+        // The rate and the level are read at the start of every stage.
+    }
+
+    /// Renders the output of the DX7 envelope generator into `output`, one value for every
+    /// sample.
+    fn render(&mut self, parameters: &EGParameters, output: &mut [f32], sample_rate: f32) {
+        for output_value in output {
+            match self.state {
+                DxState::Off | DxState::Sustain | DxState::Hold => {}
+                DxState::Stage(stage) => {
+                    if self.rising {
+                        // Rising segments slow down as they get louder
+                        self.level = self.level.max(ATTACK_JUMP_LEVEL);
+                        self.level += (FULL_LEVEL + 2.0 - self.level) * self.increment;
+                        if self.level >= self.target_level {
+                            self.finish_stage(stage, parameters, sample_rate);
+                        }
+                    } else {
+                        self.level -= self.increment;
+                        if self.level <= self.target_level {
+                            self.finish_stage(stage, parameters, sample_rate);
+                        }
+                    }
+                }
+                DxState::Shutdown => {
+                    self.level -= self.shutdown_decrement;
+                    if self.level <= 0.0 {
+                        self.level = 0.0;
+                        self.state = DxState::Off;
+                    }
+                }
+            }
+            *output_value = self.output();
+        }
+    }
+
+    /// Notifies the DX7 envelope generator that a note has been turned off. R4 starts from the
+    /// current level, wherever the envelope is.
+    fn note_off(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        if self.state != DxState::Off {
+            self.start_stage(3, parameters, sample_rate);
+        }
+    }
+
+    /// Notifies the DX7 envelope generator that a note has been turned on. The envelope starts
+    /// from its current level, like the DX7 does.
+    fn note_on(&mut self, parameters: &EGParameters, note: u8, sample_rate: f32) {
+        self.rate_scaling = rate_scaling_delta(note, parameters.rate_scaling);
+        self.start_stage(0, parameters, sample_rate);
+    }
+
+    fn shutdown(&mut self, _parameters: &EGParameters, sample_rate: f32) {
+        self.shutdown_decrement = 1000.0 * self.level / SHUTDOWN_TIME_MSEC / sample_rate;
+        nih_debug_assert!(self.shutdown_decrement >= 0.0);
+        self.state = DxState::Shutdown;
+    }
+
+    fn is_playing(&self) -> bool {
+        self.state != DxState::Off
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    fn parameters(rates: [i32; 4], levels: [i32; 4]) -> EGParameters {
+        EGParameters {
+            rates,
+            levels,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scale_level() {
+        assert_eq!(scale_level(0), 0);
+        assert_eq!(scale_level(19), 46);
+        assert_eq!(scale_level(20), 48);
+        assert_eq!(scale_level(99), 127);
+        assert_relative_eq!(level_to_octaves(99), FULL_LEVEL);
+        assert_relative_eq!(level_to_octaves(0), 0.0);
+    }
+
+    #[test]
+    fn test_rate_scaling_delta() {
+        assert_eq!(rate_scaling_delta(60, 0), 0);
+        assert_eq!(rate_scaling_delta(21, 7), 0);
+        assert_eq!(rate_scaling_delta(60, 7), 11);
+        assert_eq!(rate_scaling_delta(127, 7), 27);
+        // Higher notes never get slower
+        assert!((0..127).all(|note| rate_scaling_delta(note, 7) <= rate_scaling_delta(note + 1, 7)));
+    }
+
+    #[test]
+    fn test_stages_and_sustain() {
+        let parameters = parameters([99, 70, 70, 70], [99, 80, 60, 0]);
+        let mut eg = DxEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        let mut output = vec![0.0; 44100];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(output.iter().any(|value| *value >= 0.999));
+        // Held at L3: 39 steps of 0.75 dB below full output
+        assert_eq!(eg.state, DxState::Sustain);
+        assert_relative_eq!(
+            output[44099],
+            10.0_f32.powf(-39.0 * 0.75 / 20.0),
+            epsilon = 1e-3
+        );
+        eg.note_off(&parameters, SAMPLE_RATE);
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(!eg.is_playing());
+        assert_relative_eq!(output[44099], 0.0);
+    }
+
+    #[test]
+    fn test_audible_l4_keeps_playing() {
+        let parameters = parameters([99, 99, 99, 99], [99, 99, 99, 50]);
+        let mut eg = DxEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        let mut output = vec![0.0; 4410];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        eg.note_off(&parameters, SAMPLE_RATE);
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        // The release ends at L4, which can still be heard, so the voice must not be freed
+        assert_eq!(eg.state, DxState::Hold);
+        assert!(eg.is_playing());
+        assert_relative_eq!(output[4409], (level_to_octaves(50) - FULL_LEVEL).exp2());
+        assert!(output[4409] > 0.0);
+        // Stealing the voice fades it out
+        eg.shutdown(&parameters, SAMPLE_RATE);
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(!eg.is_playing());
+        assert_relative_eq!(output[4409], 0.0);
+    }
+
+    #[test]
+    fn test_rate_scaling_speeds_up_high_notes() {
+        let mut parameters = parameters([99, 50, 99, 99], [99, 0, 0, 0]);
+        parameters.rate_scaling = 7;
+        let mut levels = [0.0; 2];
+        for (note, level) in [36, 96].into_iter().zip(&mut levels) {
+            let mut eg = DxEG::new();
+            eg.note_on(&parameters, note, SAMPLE_RATE);
+            let mut output = vec![0.0; 4410];
+            eg.render(&parameters, &mut output, SAMPLE_RATE);
+            *level = output[4409];
+        }
+        assert!(levels[1] < levels[0]);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let parameters = parameters([99, 99, 99, 99], [99, 99, 99, 0]);
+        let mut eg = DxEG::new();
+        eg.note_on(&parameters, 60, SAMPLE_RATE);
+        let mut output = [0.0; 100];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        eg.shutdown(&parameters, SAMPLE_RATE);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let mut output = vec![0.0; (SHUTDOWN_TIME_MSEC * SAMPLE_RATE / 1000.0) as usize + 1];
+        eg.render(&parameters, &mut output, SAMPLE_RATE);
+        assert!(!eg.is_playing());
+    }
+}
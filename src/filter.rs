@@ -0,0 +1,417 @@
+use std::f32::consts::PI;
+
+use nih_plug::prelude::Enum;
+
+use crate::consts::MAX_CHANNELS;
+use crate::linear_eg::{EGParameters, EnvelopeGenerator};
+use crate::multi_mode_eg::MultiModeEG;
+
+/// The lowest cutoff frequency, in Hz.
+const MIN_CUTOFF_HZ: f32 = 20.0;
+/// The highest cutoff frequency, relative to the sample rate. The filters are stable up to the
+/// Nyquist frequency, but the cutoff warps badly just below it.
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+/// The damping of the state variable filter at full resonance. It rings for a long time, but
+/// never oscillates on its own.
+const MIN_SVF_DAMPING: f32 = 0.02;
+/// The feedback of the ladder filter at full resonance. It oscillates on its own at 4.
+const MAX_LADDER_FEEDBACK: f32 = 3.96;
+/// The note the cutoff is set for when it follows the key.
+const KEY_TRACKING_CENTER: f32 = 60.0;
+
+/// The response of the filter of the voices.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FilterMode {
+    /// The voices are not filtered
+    #[default]
+    #[id = "off"]
+    #[name = "Off"]
+    Off,
+    #[id = "low_pass"]
+    #[name = "Low Pass"]
+    LowPass,
+    #[id = "high_pass"]
+    #[name = "High Pass"]
+    HighPass,
+    #[id = "band_pass"]
+    #[name = "Band Pass"]
+    BandPass,
+    #[id = "notch"]
+    #[name = "Notch"]
+    Notch,
+    /// A 24 dB per octave low pass, modeled on the transistor ladder of the Moog synthesizers
+    #[id = "ladder"]
+    #[name = "Ladder Low Pass"]
+    Ladder,
+}
+
+/// The parameters of the filter of the voices.
+#[derive(Clone, Copy)]
+pub struct FilterParameters {
+    pub mode: FilterMode,
+    pub cutoff_hz: f32,
+    /// From 0 to 1
+    pub resonance: f32,
+    /// How much the cutoff follows the note, from 0 to 1. At 1 it moves an octave per octave,
+    /// around C4.
+    pub key_tracking: f32,
+    /// How far the envelope moves the cutoff at its peak, in octaves
+    pub env_amount: f32,
+    pub eg_params: EGParameters,
+}
+
+impl Default for FilterParameters {
+    fn default() -> Self {
+        Self {
+            mode: FilterMode::default(),
+            cutoff_hz: 20_000.0,
+            resonance: 0.0,
+            key_tracking: 0.0,
+            env_amount: 0.0,
+            eg_params: EGParameters::default(),
+        }
+    }
+}
+
+impl FilterParameters {
+    /// The cutoff for `note` with the envelope at `eg_level`, clamped to what the filters can
+    /// play at `sample_rate`.
+    pub fn cutoff(&self, note: u8, eg_level: f32, sample_rate: f32) -> f32 {
+        let octaves = self.key_tracking.mul_add(
+            (f32::from(note) - KEY_TRACKING_CENTER) / 12.0,
+            self.env_amount * eg_level,
+        );
+        (self.cutoff_hz * octaves.exp2()).clamp(
+            MIN_CUTOFF_HZ.min(MAX_CUTOFF_RATIO * sample_rate),
+            MAX_CUTOFF_RATIO * sample_rate,
+        )
+    }
+}
+
+/// The gain of the integrators of a topology-preserving transform filter at `cutoff`. The
+/// prewarping puts the cutoff of the digital filter exactly where the analog one has it.
+fn prewarped_gain(cutoff: f32, sample_rate: f32) -> f32 {
+    (PI * cutoff / sample_rate).tan()
+}
+
+/// The damping of the state variable filter for a resonance from 0 to 1. Without resonance the
+/// low and high pass are Butterworth filters, 3 dB down at the cutoff.
+fn svf_damping(resonance: f32) -> f32 {
+    (std::f32::consts::SQRT_2 * (1.0 - resonance)).max(MIN_SVF_DAMPING)
+}
+
+/// A zero-delay feedback state variable filter, after "The Art of VA Filter Design" by Vadim
+/// Zavalishin. It has low, high and band pass outputs, and the notch is the sum of the low and
+/// high pass.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SvfFilter {
+    /// The states of the two integrators
+    integrator_1: f32,
+    integrator_2: f32,
+}
+
+impl SvfFilter {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Filters a sample. `gain` is the prewarped gain of the integrators and `damping` is the
+    /// inverse of the Q of the filter. The band pass is normalized to unity at the cutoff.
+    pub fn process(&mut self, input: f32, gain: f32, damping: f32, mode: FilterMode) -> f32 {
+        let a1 = 1.0 / gain.mul_add(gain + damping, 1.0);
+        let a2 = gain * a1;
+        let a3 = gain * a2;
+        let v3 = input - self.integrator_2;
+        let band = a1.mul_add(self.integrator_1, a2 * v3);
+        let low = a2.mul_add(self.integrator_1, a3.mul_add(v3, self.integrator_2));
+        self.integrator_1 = 2.0f32.mul_add(band, -self.integrator_1);
+        self.integrator_2 = 2.0f32.mul_add(low, -self.integrator_2);
+        match mode {
+            FilterMode::Off => input,
+            FilterMode::LowPass | FilterMode::Ladder => low,
+            FilterMode::HighPass => damping.mul_add(-band, input - low),
+            FilterMode::BandPass => damping * band,
+            FilterMode::Notch => damping.mul_add(-band, input),
+        }
+    }
+}
+
+/// A zero-delay feedback model of the four pole transistor ladder, without its saturation. The
+/// feedback through the four stages is solved for every sample, so the resonance sits exactly at
+/// the cutoff.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct LadderFilter {
+    /// The states of the four one pole stages
+    stages: [f32; 4],
+}
+
+impl LadderFilter {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Filters a sample. `gain` is the prewarped gain of the stages and `feedback` goes from 0
+    /// up to 4, where the filter oscillates. The pass band is boosted back to unity, since the
+    /// feedback lowers it.
+    pub fn process(&mut self, input: f32, gain: f32, feedback: f32) -> f32 {
+        let stage_gain = gain / (1.0 + gain);
+        // The output of the last stage is `stage_gain^4 * input + sum`, with `sum` the part that
+        // comes from the states
+        let sum = self.stages.iter().fold(0.0, |sum, state| {
+            stage_gain.mul_add(sum, state / (1.0 + gain))
+        });
+        let stage_gain_4 = stage_gain.powi(4);
+        let mut stage_input = feedback.mul_add(-sum, input) / feedback.mul_add(stage_gain_4, 1.0);
+        for state in &mut self.stages {
+            let v = (stage_input - *state) * stage_gain;
+            let output = v + *state;
+            *state = output + v;
+            stage_input = output;
+        }
+        stage_input * (1.0 + feedback)
+    }
+}
+
+/// The filter of a voice and its envelope. The channels of the voice are filtered separately, so
+/// the stereo image of the voice is kept.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VoiceFilter {
+    svf: [SvfFilter; MAX_CHANNELS],
+    ladder: [LadderFilter; MAX_CHANNELS],
+    eg: MultiModeEG,
+    eg_buffer: Vec<f32>,
+    /// The note the cutoff follows
+    note: u8,
+    /// The mode of the last block, so the filters start over when it changes
+    mode: FilterMode,
+}
+
+impl VoiceFilter {
+    pub fn new() -> Self {
+        Self {
+            svf: [SvfFilter::default(); MAX_CHANNELS],
+            ladder: [LadderFilter::default(); MAX_CHANNELS],
+            eg: MultiModeEG::new(),
+            eg_buffer: vec![0.0; 1],
+            note: 60,
+            mode: FilterMode::default(),
+        }
+    }
+
+    pub fn initialize(&mut self, max_samples_per_channel: usize) {
+        self.eg_buffer = vec![0.0; max_samples_per_channel];
+    }
+
+    pub fn reset(&mut self, params: &FilterParameters) {
+        self.reset_filters();
+        self.eg.reset(&params.eg_params);
+    }
+
+    /// Starts the envelope for a new note. The filters keep their state, like an analog filter
+    /// that is retriggered.
+    pub fn note_on(&mut self, params: &FilterParameters, note: u8, sample_rate: f32) {
+        self.note = note;
+        self.eg.note_on(&params.eg_params, note, sample_rate);
+    }
+
+    /// Follows a legato note without restarting the envelope.
+    pub const fn change_note(&mut self, note: u8) {
+        self.note = note;
+    }
+
+    pub fn note_off(&mut self, params: &FilterParameters, sample_rate: f32) {
+        self.eg.note_off(&params.eg_params, sample_rate);
+    }
+
+    fn reset_filters(&mut self) {
+        self.svf.iter_mut().for_each(SvfFilter::reset);
+        self.ladder.iter_mut().for_each(LadderFilter::reset);
+    }
+
+    /// Filters the first `num_samples` of every channel of `output` in place.
+    pub fn process(
+        &mut self,
+        output: &mut [Vec<f32>],
+        num_samples: usize,
+        params: &FilterParameters,
+        sample_rate: f32,
+    ) {
+        if params.mode != self.mode {
+            self.mode = params.mode;
+            self.reset_filters();
+        }
+        let eg_buffer = &mut self.eg_buffer[..num_samples];
+        self.eg.render(&params.eg_params, eg_buffer, sample_rate);
+        if params.mode == FilterMode::Off {
+            return;
+        }
+        let resonance = params.resonance.clamp(0.0, 1.0);
+        let damping = svf_damping(resonance);
+        let feedback = MAX_LADDER_FEEDBACK * resonance;
+        for (sample_index, eg_level) in eg_buffer.iter().enumerate() {
+            let cutoff = params.cutoff(self.note, *eg_level, sample_rate);
+            let gain = prewarped_gain(cutoff, sample_rate);
+            for ((channel, svf), ladder) in
+                output.iter_mut().zip(&mut self.svf).zip(&mut self.ladder)
+            {
+                let sample = &mut channel[sample_index];
+                *sample = if params.mode == FilterMode::Ladder {
+                    ladder.process(*sample, gain, feedback)
+                } else {
+                    svf.process(*sample, gain, damping, params.mode)
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rstest::rstest;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    /// The amplitude of a sine at `frequency` after the filter has settled.
+    #[allow(clippy::cast_precision_loss)]
+    fn amplitude(mut filter: impl FnMut(f32) -> f32, frequency: f32) -> f32 {
+        let output: Vec<f32> = (0..9600)
+            .map(|i| filter((2.0 * PI * frequency * i as f32 / SAMPLE_RATE).sin()))
+            .collect();
+        let settled = &output[4800..];
+        let power =
+            settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32;
+        (2.0 * power).sqrt()
+    }
+
+    fn svf_amplitude(mode: FilterMode, resonance: f32, frequency: f32) -> f32 {
+        let mut svf = SvfFilter::default();
+        let gain = prewarped_gain(1000.0, SAMPLE_RATE);
+        let damping = svf_damping(resonance);
+        amplitude(|input| svf.process(input, gain, damping, mode), frequency)
+    }
+
+    fn ladder_amplitude(resonance: f32, frequency: f32) -> f32 {
+        let mut ladder = LadderFilter::default();
+        let gain = prewarped_gain(1000.0, SAMPLE_RATE);
+        amplitude(
+            |input| ladder.process(input, gain, MAX_LADDER_FEEDBACK * resonance),
+            frequency,
+        )
+    }
+
+    #[rstest]
+    // The pass bands are at unity, and the cutoff is 3 dB down
+    #[case(FilterMode::LowPass, 50.0, 1.0)]
+    #[case(FilterMode::LowPass, 1000.0, std::f32::consts::FRAC_1_SQRT_2)]
+    #[case(FilterMode::HighPass, 10000.0, 1.0)]
+    #[case(FilterMode::HighPass, 1000.0, std::f32::consts::FRAC_1_SQRT_2)]
+    #[case(FilterMode::BandPass, 1000.0, 1.0)]
+    #[case(FilterMode::Notch, 50.0, 1.0)]
+    #[case(FilterMode::Notch, 10000.0, 1.0)]
+    fn test_svf_responses(#[case] mode: FilterMode, #[case] frequency: f32, #[case] expected: f32) {
+        assert_relative_eq!(
+            svf_amplitude(mode, 0.0, frequency),
+            expected,
+            epsilon = 0.01
+        );
+    }
+
+    #[rstest]
+    #[case(FilterMode::LowPass, 10000.0)]
+    #[case(FilterMode::HighPass, 50.0)]
+    #[case(FilterMode::BandPass, 50.0)]
+    #[case(FilterMode::BandPass, 20000.0)]
+    #[case(FilterMode::Notch, 1000.0)]
+    fn test_svf_stop_bands(#[case] mode: FilterMode, #[case] frequency: f32) {
+        assert!(svf_amplitude(mode, 0.0, frequency) < 0.1);
+    }
+
+    #[test]
+    fn test_svf_resonance_peaks_at_cutoff() {
+        assert!(svf_amplitude(FilterMode::LowPass, 0.9, 1000.0) > 4.0);
+        assert!(
+            svf_amplitude(FilterMode::LowPass, 0.9, 1000.0)
+                > svf_amplitude(FilterMode::LowPass, 0.9, 500.0)
+        );
+    }
+
+    #[test]
+    fn test_ladder_is_24_db_per_octave() {
+        assert_relative_eq!(ladder_amplitude(0.0, 20.0), 1.0, epsilon = 0.01);
+        // Four poles at the cutoff, each 3 dB down
+        assert_relative_eq!(ladder_amplitude(0.0, 1000.0), 0.25, epsilon = 0.01);
+        let octave_up = ladder_amplitude(0.0, 4000.0) / ladder_amplitude(0.0, 8000.0);
+        assert!(20.0 * octave_up.log10() > 22.0);
+    }
+
+    #[test]
+    fn test_ladder_resonance_keeps_pass_band() {
+        assert_relative_eq!(ladder_amplitude(0.8, 20.0), 1.0, epsilon = 0.02);
+        assert!(ladder_amplitude(0.8, 1000.0) > 2.0);
+    }
+
+    #[test]
+    fn test_cutoff_follows_key_and_envelope() {
+        let params = FilterParameters {
+            cutoff_hz: 1000.0,
+            key_tracking: 1.0,
+            env_amount: 2.0,
+            ..Default::default()
+        };
+        assert_relative_eq!(params.cutoff(60, 0.0, SAMPLE_RATE), 1000.0);
+        assert_relative_eq!(params.cutoff(72, 0.0, SAMPLE_RATE), 2000.0, epsilon = 0.01);
+        assert_relative_eq!(params.cutoff(60, 1.0, SAMPLE_RATE), 4000.0, epsilon = 0.01);
+        assert_relative_eq!(
+            params.cutoff(127, 1.0, SAMPLE_RATE),
+            MAX_CUTOFF_RATIO * SAMPLE_RATE
+        );
+    }
+
+    #[test]
+    fn test_voice_filter_envelope_opens_filter() {
+        let params = FilterParameters {
+            mode: FilterMode::LowPass,
+            cutoff_hz: 100.0,
+            env_amount: 6.0,
+            eg_params: EGParameters {
+                attack_time_msec: 1.0,
+                sustain_level: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let render = |params: &FilterParameters| {
+            let mut filter = VoiceFilter::new();
+            filter.initialize(4800);
+            filter.note_on(params, 60, SAMPLE_RATE);
+            #[allow(clippy::cast_precision_loss)]
+            let mut output = vec![
+                (0..4800)
+                    .map(|i| (2.0 * PI * 3000.0 * i as f32 / SAMPLE_RATE).sin())
+                    .collect::<Vec<_>>();
+                2
+            ];
+            filter.process(&mut output, 4800, params, SAMPLE_RATE);
+            output[1][2400..]
+                .iter()
+                .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+        };
+        let closed = render(&FilterParameters {
+            env_amount: 0.0,
+            ..params
+        });
+        assert!(closed < 0.01);
+        assert!(render(&params) > 0.5);
+        // Off leaves the voice alone
+        assert_relative_eq!(
+            render(&FilterParameters {
+                mode: FilterMode::Off,
+                ..params
+            }),
+            1.0,
+            epsilon = 1e-3
+        );
+    }
+}
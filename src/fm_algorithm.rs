@@ -0,0 +1,126 @@
+use nih_plug::prelude::Enum;
+
+use crate::consts::NUM_OPERATORS;
+
+// Operator indices used in the routing tables below
+const A: usize = 0;
+const B: usize = 1;
+const C: usize = 2;
+const D: usize = 3;
+
+/// The way the four operators of an `FmVoice` are connected to each other.
+/// These are modeled on the eight algorithms of 4-operator Yamaha synths (DX21, DX27, TX81Z).
+///
+/// Every route goes from a lower operator to a higher one (A before D), so rendering the
+/// operators in order always has the modulator output ready before it is needed.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FmAlgorithm {
+    /// A -> B -> C -> D
+    #[default]
+    #[id = "stack"]
+    #[name = "1: A>B>C>D"]
+    Stack,
+    /// (A + B) -> C -> D
+    #[id = "dual_modulator_stack"]
+    #[name = "2: (A+B)>C>D"]
+    DualModulatorStack,
+    /// (A + (B -> C)) -> D
+    #[id = "branch_stack"]
+    #[name = "3: (A+(B>C))>D"]
+    BranchStack,
+    /// ((A -> B) + C) -> D
+    #[id = "stack_branch"]
+    #[name = "4: ((A>B)+C)>D"]
+    StackBranch,
+    /// (A -> B) + (C -> D)
+    #[id = "two_pairs"]
+    #[name = "5: A>B + C>D"]
+    TwoPairs,
+    /// A -> (B + C + D)
+    #[id = "one_to_three"]
+    #[name = "6: A>(B+C+D)"]
+    OneToThree,
+    /// (A -> B) + C + D
+    #[id = "pair_and_two_carriers"]
+    #[name = "7: A>B + C + D"]
+    PairAndTwoCarriers,
+    /// A + B + C + D
+    #[id = "additive"]
+    #[name = "8: A+B+C+D"]
+    Additive,
+}
+
+impl FmAlgorithm {
+    /// The phase modulation routes of this algorithm as `(modulator, carrier)` operator indices.
+    pub const fn routes(self) -> &'static [(usize, usize)] {
+        match self {
+            Self::Stack => &[(A, B), (B, C), (C, D)],
+            Self::DualModulatorStack => &[(A, C), (B, C), (C, D)],
+            Self::BranchStack => &[(B, C), (A, D), (C, D)],
+            Self::StackBranch => &[(A, B), (B, D), (C, D)],
+            Self::TwoPairs => &[(A, B), (C, D)],
+            Self::OneToThree => &[(A, B), (A, C), (A, D)],
+            Self::PairAndTwoCarriers => &[(A, B)],
+            Self::Additive => &[],
+        }
+    }
+
+    /// Which operators are mixed into the output of the voice.
+    pub const fn carriers(self) -> [bool; NUM_OPERATORS] {
+        match self {
+            Self::Stack | Self::DualModulatorStack | Self::BranchStack | Self::StackBranch => {
+                [false, false, false, true]
+            }
+            Self::TwoPairs => [false, true, false, true],
+            Self::OneToThree | Self::PairAndTwoCarriers => [false, true, true, true],
+            Self::Additive => [true, true, true, true],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_ALGORITHMS: [FmAlgorithm; 8] = [
+        FmAlgorithm::Stack,
+        FmAlgorithm::DualModulatorStack,
+        FmAlgorithm::BranchStack,
+        FmAlgorithm::StackBranch,
+        FmAlgorithm::TwoPairs,
+        FmAlgorithm::OneToThree,
+        FmAlgorithm::PairAndTwoCarriers,
+        FmAlgorithm::Additive,
+    ];
+
+    #[test]
+    fn test_routes_are_feed_forward() {
+        // The voice renders the operators in order, so a modulator must come before its carrier
+        for algorithm in ALL_ALGORITHMS {
+            for &(modulator, carrier) in algorithm.routes() {
+                assert!(
+                    modulator < carrier,
+                    "{algorithm:?} routes {modulator} into {carrier}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_algorithm_has_a_carrier() {
+        for algorithm in ALL_ALGORITHMS {
+            assert!(algorithm.carriers().contains(&true));
+        }
+    }
+
+    #[test]
+    fn test_modulators_are_not_carriers() {
+        // In the preset algorithms an operator either feeds another operator or the output
+        for algorithm in ALL_ALGORITHMS {
+            let carriers = algorithm.carriers();
+            for &(modulator, _) in algorithm.routes() {
+                assert!(!carriers[modulator]);
+            }
+        }
+    }
+}
@@ -1,24 +1,111 @@
+use std::sync::Arc;
+
 use crate::clock::Clock;
-use crate::sin_osc::SinOsc;
+use crate::key_scaling::KeyScaling;
+use crate::wavetable::{Interpolation, TableSize, Waveform, WavetableOsc, Wavetables};
+use nih_plug::prelude::Enum;
 use nih_plug::util;
 
 // An FM core has a single oscillator and an envelope
 
+/// Converts a coarse ratio setting to a frequency ratio. Like on the DX7, 0 is half the
+/// frequency of the note and the other settings are whole multiples.
+pub const fn coarse_ratio(coarse: i32) -> f32 {
+    if coarse <= 0 {
+        0.5
+    } else {
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = coarse as f32;
+        ratio
+    }
+}
+
+/// The coarse and fine settings that come closest to a frequency ratio. This converts the single
+/// ratio of the operators of older versions of the plugin, which went from 0 to 10.
+pub fn coarse_and_fine(ratio: f32) -> (i32, f32) {
+    #[allow(clippy::cast_possible_truncation)]
+    let coarse = ratio.floor().clamp(0.0, 31.0) as i32;
+    let fine = (ratio / coarse_ratio(coarse) - 1.0).clamp(0.0, 0.99);
+    (coarse, fine)
+}
+
+/// The range of the exponential velocity curve at full sensitivity, in decibels
+const EXPONENTIAL_VELOCITY_RANGE_DB: f32 = 48.0;
+
+/// How the velocity of a note maps to the output level of an operator.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum VelocityCurve {
+    /// The level is proportional to the velocity
+    #[default]
+    #[id = "linear"]
+    #[name = "Linear"]
+    Linear,
+    /// Soft notes are louder than linear, so the operator is easy to bring in
+    #[id = "soft"]
+    #[name = "Soft"]
+    Soft,
+    /// Soft notes are quieter than linear, so the operator only comes in on hard notes
+    #[id = "hard"]
+    #[name = "Hard"]
+    Hard,
+    /// The level in decibels is proportional to the velocity, like on the DX7
+    #[id = "exponential"]
+    #[name = "Exponential"]
+    Exponential,
+}
+
+impl VelocityCurve {
+    /// The gain of an operator for a note of `velocity`, in `[0, 1]`. A `sensitivity` of 0 plays
+    /// every note at full level, and a `sensitivity` of 1 uses the whole curve.
+    pub fn scale(self, velocity: f32, sensitivity: f32) -> f32 {
+        let velocity = velocity.clamp(0.0, 1.0);
+        let level = match self {
+            Self::Linear => velocity,
+            Self::Soft => velocity.sqrt(),
+            Self::Hard => velocity * velocity,
+            Self::Exponential => {
+                return util::db_to_gain(
+                    -EXPONENTIAL_VELOCITY_RANGE_DB * sensitivity * (1.0 - velocity),
+                );
+            }
+        };
+        sensitivity.mul_add(level - 1.0, 1.0)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct FmCore {
     // TODO: Remove pub from these fields
     midi_note: u8,
     pub note_velocity: f32, // amplitude in dB
     output_value: f32,      // The last output value. This is used for self Feedback
-    velocity_scale: f32,    // How much the note velocity affects the output amplitude
+    velocity_scale: f32,    // The gain of the output for the note velocity
+    key_scale: f32,         // The gain of the output for the played key
     // -- table source
-    sin_osc: SinOsc,
+    osc: WavetableOsc,
     voice_id: Option<i32>,
     midi_channel: u8,
     // -- Timebase
     pub clock: Clock,
     // -- For PM/FM
-    pub ratio: f32,
+    // The frequency ratio is `coarse * (1 + fine)`, detuned by `detune` cents
+    pub coarse: f32,
+    pub fine: f32,
+    pub detune: f32,
+    /// The frequency in Hz when the core ignores the played note, like a DX7 operator in fixed
+    /// mode. The ratio is not used then.
+    pub fixed_frequency: Option<f32>,
+    /// How much the note velocity affects the output level, from 0 to 1, and how.
+    pub velocity_sensitivity: f32,
+    pub velocity_curve: VelocityCurve,
+    /// How the output level changes with the played key
+    pub key_scaling: KeyScaling,
+    /// How far the played note is bent, in semitones. A fixed frequency is not bent.
+    pub pitch_bend: f32,
+    // The pitch of a glide to the played note, in semitones from the note, and how far it moves
+    // towards the note every sample
+    glide_offset: f32,
+    glide_step: f32,
 }
 
 impl FmCore {
@@ -26,28 +113,94 @@ impl FmCore {
         Self {
             midi_note: 0,
             note_velocity: 0.0,
-            velocity_scale: 1.0,
+            velocity_scale: 0.0,
+            key_scale: 1.0,
             output_value: 0.0,
-            sin_osc: SinOsc::new(),
+            osc: WavetableOsc::new(),
             voice_id: None,
             midi_channel: 0,
             clock: Clock::new(),
-            ratio: 1.0,
+            coarse: 1.0,
+            fine: 0.0,
+            detune: 0.0,
+            fixed_frequency: None,
+            velocity_sensitivity: 1.0,
+            velocity_curve: VelocityCurve::Linear,
+            key_scaling: KeyScaling::default(),
+            pitch_bend: 0.0,
+            glide_offset: 0.0,
+            glide_step: 0.0,
         }
     }
     pub fn reset(&mut self) {
         self.note_velocity = 0.0;
+        self.velocity_scale = 0.0;
         self.output_value = 0.0;
+        self.glide_offset = 0.0;
         self.clock.reset();
     }
 
+    /// The ratio of the frequency of the core to the frequency of the note
+    pub fn ratio(&self) -> f32 {
+        self.coarse * (1.0 + self.fine) * (self.detune / 1200.0).exp2()
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.osc.set_waveform(waveform);
+    }
+
+    /// See `WavetableOsc::set_quality`.
+    pub fn set_quality(&mut self, interpolation: Interpolation, table_size: TableSize) {
+        self.osc.set_quality(interpolation, table_size);
+    }
+
+    /// Shares a new bank of waveforms with the oscillator, see `WavetableOsc::set_wavetables`.
+    pub fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        self.osc.set_wavetables(wavetables);
+    }
+
+    /// The pitch the core plays as a fractional MIDI note, on its way to the note while it
+    /// glides. Pitch bend is not included.
+    pub fn pitch(&self) -> f32 {
+        f32::from(self.midi_note) + self.glide_offset
+    }
+
+    /// Glides the pitch from `pitch`, a fractional MIDI note, to the played note in
+    /// `glide_time_msec`. The frequency of the clock follows the pitch, so the glide sounds even
+    /// across the keyboard.
+    pub fn glide(&mut self, pitch: f32, glide_time_msec: f32, sample_rate: f32) {
+        let glide_samples = glide_time_msec / 1000.0 * sample_rate;
+        if glide_samples < 1.0 {
+            self.glide_offset = 0.0;
+            return;
+        }
+        self.glide_offset = pitch - f32::from(self.midi_note);
+        self.glide_step = self.glide_offset.abs() / glide_samples;
+    }
+
+    /// Plays another note without starting over, for legato. The clock keeps its phase and the
+    /// levels of the first note are kept.
+    pub fn change_note(&mut self, note: u8) {
+        self.midi_note = note;
+        self.glide_offset = 0.0;
+    }
+
     pub fn render(&mut self, sample_rate: f32) -> f32 {
-        // convert the midi note to a frequency
-        let frequency = util::midi_note_to_freq(self.midi_note);
+        // convert the bent and gliding midi note to a frequency, unless the frequency is fixed
+        let frequency = self.fixed_frequency.unwrap_or_else(|| {
+            util::midi_note_to_freq(self.midi_note)
+                * ((self.pitch_bend + self.glide_offset) / 12.0).exp2()
+                * self.ratio()
+        });
+        self.glide_offset = if self.glide_offset > 0.0 {
+            (self.glide_offset - self.glide_step).max(0.0)
+        } else {
+            (self.glide_offset + self.glide_step).min(0.0)
+        };
         // set the frequency of the oscillator
-        self.clock.set_freq(frequency * self.ratio, sample_rate);
-        self.output_value = self.sin_osc.read_osc(self.clock.mcounter);
-        self.output_value *= self.note_velocity * self.velocity_scale;
+        self.clock.set_freq(frequency, sample_rate);
+        self.output_value = self.osc.read_osc(self.clock.mcounter);
+        self.output_value *= self.velocity_scale * self.key_scale;
         self.clock.advance_wrap_clock(1.0);
         self.output_value
     }
@@ -61,7 +214,12 @@ impl FmCore {
         midi_channel: u8,
     ) {
         self.note_velocity = velocity;
+        self.velocity_scale = self
+            .velocity_curve
+            .scale(velocity, self.velocity_sensitivity);
+        self.key_scale = self.key_scaling.gain(note);
         self.midi_note = note;
+        self.glide_offset = 0.0;
         self.voice_id = voice_id;
         self.midi_channel = midi_channel;
         self.clock.reset();
@@ -99,4 +257,146 @@ mod tests {
         let output_5 = fm_core.render(sample_rate);
         assert_relative_eq!(output_5, 0.0);
     }
+
+    #[test]
+    fn test_ratio() {
+        let mut fm_core = FmCore::new();
+        assert_relative_eq!(fm_core.ratio(), 1.0);
+        fm_core.coarse = coarse_ratio(0);
+        assert_relative_eq!(fm_core.ratio(), 0.5);
+        fm_core.coarse = coarse_ratio(2);
+        fm_core.fine = 0.5;
+        assert_relative_eq!(fm_core.ratio(), 3.0);
+        // An octave of detune doubles the ratio
+        fm_core.detune = 1200.0;
+        assert_relative_eq!(fm_core.ratio(), 6.0);
+        fm_core.detune = -1200.0;
+        assert_relative_eq!(fm_core.ratio(), 1.5);
+    }
+
+    #[test]
+    fn test_coarse_and_fine() {
+        assert_eq!(coarse_and_fine(1.0), (1, 0.0));
+        let (coarse, fine) = coarse_and_fine(3.5);
+        assert_eq!(coarse, 3);
+        assert_relative_eq!(fine, 0.5 / 3.0);
+        // Below 1 the coarse setting is half the note
+        let (coarse, fine) = coarse_and_fine(0.75);
+        assert_eq!(coarse, 0);
+        assert_relative_eq!(fine, 0.5);
+        // Ratios below 0.5 are as low as it goes
+        assert_eq!(coarse_and_fine(0.1), (0, 0.0));
+    }
+
+    #[test]
+    fn test_fixed_frequency_ignores_note() {
+        let sample_rate = 1760.0;
+        let render = |note: u8| {
+            let mut fm_core = FmCore::new();
+            fm_core.fixed_frequency = Some(440.0);
+            fm_core.coarse = 3.0;
+            fm_core.note_on(note, 1.0, sample_rate, None, 0);
+            (0..8)
+                .map(|_| fm_core.render(sample_rate))
+                .collect::<Vec<_>>()
+        };
+        let output = render(30);
+        assert_eq!(output, render(90));
+        // A quarter of a cycle every sample, like `test_render`
+        assert_relative_eq!(output[1], 1.0);
+        assert_relative_eq!(output[3], -1.0);
+    }
+
+    #[test]
+    fn test_pitch_bend() {
+        let sample_rate = 1760.0;
+        let render = |note: u8, pitch_bend: f32| {
+            let mut fm_core = FmCore::new();
+            fm_core.pitch_bend = pitch_bend;
+            fm_core.note_on(note, 1.0, sample_rate, None, 0);
+            (0..8)
+                .map(|_| fm_core.render(sample_rate))
+                .collect::<Vec<_>>()
+        };
+        // Bending an octave up sounds like the note an octave higher
+        for (bent, played) in render(57, 12.0).iter().zip(render(69, 0.0)) {
+            assert_relative_eq!(*bent, played, epsilon = 1e-4);
+        }
+        for (bent, played) in render(69, -2.0).iter().zip(render(67, 0.0)) {
+            assert_relative_eq!(*bent, played, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_pitch_bend_ignored_by_fixed_frequency() {
+        let sample_rate = 1760.0;
+        let render = |pitch_bend: f32| {
+            let mut fm_core = FmCore::new();
+            fm_core.fixed_frequency = Some(440.0);
+            fm_core.pitch_bend = pitch_bend;
+            fm_core.note_on(60, 1.0, sample_rate, None, 0);
+            (0..8)
+                .map(|_| fm_core.render(sample_rate))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(render(0.0), render(7.0));
+    }
+
+    #[test]
+    fn test_velocity_curves() {
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+            VelocityCurve::Exponential,
+        ] {
+            // Without sensitivity every note is at full level, and hard notes always are
+            assert_relative_eq!(curve.scale(0.2, 0.0), 1.0);
+            assert_relative_eq!(curve.scale(1.0, 1.0), 1.0);
+        }
+        assert_relative_eq!(VelocityCurve::Linear.scale(0.25, 1.0), 0.25);
+        assert_relative_eq!(VelocityCurve::Linear.scale(0.25, 0.5), 0.625);
+        assert_relative_eq!(VelocityCurve::Soft.scale(0.25, 1.0), 0.5);
+        assert_relative_eq!(VelocityCurve::Hard.scale(0.5, 1.0), 0.25);
+        assert_relative_eq!(
+            VelocityCurve::Exponential.scale(0.5, 1.0),
+            util::db_to_gain(-24.0)
+        );
+    }
+
+    #[test]
+    fn test_velocity_sensitivity_scales_output() {
+        let sample_rate = 1760.0;
+        let render = |velocity: f32, sensitivity: f32| {
+            let mut fm_core = FmCore::new();
+            fm_core.velocity_sensitivity = sensitivity;
+            fm_core.note_on(69, velocity, sample_rate, None, 0);
+            fm_core.render(sample_rate);
+            fm_core.render(sample_rate)
+        };
+        assert_relative_eq!(render(0.5, 1.0), 0.5);
+        assert_relative_eq!(render(0.5, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_glide() {
+        let sample_rate = 1000.0;
+        let mut fm_core = FmCore::new();
+        fm_core.note_on(69, 1.0, sample_rate, None, 0);
+        fm_core.glide(57.0, 10.0, sample_rate);
+        assert_relative_eq!(fm_core.pitch(), 57.0);
+        for _ in 0..5 {
+            fm_core.render(sample_rate);
+        }
+        assert_relative_eq!(fm_core.pitch(), 63.0, epsilon = 1e-4);
+        // The glide stops at the note
+        for _ in 0..10 {
+            fm_core.render(sample_rate);
+        }
+        assert_relative_eq!(fm_core.pitch(), 69.0);
+        // Without a glide time the pitch jumps to the new note
+        fm_core.change_note(60);
+        fm_core.glide(69.0, 0.0, sample_rate);
+        assert_relative_eq!(fm_core.pitch(), 60.0);
+    }
 }
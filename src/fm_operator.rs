@@ -1,89 +1,160 @@
-use nih_plug::nih_error;
+use std::sync::Arc;
 
-use crate::fm_core::FmCore;
-use crate::linear_eg::EnvelopeGenerator;
-use crate::linear_eg::{self};
+use crate::fm_core::{FmCore, VelocityCurve};
+use crate::key_scaling::KeyScaling;
+use crate::linear_eg::{EGParameters, EnvelopeGenerator};
+use crate::multi_mode_eg::MultiModeEG;
+use crate::wavetable::{Interpolation, TableSize, Waveform, Wavetables};
+
+/// The highest DX7 style feedback setting.
+pub const MAX_FEEDBACK: i32 = 7;
+
+/// Converts a DX7 style feedback setting (0-7) to a feedback depth in cycles. Every step doubles
+/// the depth. The highest setting modulates the phase by up to a quarter cycle, which is as far as
+/// the averaged feedback path goes before it breaks up into an oscillation at Nyquist.
+pub fn feedback_depth(feedback: i32) -> f32 {
+    if feedback <= 0 {
+        0.0
+    } else {
+        0.25 * 2.0_f32.powi(feedback.min(MAX_FEEDBACK) - MAX_FEEDBACK)
+    }
+}
 
 /// An operator is one of several oscillators in an FM voice.
 pub struct Operator {
     // TODO: Should probably refactor to make the fields private
     pub core: FmCore,
-    pub eg: linear_eg::LinearEG,
-    last_output: f32,                 // used for self modulation (feedback)
-    pub output_buffer: Vec<Vec<f32>>, // 2D output buffer for stereo
-    pm_input: Vec<f32>,
+    pub eg: MultiModeEG,
+    last_output: f32,     // used for self modulation (feedback)
+    previous_output: f32, // the output before `last_output`
+    /// The last sample of `output_buffer`, which modulates the other operators. It outlives the
+    /// block, so an operator rendered later in the sample is always heard a sample late.
+    output: f32,
+    /// The output of the operator. It is mono: the voice pans the carriers when it mixes them.
+    pub output_buffer: Vec<f32>,
+    eg_buffer: Vec<f32>,
 }
 
 impl Operator {
     pub fn new() -> Self {
         Self {
             core: FmCore::new(),
-            eg: linear_eg::LinearEG::new(),
+            eg: MultiModeEG::new(),
             last_output: 0.0,
-            output_buffer: vec![vec![0.0; 1]; 2],
-            pm_input: vec![0.0; 1],
+            previous_output: 0.0,
+            output: 0.0,
+            output_buffer: vec![0.0; 1],
+            eg_buffer: vec![0.0; 1],
         }
     }
-    pub fn reset(&mut self, params: &crate::voice_utils::Parameters) {
+    pub fn reset(&mut self, eg_params: &EGParameters) {
         self.core.reset();
-        self.eg.reset(&params.eg_params);
+        self.eg.reset(eg_params);
+        self.last_output = 0.0;
+        self.previous_output = 0.0;
+        self.output = 0.0;
     }
-    pub fn initialize(&mut self, num_channels: usize, max_samples_per_channel: usize) {
-        self.output_buffer = vec![vec![0.0; max_samples_per_channel]; num_channels];
-        self.pm_input = vec![0.0; max_samples_per_channel];
+    pub fn initialize(&mut self, max_samples_per_channel: usize) {
+        self.output_buffer = vec![0.0; max_samples_per_channel];
+        self.eg_buffer = vec![0.0; max_samples_per_channel];
     }
 
-    pub fn update_core_ratio(&mut self, new_ratio: f32) {
-        self.core.ratio = new_ratio;
+    pub fn update_core_ratio(&mut self, coarse: f32, fine: f32, detune: f32) {
+        self.core.coarse = coarse;
+        self.core.fine = fine;
+        self.core.detune = detune;
     }
 
-    pub fn render(
+    pub fn update_core_fixed_frequency(&mut self, fixed_frequency: Option<f32>) {
+        self.core.fixed_frequency = fixed_frequency;
+    }
+
+    pub fn update_core_velocity_sensitivity(&mut self, sensitivity: f32, curve: VelocityCurve) {
+        self.core.velocity_sensitivity = sensitivity;
+        self.core.velocity_curve = curve;
+    }
+
+    pub fn update_core_key_scaling(&mut self, key_scaling: KeyScaling) {
+        self.core.key_scaling = key_scaling;
+    }
+
+    pub fn update_core_waveform(&mut self, waveform: Waveform) {
+        self.core.set_waveform(waveform);
+    }
+
+    pub fn update_core_quality(&mut self, interpolation: Interpolation, table_size: TableSize) {
+        self.core.set_quality(interpolation, table_size);
+    }
+
+    pub fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        self.core.set_wavetables(wavetables);
+    }
+
+    pub fn update_core_pitch_bend(&mut self, semitones: f32) {
+        self.core.pitch_bend = semitones;
+    }
+
+    /// The pitch of the core as a fractional MIDI note, see `FmCore::pitch`.
+    pub fn pitch(&self) -> f32 {
+        self.core.pitch()
+    }
+
+    pub fn glide(&mut self, pitch: f32, glide_time_msec: f32, sample_rate: f32) {
+        self.core.glide(pitch, glide_time_msec, sample_rate);
+    }
+
+    /// Plays another note without restarting the envelope, for legato.
+    pub fn change_note(&mut self, note: u8) {
+        self.core.change_note(note);
+    }
+
+    /// Renders the envelope of the operator for the next block. The samples of the block are
+    /// then rendered one at a time with `render_sample`, so the operators of a voice can
+    /// modulate each other sample by sample.
+    pub fn start_block(
         &mut self,
         num_samples_to_process: usize,
-        _params: &crate::voice_utils::Parameters,
+        eg_params: &EGParameters,
         sample_rate: f32,
-        self_modulation: bool,
-        index: f32,
     ) {
-        // add the output of core to the phase modulation buffer
-        for sample_index in 0..num_samples_to_process {
-            // We will not batch process the eg value for this
-            // let eg_value = self.eg.render(&params.eg_params, 1, sample_rate);
-            if self_modulation {
-                // add the output of the core to the phase modulation buffer
-                self.pm_input[sample_index] = self.last_output; // TODO: We may need some sort of feedback value here to make things not explode
-            }
-            // modulate the phase by the pm_input
-            self.core
-                .clock
-                .add_phase_offset(self.pm_input[sample_index] * index, true);
-            let core_output = self.core.render(sample_rate);
-            self.core.clock.remove_phase_offset();
-            self.last_output = core_output;
-            for chanel in &mut self.output_buffer {
-                chanel[sample_index] = self.last_output;
-            }
-        }
-        // Zero out the pm_input buffer using the fill method
-        self.pm_input.fill(0.0);
+        self.eg.render(
+            eg_params,
+            &mut self.eg_buffer[..num_samples_to_process],
+            sample_rate,
+        );
     }
 
-    #[allow(clippy::cast_precision_loss)]
-    pub fn add_pm_source(&mut self, other_operator: &Self) {
-        // ensure that the pm_input buffer is the same size as the other operator's output buffer
-        if self.pm_input.len() != other_operator.output_buffer[0].len() {
-            nih_error!(
-                "The pm_input buffer is not the same size as the other operator's output buffer"
-            );
-        }
-        // get the number of channels in the other operator
-        let num_channels = other_operator.output_buffer.len();
-        let channel_weight = 1.0 / num_channels as f32;
-        for channel in &other_operator.output_buffer {
-            for (sample_index, sample) in channel.iter().enumerate() {
-                self.pm_input[sample_index] += sample * channel_weight;
-            }
-        }
+    /// Renders the sample at `sample_index` of the block into the output buffer. The output is
+    /// scaled by the operator's envelope, so the envelope also shapes the depth of the
+    /// modulation into other operators, and by `gain`, the tremolo.
+    ///
+    /// `pm_input` is the phase modulation by the other operators and `feedback` the depth of the
+    /// self modulation, both in cycles. The feedback path uses the average of the last two
+    /// outputs like the DX7 does, which cancels the oscillation at Nyquist that a single sample
+    /// of feedback builds up at high depths.
+    pub fn render_sample(
+        &mut self,
+        sample_index: usize,
+        pm_input: f32,
+        feedback: f32,
+        gain: f32,
+        sample_rate: f32,
+    ) {
+        // add the averaged previous outputs of the core to the phase modulation
+        let pm_input =
+            ((self.last_output + self.previous_output) * 0.5).mul_add(feedback, pm_input);
+        self.core.clock.add_phase_offset(pm_input, true);
+        let core_output = self.core.render(sample_rate) * self.eg_buffer[sample_index];
+        self.core.clock.remove_phase_offset();
+        self.previous_output = self.last_output;
+        self.last_output = core_output;
+        self.output = core_output * gain;
+        self.output_buffer[sample_index] = self.output;
+    }
+
+    /// The last sample the operator rendered, with the tremolo.
+    pub const fn output(&self) -> f32 {
+        self.output
     }
 
     pub fn note_on(
@@ -92,16 +163,108 @@ impl Operator {
         velocity: f32,
         voice_id: Option<i32>,
         channel: u8,
-        params: &crate::voice_utils::Parameters,
+        eg_params: &EGParameters,
         sample_rate: f32,
     ) {
         self.core
             .note_on(note, velocity, sample_rate, voice_id, channel);
-        self.eg.note_on(&params.eg_params, sample_rate);
+        self.eg.note_on(eg_params, note, sample_rate);
     }
 
-    pub fn note_off(&mut self, params: &crate::voice_utils::Parameters, sample_rate: f32) {
+    pub fn note_off(&mut self, eg_params: &EGParameters, sample_rate: f32) {
         self.core.note_off();
-        self.eg.note_off(&params.eg_params, sample_rate);
+        self.eg.note_off(eg_params, sample_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    /// Renders a block of the operator on its own.
+    fn render(
+        operator: &mut Operator,
+        num_samples: usize,
+        eg_params: &EGParameters,
+        feedback: f32,
+    ) {
+        operator.start_block(num_samples, eg_params, SAMPLE_RATE);
+        for sample_index in 0..num_samples {
+            operator.render_sample(sample_index, 0.0, feedback, 1.0, SAMPLE_RATE);
+        }
+    }
+
+    fn render_operator(feedback: f32, num_samples: usize) -> Vec<f32> {
+        // A short attack into full sustain, so the envelope barely changes the sine
+        let eg_params = EGParameters {
+            attack_time_msec: 1.0,
+            sustain_level: 1.0,
+            ..Default::default()
+        };
+        let mut operator = Operator::new();
+        operator.initialize(num_samples);
+        operator.note_on(45, 1.0, None, 0, &eg_params, SAMPLE_RATE);
+        render(&mut operator, num_samples, &eg_params, feedback);
+        operator.output_buffer.clone()
+    }
+
+    #[test]
+    fn test_envelope_scales_output() {
+        // A pluck: the operator should be silent once the decay reaches the sustain level of 0
+        let eg_params = EGParameters {
+            attack_time_msec: 1.0,
+            decay_time_msec: 10.0,
+            sustain_level: 0.0,
+            ..Default::default()
+        };
+        let num_samples = 1024;
+        let mut operator = Operator::new();
+        operator.initialize(num_samples);
+        operator.note_on(69, 1.0, None, 0, &eg_params, SAMPLE_RATE);
+        render(&mut operator, num_samples, &eg_params, 0.0);
+        let output = &operator.output_buffer;
+        assert!(output[..100].iter().any(|sample| sample.abs() > 0.1));
+        assert!(output[600..].iter().all(|sample| sample.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_feedback_depth() {
+        assert_relative_eq!(feedback_depth(0), 0.0);
+        assert_relative_eq!(feedback_depth(MAX_FEEDBACK), 0.25);
+        assert_relative_eq!(feedback_depth(MAX_FEEDBACK - 1), 0.125);
+        // Out of range settings are clamped
+        assert_relative_eq!(feedback_depth(-1), 0.0);
+        assert_relative_eq!(feedback_depth(MAX_FEEDBACK + 1), 0.25);
+    }
+
+    #[test]
+    fn test_feedback_changes_output() {
+        let sine = render_operator(0.0, 512);
+        let with_feedback = render_operator(feedback_depth(4), 512);
+        assert!(sine
+            .iter()
+            .zip(&with_feedback)
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+    }
+
+    #[test]
+    fn test_max_feedback_is_stable() {
+        // With a single sample of feedback, the output at this depth turns into an oscillation at
+        // Nyquist. The averaged path should cross zero about as often as the plain sine does.
+        fn zero_crossings(output: &[f32]) -> usize {
+            output
+                .windows(2)
+                .filter(|pair| pair[0].signum() != pair[1].signum())
+                .count()
+        }
+        let sine = render_operator(0.0, 4096);
+        let output = render_operator(feedback_depth(MAX_FEEDBACK), 4096);
+        assert!(output
+            .iter()
+            .all(|sample| sample.is_finite() && sample.abs() <= 1.0));
+        assert!(zero_crossings(&output) <= zero_crossings(&sine) + 2);
     }
 }
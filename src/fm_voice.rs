@@ -1,55 +1,101 @@
+use std::sync::Arc;
+
+use nih_plug::prelude::{Smoother, SmoothingStyle};
+
 use crate::{
-    fm_operator::Operator,
-    linear_eg::{EnvelopeGenerator, LinearEG},
-    voice_utils::{MidiEvent, Voice},
+    consts::NUM_OPERATORS,
+    filter::VoiceFilter,
+    fm_operator::{feedback_depth, Operator, MAX_FEEDBACK},
+    lfo::{Lfo, LfoModulation, LfoScope, NUM_LFOS},
+    linear_eg::EnvelopeGenerator,
+    mono::HeldNote,
+    multi_mode_eg::MultiModeEG,
+    pan::constant_power_gains,
+    voice_utils::{
+        MidiEvent, NoteExpression, Parameters, PolyModulationTarget, UnisonVoice, Voice, VoiceNote,
+        NUM_POLY_MODULATION_TARGETS,
+    },
+    wavetable::Wavetables,
 };
 
+/// The time it takes a voice to follow a change of its poly modulation
+const POLY_MODULATION_SMOOTHING_MSEC: f32 = 5.0;
+
 /// This is an FM Synth voice that implements the Voice trait.
 /// It is modeled on section 16.8 in the book "Designing Software
 /// Synthesizer Plugins in C++: 2nd Edition" by Will Pirkle.
 pub struct FmVoice {
-    operator_a: Operator,
-    operator_b: Operator,
-    operator_c: Operator,
-    operator_d: Operator,
-    eg: LinearEG,
-    // TODO: Add a filter
+    /// Operators A, B, C and D, in rendering order
+    operators: [Operator; NUM_OPERATORS],
+    /// The amplitude envelope of the whole voice, on top of the envelope of every operator
+    eg: MultiModeEG,
+    /// The filter of the mix of the carriers, with an envelope of its own
+    filter: VoiceFilter,
+    /// The per voice LFOs. The ones that are global run in the voice group instead.
+    lfos: [Lfo; NUM_LFOS],
     _id: Option<i32>,
     // TODO: decide if there should be some other way to handle the output
     is_stealing: bool,
     current_midi_event: Option<MidiEvent>,
     next_midi_event: Option<MidiEvent>,
+    /// The note the voice plays, which stays after the note off until the release is done
+    played_note: Option<VoiceNote>,
+    /// The modulations of the parameters for the playing note, by target ID: the normalized
+    /// offset sent by the host and the modulated value
+    poly_modulations: [Option<(f32, Smoother<f32>)>; NUM_POLY_MODULATION_TARGETS],
+    /// The pitch bend of all the voices, in semitones
+    pitch_bend: f32,
+    /// The vibrato of the LFOs during the block, in semitones
+    lfo_pitch: f32,
+    /// The tremolo gain of every operator at the end of the last block, which the next block
+    /// ramps from. `None` until the first block of a note.
+    tremolo_gains: Option<[f32; NUM_OPERATORS]>,
+    // The note expressions of the playing note
+    tuning: f32,
+    pressure: f32,
+    brightness: f32,
+    /// The detune, pan and gain of the voice among the voices of its note
+    unison: UnisonVoice,
+    /// The unison of the note that waits for a steal to finish
+    next_unison: UnisonVoice,
     output_buffer: Vec<Vec<f32>>, // 2D output buffer for stereo
+    eg_buffer: Vec<f32>,
 }
 
 impl Voice for FmVoice {
     fn new() -> Self {
         Self {
-            operator_a: Operator::new(),
-            operator_b: Operator::new(),
-            operator_c: Operator::new(),
-            operator_d: Operator::new(),
-
-            eg: LinearEG::new(),
+            operators: std::array::from_fn(|_| Operator::new()),
+            eg: MultiModeEG::new(),
+            filter: VoiceFilter::new(),
+            lfos: std::array::from_fn(|_| Lfo::new()),
             _id: None,
             is_stealing: false,
             current_midi_event: None,
             next_midi_event: None,
+            played_note: None,
+            poly_modulations: std::array::from_fn(|_| None),
+            pitch_bend: 0.0,
+            lfo_pitch: 0.0,
+            tremolo_gains: None,
+            tuning: 0.0,
+            pressure: 0.0,
+            brightness: 0.0,
+            unison: UnisonVoice::default(),
+            next_unison: UnisonVoice::default(),
             output_buffer: vec![vec![0.0; 1]; 2],
+            eg_buffer: vec![0.0; 1],
         }
     }
 
     fn initialize(&mut self, num_channels: usize, max_samples_per_channel: usize) {
-        for operator in [
-            &mut self.operator_a,
-            &mut self.operator_b,
-            &mut self.operator_c,
-            &mut self.operator_d,
-        ] {
-            operator.initialize(num_channels, max_samples_per_channel);
+        for operator in &mut self.operators {
+            operator.initialize(max_samples_per_channel);
         }
 
         self.output_buffer = vec![vec![0.0; max_samples_per_channel]; num_channels];
+        self.eg_buffer = vec![0.0; max_samples_per_channel];
+        self.filter.initialize(max_samples_per_channel);
     }
 
     fn render(
@@ -57,74 +103,94 @@ impl Voice for FmVoice {
         num_samples_to_process: usize,
         params: &crate::voice_utils::Parameters,
         sample_rate: f32,
-    ) {
-        // update the ratio of the core A oscillator
+    ) -> Option<VoiceNote> {
+        let mut params = self.modulated_parameters(params, num_samples_to_process);
+        let lfo = self.render_lfos(&params, num_samples_to_process, sample_rate);
+        Self::apply_lfo(&mut params, &lfo);
+        self.lfo_pitch = lfo.pitch;
+        self.update_core_pitch_bends();
+        let params = &params;
         self.update_core_ratios(params);
-        // Core A phase modulates Core B. Core A is the modulator and Core B is the carrier.
-        // The EG output is then multiplied by the output of Core B.
-
-        // get the length of the audio buffer
-        let eg_value = self
-            .eg
-            .render(&params.eg_params, num_samples_to_process, sample_rate);
+        // The algorithm and the modulation matrix decide which operators phase modulate which.
+        // The EG output is then multiplied by the mix of the carriers.
+        let algorithm = params.fm_params.algorithm;
 
-        self.operator_a.render(
-            num_samples_to_process,
-            params,
+        self.eg.render(
+            &params.eg_params,
+            &mut self.eg_buffer[..num_samples_to_process],
             sample_rate,
-            false,
-            params.fm_params.op_a_index,
         );
 
-        // copy the output of operator a into the pm input of operator b
-        self.operator_b.add_pm_source(&self.operator_a);
-        self.operator_b.render(
-            num_samples_to_process,
-            params,
-            sample_rate,
-            false,
-            params.fm_params.op_b_index,
-        );
-        self.operator_c.add_pm_source(&self.operator_b);
-        self.operator_c.render(
-            num_samples_to_process,
-            params,
-            sample_rate,
-            false,
-            params.fm_params.op_c_index,
-        );
-        self.operator_d.add_pm_source(&self.operator_c);
-        self.operator_d.render(
+        self.render_operators(num_samples_to_process, params, &lfo, sample_rate);
+
+        // mix the carriers into stereo and multiply them by the eg output. The pan of a carrier
+        // is added to the pan of the voice.
+        let carriers = algorithm.carriers();
+        let is_stereo = self.output_buffer.len() == 2;
+        for (channel, output) in self.output_buffer.iter_mut().enumerate() {
+            let carrier_gains: [f32; NUM_OPERATORS] = std::array::from_fn(|operator_index| {
+                let operator_params = &params.fm_params.operators[operator_index];
+                if !carriers[operator_index] {
+                    return 0.0;
+                }
+                let pan_gain = if is_stereo {
+                    constant_power_gains(operator_params.pan + self.unison.pan)[channel]
+                } else {
+                    1.0
+                };
+                operator_params.mix * pan_gain * self.unison.gain * params.gain
+            });
+            for (sample_index, sample) in output[..num_samples_to_process].iter_mut().enumerate() {
+                let mixed: f32 = self
+                    .operators
+                    .iter()
+                    .zip(carrier_gains)
+                    .map(|(operator, gain)| operator.output_buffer[sample_index] * gain)
+                    .sum();
+                *sample = mixed * self.eg_buffer[sample_index];
+            }
+        }
+        self.filter.process(
+            &mut self.output_buffer,
             num_samples_to_process,
-            params,
+            &params.filter,
             sample_rate,
-            false,
-            params.fm_params.op_d_index,
         );
-        // multiply the output of operator b by the eg value
-        for (channel, output) in self.output_buffer.iter_mut().enumerate() {
-            for (sample_index, sample) in output.iter_mut().enumerate() {
-                *sample += self.operator_a.output_buffer[channel][sample_index]
-                    * params.fm_params.op_a_mix;
-                *sample += self.operator_b.output_buffer[channel][sample_index]
-                    * params.fm_params.op_b_mix;
-                *sample += self.operator_c.output_buffer[channel][sample_index]
-                    * params.fm_params.op_c_mix;
-                *sample += self.operator_d.output_buffer[channel][sample_index]
-                    * params.fm_params.op_d_mix;
-                *sample *= eg_value;
-            }
+        // The voice is silent once the envelopes of all the operators are done, so its own
+        // envelope ends with them. A stolen voice then starts the next note at once.
+        if !self
+            .operators
+            .iter()
+            .any(|operator| operator.eg.is_playing())
+        {
+            self.eg.reset(&params.eg_params);
         }
+        let ended_note = if self.eg.is_playing() {
+            None
+        } else {
+            self.played_note.take()
+        };
         // Check the stealPending flag to see if the voice is being stolen, and if so:
         if self.is_stealing && !self.eg.is_playing() {
             self.finish_voice_steal(params, sample_rate);
         }
+        ended_note
     }
 
     fn reset(&mut self, params: &crate::voice_utils::Parameters) {
-        self.operator_a.reset(params);
-        self.operator_b.reset(params);
+        for (operator, operator_params) in
+            self.operators.iter_mut().zip(&params.fm_params.operators)
+        {
+            operator.reset(&operator_params.eg_params);
+        }
         self.eg.reset(&params.eg_params);
+        self.filter.reset(&params.filter);
+        for lfo in &mut self.lfos {
+            lfo.reset();
+        }
+        self.tremolo_gains = None;
+        self.played_note = None;
+        self.clear_modulations();
     }
 
     fn note_on(
@@ -148,24 +214,64 @@ impl Voice for FmVoice {
             });
             self.eg.shutdown(&params.eg_params, sample_rate);
         } else {
+            self.start_note(note, velocity, voice_id, channel, params, sample_rate);
+        }
+        // The modulations of the previous note don't apply to the new one
+        self.clear_modulations();
+    }
+
+    fn change_note(
+        &mut self,
+        held_note: HeldNote,
+        legato: bool,
+        glide_time_msec: f32,
+        params: &Parameters,
+        sample_rate: f32,
+    ) {
+        if self.is_stealing {
+            // The stolen note is still on its way out, so the new one waits for it
+            self.note_on(
+                held_note.note,
+                held_note.velocity,
+                held_note.voice_id,
+                held_note.channel,
+                params,
+                sample_rate,
+            );
+            return;
+        }
+        let pitches = self.operators.each_ref().map(Operator::pitch);
+        if legato {
             self.current_midi_event = Some(MidiEvent {
                 timing: 0,
-                voice_id,
-                channel,
-                note,
-                velocity,
+                voice_id: held_note.voice_id,
+                channel: held_note.channel,
+                note: held_note.note,
+                velocity: held_note.velocity,
             });
-            // Core A is the modulator and Core B is the carrier. Thus, Apply the fm ratio to the core a note
-            self.operator_a
-                .note_on(note, velocity, voice_id, channel, params, sample_rate);
-            self.operator_b
-                .note_on(note, velocity, voice_id, channel, params, sample_rate);
-            self.operator_c
-                .note_on(note, velocity, voice_id, channel, params, sample_rate);
-            self.operator_d
-                .note_on(note, velocity, voice_id, channel, params, sample_rate);
-            self.eg.note_on(&params.eg_params, sample_rate);
+            self.played_note = Some(VoiceNote {
+                voice_id: held_note.voice_id,
+                channel: held_note.channel,
+                note: held_note.note,
+            });
+            for operator in &mut self.operators {
+                operator.change_note(held_note.note);
+            }
+            self.filter.change_note(held_note.note);
+        } else {
+            self.start_note(
+                held_note.note,
+                held_note.velocity,
+                held_note.voice_id,
+                held_note.channel,
+                params,
+                sample_rate,
+            );
+        }
+        for (operator, pitch) in self.operators.iter_mut().zip(pitches) {
+            operator.glide(pitch, glide_time_msec, sample_rate);
         }
+        self.clear_modulations();
     }
 
     fn note_off(
@@ -190,19 +296,131 @@ impl Voice for FmVoice {
                 || (midi_event.channel == channel && midi_event.note == note)
             {
                 self.eg.note_off(&params.eg_params, sample_rate);
-                self.operator_a.note_off(params, sample_rate);
-                self.operator_b.note_off(params, sample_rate);
-                self.operator_c.note_off(params, sample_rate);
-                self.operator_d.note_off(params, sample_rate);
+                self.filter.note_off(&params.filter, sample_rate);
+                for (operator, operator_params) in
+                    self.operators.iter_mut().zip(&params.fm_params.operators)
+                {
+                    operator.note_off(&operator_params.eg_params, sample_rate);
+                }
                 self.current_midi_event = None;
             }
         }
     }
 
+    /// The voice plays until its envelope is done. The envelope is ended early when the
+    /// envelopes of all the operators are done.
     fn is_playing(&self) -> bool {
         self.eg.is_playing()
     }
 
+    fn note(&self) -> Option<VoiceNote> {
+        self.played_note
+    }
+
+    /// A note waiting for a steal to finish is dropped, and the stolen note is already on its
+    /// way out.
+    fn release(&mut self, params: &crate::voice_utils::Parameters, sample_rate: f32) {
+        self.next_midi_event = None;
+        if self.is_stealing {
+            return;
+        }
+        if let Some(midi_event) = &self.current_midi_event {
+            self.note_off(
+                midi_event.voice_id,
+                midi_event.channel,
+                midi_event.note,
+                params,
+                sample_rate,
+            );
+        }
+    }
+
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        self.update_core_pitch_bends();
+    }
+
+    fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        for operator in &mut self.operators {
+            operator.set_wavetables(wavetables);
+        }
+    }
+
+    /// While the voice is being stolen, the stolen note keeps its place and the unison waits for
+    /// the new note.
+    fn set_unison(&mut self, unison_voice: UnisonVoice) {
+        if self.is_stealing {
+            self.next_unison = unison_voice;
+            return;
+        }
+        self.unison = unison_voice;
+        self.update_core_pitch_bends();
+    }
+
+    fn set_poly_modulation(
+        &mut self,
+        voice_id: i32,
+        target: PolyModulationTarget,
+        normalized_offset: f32,
+        value: f32,
+        sample_rate: f32,
+    ) {
+        if self
+            .playing_event()
+            .is_none_or(|event| event.voice_id != Some(voice_id))
+        {
+            return;
+        }
+        match &mut self.poly_modulations[target.id() as usize] {
+            Some((offset, smoother)) => {
+                *offset = normalized_offset;
+                smoother.set_target(sample_rate, value);
+            }
+            // The first modulation of a note applies right away
+            modulation @ None => {
+                let smoother =
+                    Smoother::new(SmoothingStyle::Linear(POLY_MODULATION_SMOOTHING_MSEC));
+                smoother.reset(value);
+                *modulation = Some((normalized_offset, smoother));
+            }
+        }
+    }
+
+    fn update_poly_modulation(
+        &mut self,
+        target: PolyModulationTarget,
+        value: &dyn Fn(f32) -> f32,
+        sample_rate: f32,
+    ) {
+        if let Some((offset, smoother)) = &self.poly_modulations[target.id() as usize] {
+            smoother.set_target(sample_rate, value(*offset));
+        }
+    }
+
+    fn set_note_expression(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        expression: NoteExpression,
+    ) {
+        let plays_note = self.playing_event().is_some_and(|event| {
+            event.voice_id == voice_id || (event.channel == channel && event.note == note)
+        });
+        if plays_note {
+            self.apply_expression(expression);
+        }
+    }
+
+    fn set_channel_expression(&mut self, channel: u8, expression: NoteExpression) {
+        if self
+            .playing_event()
+            .is_some_and(|event| event.channel == channel)
+        {
+            self.apply_expression(expression);
+        }
+    }
+
     fn accumulate_output(
         &mut self,
         audio_buffer: &mut [&mut [f32]],
@@ -218,15 +436,242 @@ impl Voice for FmVoice {
 }
 
 impl FmVoice {
+    /// Starts playing a note right away
+    fn start_note(
+        &mut self,
+        note: u8,
+        velocity: f32,
+        voice_id: Option<i32>,
+        channel: u8,
+        params: &Parameters,
+        sample_rate: f32,
+    ) {
+        self.current_midi_event = Some(MidiEvent {
+            timing: 0,
+            voice_id,
+            channel,
+            note,
+            velocity,
+        });
+        self.played_note = Some(VoiceNote {
+            voice_id,
+            channel,
+            note,
+        });
+        for (operator, operator_params) in
+            self.operators.iter_mut().zip(&params.fm_params.operators)
+        {
+            operator.update_core_velocity_sensitivity(
+                operator_params.velocity_sensitivity,
+                operator_params.velocity_curve,
+            );
+            operator.update_core_key_scaling(operator_params.key_scaling);
+            operator.note_on(
+                note,
+                velocity,
+                voice_id,
+                channel,
+                &operator_params.eg_params,
+                sample_rate,
+            );
+        }
+        self.eg.note_on(&params.eg_params, note, sample_rate);
+        self.filter.note_on(&params.filter, note, sample_rate);
+        for (lfo, lfo_params) in self.lfos.iter_mut().zip(&params.lfos) {
+            if lfo_params.scope == LfoScope::Voice {
+                lfo.note_on(lfo_params, true);
+            }
+        }
+        // A new note starts at the tremolo of its first block
+        self.tremolo_gains = None;
+    }
+
+    /// The note the voice plays, or will play once it has been stolen
+    const fn playing_event(&self) -> Option<&MidiEvent> {
+        if self.is_stealing {
+            self.next_midi_event.as_ref()
+        } else {
+            self.current_midi_event.as_ref()
+        }
+    }
+
+    fn apply_expression(&mut self, expression: NoteExpression) {
+        match expression {
+            NoteExpression::Tuning(semitones) => {
+                self.tuning = semitones;
+                self.update_core_pitch_bends();
+            }
+            NoteExpression::Pressure(pressure) => self.pressure = pressure,
+            NoteExpression::Brightness(brightness) => self.brightness = brightness,
+        }
+    }
+
+    fn clear_modulations(&mut self) {
+        self.poly_modulations = std::array::from_fn(|_| None);
+        self.tuning = 0.0;
+        self.pressure = 0.0;
+        self.brightness = 0.0;
+        self.update_core_pitch_bends();
+    }
+
+    fn update_core_pitch_bends(&mut self) {
+        for operator in &mut self.operators {
+            operator.update_core_pitch_bend(
+                self.pitch_bend + self.tuning + self.lfo_pitch + self.unison.detune / 100.0,
+            );
+        }
+    }
+
+    /// The parameters with the poly modulations and the note expressions of the voice applied.
+    /// Pressure and brightness add to the mod wheel.
+    fn modulated_parameters(
+        &self,
+        params: &Parameters,
+        num_samples_to_process: usize,
+    ) -> Parameters {
+        let mut params = *params;
+        #[allow(clippy::cast_possible_truncation)]
+        let num_samples_to_process_u32 = num_samples_to_process as u32;
+        for (id, modulation) in self.poly_modulations.iter().enumerate() {
+            if let Some((_, smoother)) = modulation {
+                #[allow(clippy::cast_possible_truncation)]
+                if let Some(target) = PolyModulationTarget::from_id(id as u32) {
+                    target.apply(&mut params, smoother.next_step(num_samples_to_process_u32));
+                }
+            }
+        }
+        params.mod_wheel = (params.mod_wheel + self.pressure + self.brightness).min(1.0);
+        params
+    }
+
+    /// Runs the per voice LFOs for the block and adds them to the global ones.
+    fn render_lfos(
+        &mut self,
+        params: &Parameters,
+        num_samples_to_process: usize,
+        sample_rate: f32,
+    ) -> LfoModulation {
+        self.lfos
+            .iter_mut()
+            .zip(&params.lfos)
+            .filter(|(_, lfo_params)| lfo_params.scope == LfoScope::Voice)
+            .fold(params.global_lfo, |modulation, (lfo, lfo_params)| {
+                modulation + lfo.next_block(lfo_params, num_samples_to_process, sample_rate)
+            })
+    }
+
+    /// Scales the modulation indexes and the routes of the modulation matrix by the LFOs. The
+    /// pitch and the amplitude are modulated while rendering.
+    fn apply_lfo(params: &mut Parameters, lfo: &LfoModulation) {
+        let index_factor = lfo.index_factor();
+        for operator in &mut params.fm_params.operators {
+            operator.index *= index_factor;
+            operator.mod_wheel_index *= index_factor;
+            for depth in &mut operator.modulation {
+                *depth *= index_factor;
+            }
+        }
+    }
+
+    /// Renders the operators into their output buffers, with the tremolo of the LFOs.
+    fn render_operators(
+        &mut self,
+        num_samples_to_process: usize,
+        params: &Parameters,
+        lfo: &LfoModulation,
+        sample_rate: f32,
+    ) {
+        // The tremolo ramps across the block from the gain at the end of the last one, so its
+        // steps can't be heard
+        let tremolo_gains = params
+            .fm_params
+            .operators
+            .each_ref()
+            .map(|operator_params| lfo.gain(operator_params.amp_mod_sensitivity));
+        let previous_tremolo_gains = self.tremolo_gains.unwrap_or(tremolo_gains);
+        self.tremolo_gains = Some(tremolo_gains);
+        #[allow(clippy::cast_precision_loss)]
+        let tremolo_steps: [f32; NUM_OPERATORS] = std::array::from_fn(|operator_index| {
+            (tremolo_gains[operator_index] - previous_tremolo_gains[operator_index])
+                / num_samples_to_process as f32
+        });
+        // The matrix route from an operator into itself goes through the feedback path, which is
+        // capped at the highest feedback setting to stay stable
+        let feedbacks: [f32; NUM_OPERATORS] = std::array::from_fn(|operator_index| {
+            let operator_params = &params.fm_params.operators[operator_index];
+            (operator_params.feedback
+                + operator_params.route_depth(operator_index, params.mod_wheel))
+            .min(feedback_depth(MAX_FEEDBACK))
+        });
+        let pm_depths = Self::pm_depths(params);
+        for (operator, operator_params) in
+            self.operators.iter_mut().zip(&params.fm_params.operators)
+        {
+            operator.start_block(
+                num_samples_to_process,
+                &operator_params.eg_params,
+                sample_rate,
+            );
+        }
+        // The operators are rendered in order, one sample at a time. A modulator that comes
+        // after its carrier is heard a sample late, whatever the size of the block.
+        for sample_index in 0..num_samples_to_process {
+            #[allow(clippy::cast_precision_loss)]
+            let step = (sample_index + 1) as f32;
+            for operator_index in 0..NUM_OPERATORS {
+                let pm_input: f32 = pm_depths[operator_index]
+                    .iter()
+                    .zip(&self.operators)
+                    .map(|(depth, modulator)| depth * modulator.output())
+                    .sum();
+                let gain = tremolo_steps[operator_index]
+                    .mul_add(step, previous_tremolo_gains[operator_index]);
+                self.operators[operator_index].render_sample(
+                    sample_index,
+                    pm_input,
+                    feedbacks[operator_index],
+                    gain,
+                    sample_rate,
+                );
+            }
+        }
+    }
+
+    /// The depth of the phase modulation of every operator (the rows) by every other operator
+    /// (the columns), in cycles: the routes of the algorithm plus the routes of the modulation
+    /// matrix. Matrix routes from an operator into itself are added to the operator's feedback
+    /// instead.
+    fn pm_depths(params: &Parameters) -> [[f32; NUM_OPERATORS]; NUM_OPERATORS] {
+        let fm_params = &params.fm_params;
+        let mut depths = [[0.0; NUM_OPERATORS]; NUM_OPERATORS];
+        for &(modulator_index, carrier_index) in fm_params.algorithm.routes() {
+            depths[carrier_index][modulator_index] +=
+                fm_params.operators[modulator_index].modulation_index(params.mod_wheel);
+        }
+        for (carrier_index, carrier_depths) in depths.iter_mut().enumerate() {
+            for (modulator_index, depth) in carrier_depths.iter_mut().enumerate() {
+                if modulator_index != carrier_index {
+                    *depth += fm_params.operators[modulator_index]
+                        .route_depth(carrier_index, params.mod_wheel);
+                }
+            }
+        }
+        depths
+    }
+
     fn update_core_ratios(&mut self, params: &crate::voice_utils::Parameters) {
-        self.operator_a
-            .update_core_ratio(params.fm_params.op_a_ratio);
-        self.operator_b
-            .update_core_ratio(params.fm_params.op_b_ratio);
-        self.operator_c
-            .update_core_ratio(params.fm_params.op_c_ratio);
-        self.operator_d
-            .update_core_ratio(params.fm_params.op_d_ratio);
+        for (operator, operator_params) in
+            self.operators.iter_mut().zip(&params.fm_params.operators)
+        {
+            operator.update_core_ratio(
+                operator_params.coarse,
+                operator_params.fine,
+                operator_params.detune,
+            );
+            operator.update_core_fixed_frequency(operator_params.fixed_frequency);
+            operator.update_core_waveform(operator_params.waveform);
+            operator.update_core_quality(params.interpolation, params.table_size);
+        }
     }
     /// This should be called after the voice has been stolen and the steal operation is complete
     fn finish_voice_steal(&mut self, params: &crate::voice_utils::Parameters, sample_rate: f32) {
@@ -247,9 +692,11 @@ impl FmVoice {
         // Note: it is possible we received the `note_off` event for the `next_midi_event` during the steal
         // operation and so there is nothing to be done here.
         self.current_midi_event = self.next_midi_event.take();
+        self.unison = self.next_unison;
+        self.update_core_pitch_bends();
 
         if let Some(midi_event) = &self.current_midi_event {
-            self.note_on(
+            self.start_note(
                 midi_event.note,
                 midi_event.velocity,
                 midi_event.voice_id,
@@ -260,3 +707,415 @@ impl FmVoice {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{FilterMode, FilterParameters};
+    use crate::fm_algorithm::FmAlgorithm;
+    use crate::lfo::{LfoParameters, LfoWaveform};
+    use crate::linear_eg::{EGMode, EGParameters};
+    use crate::voice_utils::{OperatorParameters, Parameters};
+    use crate::wavetable::Interpolation;
+    use approx::assert_relative_eq;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+    const BLOCK_SIZE: usize = 64;
+
+    /// Only operator B is heard, and operator A modulates it through the matrix with `depth`
+    fn params(depth: f32) -> Parameters {
+        let mut params = Parameters::default();
+        params.fm_params.algorithm = FmAlgorithm::Additive;
+        params.fm_params.operators = [OperatorParameters {
+            coarse: 1.0,
+            ..Default::default()
+        }; NUM_OPERATORS];
+        params.fm_params.operators[1].mix = 1.0;
+        params.fm_params.operators[0].modulation[1] = depth;
+        params
+    }
+
+    fn render_blocks(params: &Parameters, num_blocks: usize) -> Vec<f32> {
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, params, SAMPLE_RATE);
+        let mut output = Vec::new();
+        for _ in 0..num_blocks {
+            voice.render(BLOCK_SIZE, params, SAMPLE_RATE);
+            output.extend_from_slice(&voice.output_buffer[0]);
+        }
+        output
+    }
+
+    #[test]
+    fn test_matrix_route_modulates_carrier() {
+        let unmodulated = render_blocks(&params(0.0), 4);
+        let modulated = render_blocks(&params(1.0), 4);
+        assert!(unmodulated.iter().any(|sample| *sample != 0.0));
+        assert!(unmodulated
+            .iter()
+            .zip(&modulated)
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+    }
+
+    #[test]
+    fn test_later_modulator_reaches_carrier_a_sample_late() {
+        // D modulates A, which is rendered before it. The envelopes are open after the first
+        // block, so the operators are plain sines from then on.
+        let mut params = params(0.0);
+        params.interpolation = Interpolation::Exact;
+        for operator in &mut params.fm_params.operators {
+            operator.eg_params = EGParameters {
+                attack_time_msec: 1.0,
+                sustain_level: 1.0,
+                ..Default::default()
+            };
+        }
+        params.fm_params.operators[3].modulation[0] = 0.5;
+        // The same samples in even blocks and in blocks split by events at odd times
+        let render = |block_sizes: &[usize]| {
+            let mut voice = FmVoice::new();
+            voice.initialize(2, BLOCK_SIZE);
+            voice.note_on(69, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+            let mut output = [Vec::new(), Vec::new(), Vec::new()];
+            for &block_size in block_sizes {
+                voice.render(block_size, &params, SAMPLE_RATE);
+                output[0].extend_from_slice(&voice.operators[0].output_buffer[..block_size]);
+                output[1].extend_from_slice(&voice.operators[3].output_buffer[..block_size]);
+                output[2].extend_from_slice(&voice.output_buffer[0][..block_size]);
+            }
+            output
+        };
+        let even = render(&[BLOCK_SIZE; 3]);
+        let uneven = render(&[5, 64, 17, 1, 40, 32, 33]);
+        for (even, uneven) in even.iter().zip(&uneven) {
+            assert_eq!(even.len(), uneven.len());
+            for (even, uneven) in even.iter().zip(uneven) {
+                assert_relative_eq!(*even, *uneven, epsilon = 1e-6);
+            }
+        }
+        // A is phase modulated by the sample of D before it
+        let [a, d, _] = even;
+        let phase_step = 440.0 / SAMPLE_RATE;
+        for sample_index in BLOCK_SIZE..3 * BLOCK_SIZE {
+            #[allow(clippy::cast_precision_loss)]
+            let phase = (sample_index as f32 * phase_step).fract();
+            let expected = (2.0 * PI * 0.5f32.mul_add(d[sample_index - 1], phase)).sin();
+            assert_relative_eq!(a[sample_index], expected, epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_mod_wheel_deepens_matrix_routes() {
+        let with_mod_wheel = |mut params: Parameters| {
+            params.fm_params.operators[0].mod_wheel_index = 1.0;
+            params.mod_wheel = 1.0;
+            params
+        };
+        assert_eq!(
+            render_blocks(&with_mod_wheel(params(1.0)), 4),
+            render_blocks(&params(2.0), 4)
+        );
+        // A route that is off stays off
+        assert_eq!(
+            render_blocks(&with_mod_wheel(params(0.0)), 4),
+            render_blocks(&params(0.0), 4)
+        );
+    }
+
+    #[test]
+    fn test_self_route_is_capped_like_feedback() {
+        let mut self_routed = params(0.0);
+        self_routed.fm_params.operators[1].modulation[1] = 10.0;
+        let mut max_feedback = params(0.0);
+        max_feedback.fm_params.operators[1].feedback = feedback_depth(MAX_FEEDBACK);
+        let output = render_blocks(&self_routed, 16);
+        assert!(output.iter().all(|sample| sample.is_finite()));
+        assert_eq!(output, render_blocks(&max_feedback, 16));
+    }
+
+    #[test]
+    fn test_poly_modulation_applies_to_its_voice() {
+        let params = params(0.0);
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+        // Another voice is modulated
+        voice.set_poly_modulation(2, PolyModulationTarget::Gain, -1.0, 0.0, SAMPLE_RATE);
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        assert!(voice.output_buffer[0].iter().any(|sample| *sample != 0.0));
+
+        voice.set_poly_modulation(1, PolyModulationTarget::Gain, -1.0, 0.0, SAMPLE_RATE);
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        assert!(voice.output_buffer[0].iter().all(|sample| *sample == 0.0));
+
+        // The modulation follows the automation of the gain
+        voice.update_poly_modulation(
+            PolyModulationTarget::Gain,
+            &|normalized_offset| 1.0 + normalized_offset,
+            SAMPLE_RATE,
+        );
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        assert!(voice.output_buffer[0].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn test_note_on_clears_poly_modulation() {
+        let params = params(0.0);
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+        voice.set_poly_modulation(1, PolyModulationTarget::Gain, -1.0, 0.0, SAMPLE_RATE);
+        voice.reset(&params);
+        voice.note_on(60, 1.0, Some(2), 0, &params, SAMPLE_RATE);
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        assert!(voice.output_buffer[0].iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn test_poly_modulation_target_ids() {
+        for id in 0..NUM_POLY_MODULATION_TARGETS {
+            #[allow(clippy::cast_possible_truncation)]
+            let id = id as u32;
+            let target = PolyModulationTarget::from_id(id).expect("every ID has a target");
+            assert_eq!(target.id(), id);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let past_the_end = NUM_POLY_MODULATION_TARGETS as u32;
+        assert_eq!(PolyModulationTarget::from_id(past_the_end), None);
+    }
+
+    #[test]
+    fn test_channel_expression_bends_the_voice_on_the_channel() {
+        let params = params(0.0);
+        let render = |note: u8, expressions: &[(u8, NoteExpression)]| {
+            let mut voice = FmVoice::new();
+            voice.initialize(2, BLOCK_SIZE);
+            voice.note_on(note, 1.0, None, 1, &params, SAMPLE_RATE);
+            for &(channel, expression) in expressions {
+                voice.set_channel_expression(channel, expression);
+            }
+            voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+            voice.output_buffer[0].clone()
+        };
+        let other_channel = render(60, &[(2, NoteExpression::Tuning(12.0))]);
+        assert_eq!(other_channel, render(60, &[]));
+        let bent = render(60, &[(1, NoteExpression::Tuning(12.0))]);
+        for (bent, played) in bent.iter().zip(render(72, &[])) {
+            assert!((bent - played).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_filter_is_applied_after_the_mix() {
+        let peak = |output: &[f32]| output.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        let unfiltered = render_blocks(&params(0.0), 16);
+        let mut filtered_params = params(0.0);
+        filtered_params.filter = FilterParameters {
+            mode: FilterMode::Ladder,
+            cutoff_hz: 20.0,
+            ..Default::default()
+        };
+        let filtered = render_blocks(&filtered_params, 16);
+        assert!(peak(&filtered[512..]) < 0.01 * peak(&unfiltered[512..]));
+    }
+
+    #[test]
+    fn test_unison_voice_is_panned() {
+        let params = params(0.0);
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+        voice.set_unison(UnisonVoice {
+            pan: -1.0,
+            ..Default::default()
+        });
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        assert!(voice.output_buffer[0].iter().any(|sample| *sample != 0.0));
+        assert!(voice.output_buffer[1]
+            .iter()
+            .all(|sample| sample.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_unison_of_a_stealing_note_waits_for_the_steal() {
+        let params = params(0.0);
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        voice.note_on(64, 1.0, Some(2), 0, &params, SAMPLE_RATE);
+        let unison = UnisonVoice {
+            detune: 10.0,
+            pan: -1.0,
+            gain: 0.5,
+        };
+        voice.set_unison(unison);
+        // The stolen note fades out where it was
+        assert_eq!(voice.unison, UnisonVoice::default());
+        for _ in 0..16 {
+            voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        }
+        assert!(!voice.is_stealing);
+        assert_eq!(voice.unison, unison);
+    }
+
+    #[test]
+    fn test_operator_pan_adds_to_voice_pan() {
+        let mut params = params(0.0);
+        params.fm_params.operators[1].pan = 1.0;
+        let render = |voice_pan: f32| {
+            let mut voice = FmVoice::new();
+            voice.initialize(2, BLOCK_SIZE);
+            voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+            voice.set_unison(UnisonVoice {
+                pan: voice_pan,
+                ..Default::default()
+            });
+            voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+            voice.output_buffer.clone()
+        };
+        let right = render(0.0);
+        assert!(right[0].iter().all(|sample| sample.abs() < 1e-6));
+        assert!(right[1].iter().any(|sample| *sample != 0.0));
+        // A voice panned hard left brings the operator back to the center
+        let center = render(-1.0);
+        for (left, right) in center[0].iter().zip(&center[1]) {
+            assert_relative_eq!(*left, *right);
+        }
+    }
+
+    #[test]
+    fn test_voice_lfo_bends_the_pitch() {
+        // A slow square at full depth plays an octave up for the first blocks
+        let mut vibrato_params = params(0.0);
+        vibrato_params.lfos[0] = LfoParameters {
+            scope: LfoScope::Voice,
+            waveform: LfoWaveform::Square,
+            rate_hz: 0.1,
+            pitch_depth: 12.0,
+            ..Default::default()
+        };
+        let render = |params: &Parameters, note: u8| {
+            let mut voice = FmVoice::new();
+            voice.initialize(2, BLOCK_SIZE);
+            voice.note_on(note, 1.0, Some(1), 0, params, SAMPLE_RATE);
+            voice.render(BLOCK_SIZE, params, SAMPLE_RATE);
+            voice.output_buffer[0].clone()
+        };
+        let bent = render(&vibrato_params, 60);
+        for (bent, played) in bent.iter().zip(render(&params(0.0), 72)) {
+            assert!((bent - played).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_lfo_amplitude_follows_operator_sensitivity() {
+        let mut params = params(0.0);
+        params.global_lfo = LfoModulation {
+            amplitude: 1.0,
+            ..Default::default()
+        };
+        let unmodulated = render_blocks(&params, 1);
+        assert!(unmodulated.iter().any(|sample| *sample != 0.0));
+        params.fm_params.operators[1].amp_mod_sensitivity = 1.0;
+        assert!(render_blocks(&params, 1)
+            .iter()
+            .all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn test_tremolo_ramps_across_the_block() {
+        let mut params = params(0.0);
+        params.fm_params.operators[1].amp_mod_sensitivity = 1.0;
+        let mut tremolo_params = params;
+        tremolo_params.global_lfo = LfoModulation {
+            amplitude: 1.0,
+            ..Default::default()
+        };
+        let render = |second_block: &Parameters| {
+            let mut voice = FmVoice::new();
+            voice.initialize(2, BLOCK_SIZE);
+            voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+            voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+            voice.render(BLOCK_SIZE, second_block, SAMPLE_RATE);
+            voice.output_buffer[0].clone()
+        };
+        // The operator fades out over the block instead of going silent at once
+        let unmodulated = render(&params);
+        let modulated = render(&tremolo_params);
+        #[allow(clippy::cast_precision_loss)]
+        for (sample_index, (modulated, unmodulated)) in
+            modulated.iter().zip(unmodulated).enumerate()
+        {
+            let gain = 1.0 - (sample_index + 1) as f32 / BLOCK_SIZE as f32;
+            assert_relative_eq!(*modulated, unmodulated * gain, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_lfo_scales_modulation_index() {
+        // The LFO takes the whole modulation away
+        let unmodulated = render_blocks(&params(0.0), 4);
+        let mut modulated_params = params(1.0);
+        modulated_params.global_lfo = LfoModulation {
+            index: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(render_blocks(&modulated_params, 4), unmodulated);
+    }
+
+    #[test]
+    fn test_voice_ends_with_its_operator_envelopes() {
+        // The operators release at once, while the envelope of the voice takes 10 seconds
+        let mut params = params(0.0);
+        params.eg_params.release_time_msec = 10_000.0;
+        for operator in &mut params.fm_params.operators {
+            operator.eg_params.mode = EGMode::Dx;
+        }
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(1), 0, &params, SAMPLE_RATE);
+        voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        voice.note_off(Some(1), 0, 60, &params, SAMPLE_RATE);
+        assert!(voice.is_playing());
+        for _ in 0..16 {
+            voice.render(BLOCK_SIZE, &params, SAMPLE_RATE);
+        }
+        assert!(!voice.is_playing());
+        // The next note starts at once instead of stealing the voice
+        voice.note_on(62, 1.0, Some(2), 0, &params, SAMPLE_RATE);
+        assert!(!voice.is_stealing);
+        assert_eq!(voice.current_midi_event.map(|event| event.note), Some(62));
+    }
+
+    #[test]
+    fn test_released_voice_reports_its_note() {
+        let mut params = params(0.0);
+        params.eg_params.release_time_msec = 10.0;
+        let mut voice = FmVoice::new();
+        voice.initialize(2, BLOCK_SIZE);
+        voice.note_on(60, 1.0, Some(7), 3, &params, SAMPLE_RATE);
+        assert_eq!(voice.render(BLOCK_SIZE, &params, SAMPLE_RATE), None);
+        voice.note_off(Some(7), 3, 60, &params, SAMPLE_RATE);
+        // The note is reported in the block its release ends in, and only then
+        let mut ended_notes = Vec::new();
+        for _ in 0..32 {
+            let was_playing = voice.is_playing();
+            if let Some(note) = voice.render(BLOCK_SIZE, &params, SAMPLE_RATE) {
+                assert!(was_playing && !voice.is_playing());
+                ended_notes.push(note);
+            }
+        }
+        assert_eq!(
+            ended_notes,
+            [VoiceNote {
+                voice_id: Some(7),
+                channel: 3,
+                note: 60,
+            }]
+        );
+        assert_eq!(voice.note(), None);
+    }
+}
@@ -0,0 +1,149 @@
+use nih_plug::prelude::Enum;
+
+/// The boost of keyboard level scaling is limited to 12 dB, as an operator can't get louder than
+/// its full level on the DX7 either.
+const MAX_GAIN: f32 = 4.0;
+
+/// How the level changes away from the breakpoint, like the four curves of the DX7.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum KeyScalingCurve {
+    /// The level drops by the same amount every three keys
+    #[default]
+    #[id = "negative_linear"]
+    #[name = "-LIN"]
+    NegativeLinear,
+    /// The level drops slowly near the breakpoint and quickly far away from it
+    #[id = "negative_exponential"]
+    #[name = "-EXP"]
+    NegativeExponential,
+    #[id = "positive_exponential"]
+    #[name = "+EXP"]
+    PositiveExponential,
+    #[id = "positive_linear"]
+    #[name = "+LIN"]
+    PositiveLinear,
+}
+
+impl KeyScalingCurve {
+    /// Converts a curve of a DX7 voice (0-3)
+    pub const fn from_dx7(curve: u8) -> Self {
+        match curve & 0x03 {
+            0 => Self::NegativeLinear,
+            1 => Self::NegativeExponential,
+            2 => Self::PositiveExponential,
+            _ => Self::PositiveLinear,
+        }
+    }
+
+    /// The change of the level `group` groups of three keys away from the breakpoint, in the
+    /// DX7's steps of 0.75 dB. `depth` is 0-99.
+    fn steps(self, group: i32, depth: i32) -> i32 {
+        const EXPONENTIAL: [i32; 33] = [
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 14, 16, 19, 23, 27, 33, 39, 47, 56, 66, 80, 94, 112,
+            133, 158, 187, 224, 259, 312, 361, 435, 500,
+        ];
+        let depth = depth.clamp(0, 99);
+        let steps = match self {
+            Self::NegativeLinear | Self::PositiveLinear => (group * depth * 329) >> 12,
+            Self::NegativeExponential | Self::PositiveExponential => {
+                let index = usize::try_from(group)
+                    .unwrap_or(0)
+                    .min(EXPONENTIAL.len() - 1);
+                (EXPONENTIAL[index] * depth * 329) >> 15
+            }
+        };
+        match self {
+            Self::NegativeLinear | Self::NegativeExponential => -steps,
+            Self::PositiveLinear | Self::PositiveExponential => steps,
+        }
+    }
+}
+
+/// Keyboard level scaling changes the level of an operator with the played key. It is modeled on
+/// the DX7, as emulated by Music Synthesizer for Android: the keys left and right of the
+/// breakpoint each have their own depth and curve.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KeyScaling {
+    /// The MIDI note where the scaling starts
+    pub breakpoint: u8,
+    /// The depth of the scaling below and above the breakpoint, 0-99
+    pub left_depth: i32,
+    pub right_depth: i32,
+    pub left_curve: KeyScalingCurve,
+    pub right_curve: KeyScalingCurve,
+}
+
+impl Default for KeyScaling {
+    /// No scaling, around C3 (middle C)
+    fn default() -> Self {
+        Self {
+            breakpoint: 60,
+            left_depth: 0,
+            right_depth: 0,
+            left_curve: KeyScalingCurve::NegativeLinear,
+            right_curve: KeyScalingCurve::NegativeLinear,
+        }
+    }
+}
+
+impl KeyScaling {
+    /// The gain of the operator for `note`. The level changes every three keys.
+    pub fn gain(&self, note: u8) -> f32 {
+        let offset = i32::from(note) - i32::from(self.breakpoint);
+        let steps = if offset >= 0 {
+            self.right_curve.steps((offset + 1) / 3, self.right_depth)
+        } else {
+            self.left_curve.steps((1 - offset) / 3, self.left_depth)
+        };
+        // Every step is 0.75 dB
+        #[allow(clippy::cast_precision_loss)]
+        (steps as f32 / 8.0).exp2().min(MAX_GAIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_no_depth_keeps_the_level() {
+        let key_scaling = KeyScaling::default();
+        for note in 0..128 {
+            assert_relative_eq!(key_scaling.gain(note), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_sides_of_the_breakpoint() {
+        let key_scaling = KeyScaling {
+            breakpoint: 60,
+            left_depth: 99,
+            right_depth: 50,
+            left_curve: KeyScalingCurve::PositiveLinear,
+            right_curve: KeyScalingCurve::NegativeLinear,
+        };
+        assert_relative_eq!(key_scaling.gain(60), 1.0);
+        // Three keys up are one group, (1 * 50 * 329) >> 12 = 4 steps of 0.75 dB
+        assert_relative_eq!(key_scaling.gain(62), 0.5_f32.sqrt());
+        // The bass is boosted, up to the limit
+        assert!(key_scaling.gain(50) > 1.0);
+        assert_relative_eq!(key_scaling.gain(0), MAX_GAIN);
+        // The treble gets quieter and quieter
+        assert!((60..127).all(|note| key_scaling.gain(note + 1) <= key_scaling.gain(note)));
+    }
+
+    #[test]
+    fn test_exponential_curve_starts_slowly() {
+        let curve = |curve| KeyScaling {
+            breakpoint: 21,
+            right_depth: 99,
+            right_curve: curve,
+            ..Default::default()
+        };
+        let linear = curve(KeyScalingCurve::NegativeLinear);
+        let exponential = curve(KeyScalingCurve::NegativeExponential);
+        assert!(exponential.gain(27) > linear.gain(27));
+        assert!(exponential.gain(127) < linear.gain(127));
+    }
+}
@@ -0,0 +1,456 @@
+use std::f32::consts::PI;
+use std::ops::Add;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use nih_plug::prelude::Enum;
+
+use crate::clock::Clock;
+
+/// The number of LFOs. Each one is either global or runs in every voice.
+pub const NUM_LFOS: usize = 2;
+
+/// The seeds of the sample and hold of the LFOs, so every LFO picks different values.
+static NEXT_SEED: AtomicU32 = AtomicU32::new(0x9E37_79B9);
+
+/// The shape of an LFO. These are the shapes of the DX7, in its order.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum LfoWaveform {
+    #[default]
+    #[id = "triangle"]
+    #[name = "Triangle"]
+    Triangle,
+    #[id = "saw_down"]
+    #[name = "Saw Down"]
+    SawDown,
+    #[id = "saw_up"]
+    #[name = "Saw Up"]
+    SawUp,
+    #[id = "square"]
+    #[name = "Square"]
+    Square,
+    #[id = "sine"]
+    #[name = "Sine"]
+    Sine,
+    /// A new random value at the start of every cycle
+    #[id = "sample_and_hold"]
+    #[name = "Sample & Hold"]
+    SampleAndHold,
+}
+
+impl LfoWaveform {
+    /// Converts a DX7 LFO waveform setting (0-5). Out of range settings are sample and hold.
+    pub const fn from_dx7(waveform: u8) -> Self {
+        match waveform {
+            0 => Self::Triangle,
+            1 => Self::SawDown,
+            2 => Self::SawUp,
+            3 => Self::Square,
+            4 => Self::Sine,
+            _ => Self::SampleAndHold,
+        }
+    }
+
+    /// The value of the waveform at `phase`, in `[0, 1)`, from -1 to 1. The sample and hold
+    /// returns `held_value`.
+    fn value(self, phase: f32, held_value: f32) -> f32 {
+        match self {
+            // Starts at 0 and rises, like the sine
+            Self::Triangle => 4.0f32.mul_add(-((phase + 0.25).fract() - 0.5).abs(), 1.0),
+            Self::SawDown => 2.0f32.mul_add(-phase, 1.0),
+            Self::SawUp => 2.0f32.mul_add(phase, -1.0),
+            Self::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Self::Sine => (2.0 * PI * phase).sin(),
+            Self::SampleAndHold => held_value,
+        }
+    }
+}
+
+/// Whether an LFO is shared by all the voices or every voice runs its own.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum LfoScope {
+    /// A single LFO modulates all the voices, like on the DX7. Key retrigger restarts it on
+    /// every note, and the delay starts over when a note is played after all the voices ended.
+    #[default]
+    #[id = "global"]
+    #[name = "Global"]
+    Global,
+    /// Every voice has its own LFO, whose delay starts with its note
+    #[id = "voice"]
+    #[name = "Per Voice"]
+    Voice,
+}
+
+/// The length of a cycle of an LFO that is synced to the tempo of the host.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SyncDivision {
+    #[id = "4_bars"]
+    #[name = "4 Bars"]
+    FourBars,
+    #[id = "2_bars"]
+    #[name = "2 Bars"]
+    TwoBars,
+    #[id = "1_bar"]
+    #[name = "1 Bar"]
+    OneBar,
+    #[id = "1/2"]
+    #[name = "1/2"]
+    Half,
+    #[default]
+    #[id = "1/4"]
+    #[name = "1/4"]
+    Quarter,
+    #[id = "1/4t"]
+    #[name = "1/4 Triplet"]
+    QuarterTriplet,
+    #[id = "1/8"]
+    #[name = "1/8"]
+    Eighth,
+    #[id = "1/8t"]
+    #[name = "1/8 Triplet"]
+    EighthTriplet,
+    #[id = "1/16"]
+    #[name = "1/16"]
+    Sixteenth,
+}
+
+impl SyncDivision {
+    /// The length of a cycle in quarter notes. The bars are in 4/4.
+    pub fn beats(self) -> f64 {
+        match self {
+            Self::FourBars => 16.0,
+            Self::TwoBars => 8.0,
+            Self::OneBar => 4.0,
+            Self::Half => 2.0,
+            Self::Quarter => 1.0,
+            Self::QuarterTriplet => 2.0 / 3.0,
+            Self::Eighth => 0.5,
+            Self::EighthTriplet => 1.0 / 3.0,
+            Self::Sixteenth => 0.25,
+        }
+    }
+}
+
+/// The parameters of an LFO. The depths are like the PMD and AMD of the DX7, and the
+/// sensitivity of every operator to the amplitude modulation is part of its parameters.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LfoParameters {
+    pub scope: LfoScope,
+    pub waveform: LfoWaveform,
+    pub rate_hz: f32,
+    /// The phase the LFO is at when it follows the position of the host, see
+    /// `sync_to_tempo`.
+    pub sync_phase: Option<f32>,
+    /// The time from the note to the start of the modulation, and the time it takes to fade in
+    pub delay_msec: f32,
+    pub fade_in_msec: f32,
+    /// Whether every note restarts the cycle
+    pub key_retrigger: bool,
+    /// How far the LFO bends the pitch at its peaks, in semitones
+    pub pitch_depth: f32,
+    /// How far the LFO lowers the level of the operators, from 0 to 1
+    pub amp_depth: f32,
+    /// How far the LFO moves the modulation indexes, relative to their value
+    pub index_depth: f32,
+}
+
+impl Default for LfoParameters {
+    fn default() -> Self {
+        Self {
+            scope: LfoScope::default(),
+            waveform: LfoWaveform::default(),
+            rate_hz: 5.0,
+            sync_phase: None,
+            delay_msec: 0.0,
+            fade_in_msec: 0.0,
+            key_retrigger: true,
+            pitch_depth: 0.0,
+            amp_depth: 0.0,
+            index_depth: 0.0,
+        }
+    }
+}
+
+impl LfoParameters {
+    /// Sets the rate for cycles of `division` at `tempo` in beats per minute. While the host
+    /// plays, a global LFO that is not retriggered by the keys follows its position in
+    /// `position_beats`, so it is in the same place every time the song plays.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn sync_to_tempo(
+        &mut self,
+        division: SyncDivision,
+        tempo: f64,
+        position_beats: Option<f64>,
+    ) {
+        self.rate_hz = (tempo / 60.0 / division.beats()) as f32;
+        self.sync_phase = position_beats
+            .filter(|_| self.scope == LfoScope::Global && !self.key_retrigger)
+            .map(|beats| (beats / division.beats()).rem_euclid(1.0) as f32);
+    }
+}
+
+/// What the LFOs do to a voice during a block.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct LfoModulation {
+    /// The pitch bend in semitones
+    pub pitch: f32,
+    /// How much the level of an operator with full sensitivity is lowered, from 0 to 1
+    pub amplitude: f32,
+    /// The change of the modulation indexes, relative to their value
+    pub index: f32,
+}
+
+impl Add for LfoModulation {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            pitch: self.pitch + other.pitch,
+            amplitude: self.amplitude + other.amplitude,
+            index: self.index + other.index,
+        }
+    }
+}
+
+impl LfoModulation {
+    /// The gain of an operator with a `sensitivity` from 0 to 1 to the amplitude modulation.
+    pub fn gain(&self, sensitivity: f32) -> f32 {
+        1.0 - (self.amplitude * sensitivity).clamp(0.0, 1.0)
+    }
+
+    /// The factor of the modulation indexes.
+    pub fn index_factor(&self) -> f32 {
+        (1.0 + self.index).max(0.0)
+    }
+}
+
+/// A low frequency oscillator. It runs at the rate of the blocks, which are short enough for
+/// vibrato and tremolo.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lfo {
+    clock: Clock,
+    /// The value of the sample and hold for the current cycle
+    held_value: f32,
+    /// The state of the xorshift generator of the sample and hold
+    random_state: u32,
+    /// The time since the delay started, in seconds
+    elapsed_sec: f32,
+}
+
+impl Lfo {
+    pub fn new() -> Self {
+        let mut lfo = Self {
+            clock: Clock::new(),
+            held_value: 0.0,
+            random_state: NEXT_SEED.fetch_add(0x6D2B_79F5, Ordering::Relaxed) | 1,
+            elapsed_sec: 0.0,
+        };
+        lfo.hold_next_value();
+        lfo
+    }
+
+    pub fn reset(&mut self) {
+        self.clock.reset();
+        self.elapsed_sec = 0.0;
+    }
+
+    /// Starts a note. `restart_delay` says whether the delay and fade in start over.
+    pub fn note_on(&mut self, params: &LfoParameters, restart_delay: bool) {
+        if params.key_retrigger {
+            self.clock.reset();
+            self.hold_next_value();
+        }
+        if restart_delay {
+            self.elapsed_sec = 0.0;
+        }
+    }
+
+    /// The modulation for a block of `num_samples`. The LFO then moves to the next block.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn next_block(
+        &mut self,
+        params: &LfoParameters,
+        num_samples: usize,
+        sample_rate: f32,
+    ) -> LfoModulation {
+        if let Some(phase) = params.sync_phase {
+            self.clock.mcounter = phase;
+        }
+        let value = params.waveform.value(self.clock.mcounter, self.held_value);
+        let fade = self.fade(params);
+
+        self.clock.set_freq(params.rate_hz, sample_rate);
+        self.clock.advance_clock(num_samples as f32);
+        if self.clock.mcounter >= 1.0 {
+            self.hold_next_value();
+        }
+        self.clock.wrap_clock();
+        self.elapsed_sec += num_samples as f32 / sample_rate;
+
+        LfoModulation {
+            pitch: params.pitch_depth * fade * value,
+            // The amplitude modulation only lowers the level, like on the DX7
+            amplitude: params.amp_depth * fade * 0.5 * (1.0 - value),
+            index: params.index_depth * fade * value,
+        }
+    }
+
+    /// How far the LFO has faded in after its delay, from 0 to 1.
+    fn fade(&self, params: &LfoParameters) -> f32 {
+        let faded_msec = self.elapsed_sec.mul_add(1000.0, -params.delay_msec);
+        if faded_msec < 0.0 {
+            0.0
+        } else if faded_msec >= params.fade_in_msec {
+            1.0
+        } else {
+            faded_msec / params.fade_in_msec
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn hold_next_value(&mut self) {
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 17;
+        self.random_state ^= self.random_state << 5;
+        self.held_value = (self.random_state as f32 / u32::MAX as f32).mul_add(2.0, -1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rstest::rstest;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    #[rstest]
+    #[case(LfoWaveform::Triangle, [0.0, 1.0, 0.0, -1.0])]
+    #[case(LfoWaveform::SawDown, [1.0, 0.5, 0.0, -0.5])]
+    #[case(LfoWaveform::SawUp, [-1.0, -0.5, 0.0, 0.5])]
+    #[case(LfoWaveform::Square, [1.0, 1.0, -1.0, -1.0])]
+    #[case(LfoWaveform::Sine, [0.0, 1.0, 0.0, -1.0])]
+    fn test_waveforms(#[case] waveform: LfoWaveform, #[case] expected: [f32; 4]) {
+        for (phase, expected) in [0.0, 0.25, 0.5, 0.75].into_iter().zip(expected) {
+            assert_relative_eq!(waveform.value(phase, 0.0), expected, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_vibrato_cycles_at_rate() {
+        let params = LfoParameters {
+            waveform: LfoWaveform::Sine,
+            rate_hz: 2.0,
+            pitch_depth: 0.5,
+            ..Default::default()
+        };
+        let mut lfo = Lfo::new();
+        lfo.note_on(&params, true);
+        // 125 samples are a quarter of a cycle at 2 Hz
+        let pitches: Vec<f32> = (0..5)
+            .map(|_| lfo.next_block(&params, 125, SAMPLE_RATE).pitch)
+            .collect();
+        for (pitch, expected) in pitches.into_iter().zip([0.0, 0.5, 0.0, -0.5, 0.0]) {
+            assert_relative_eq!(pitch, expected, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_delay_and_fade_in() {
+        let params = LfoParameters {
+            waveform: LfoWaveform::Square,
+            rate_hz: 0.1,
+            delay_msec: 100.0,
+            fade_in_msec: 100.0,
+            amp_depth: 1.0,
+            pitch_depth: 1.0,
+            ..Default::default()
+        };
+        let mut lfo = Lfo::new();
+        lfo.note_on(&params, true);
+        let pitches: Vec<f32> = (0..5)
+            .map(|_| lfo.next_block(&params, 50, SAMPLE_RATE).pitch)
+            .collect();
+        for (pitch, expected) in pitches.into_iter().zip([0.0, 0.0, 0.0, 0.5, 1.0]) {
+            assert_relative_eq!(pitch, expected, epsilon = 1e-5);
+        }
+        // The square is at its top, so the amplitude is not lowered
+        assert_relative_eq!(lfo.next_block(&params, 50, SAMPLE_RATE).amplitude, 0.0);
+        // Playing another note without restarting the delay keeps the LFO going
+        lfo.note_on(&params, false);
+        assert_relative_eq!(lfo.next_block(&params, 50, SAMPLE_RATE).pitch, 1.0);
+        lfo.note_on(&params, true);
+        assert_relative_eq!(lfo.next_block(&params, 50, SAMPLE_RATE).pitch, 0.0);
+    }
+
+    #[test]
+    fn test_key_retrigger_restarts_cycle() {
+        let mut params = LfoParameters {
+            waveform: LfoWaveform::SawUp,
+            rate_hz: 1.0,
+            index_depth: 1.0,
+            ..Default::default()
+        };
+        let mut lfo = Lfo::new();
+        lfo.next_block(&params, 250, SAMPLE_RATE);
+        lfo.note_on(&params, true);
+        assert_relative_eq!(lfo.next_block(&params, 250, SAMPLE_RATE).index, -1.0);
+        params.key_retrigger = false;
+        lfo.note_on(&params, true);
+        assert_relative_eq!(lfo.next_block(&params, 250, SAMPLE_RATE).index, -0.5);
+    }
+
+    #[test]
+    fn test_sample_and_hold_holds_for_a_cycle() {
+        let params = LfoParameters {
+            waveform: LfoWaveform::SampleAndHold,
+            rate_hz: 1.0,
+            pitch_depth: 1.0,
+            ..Default::default()
+        };
+        let mut lfo = Lfo::new();
+        let values: Vec<f32> = (0..8)
+            .map(|_| lfo.next_block(&params, 250, SAMPLE_RATE).pitch)
+            .collect();
+        assert!(values.iter().all(|value| value.abs() <= 1.0));
+        assert_relative_eq!(values[0], values[3]);
+        assert_relative_eq!(values[4], values[7]);
+        assert!((values[0] - values[4]).abs() > 1e-6);
+    }
+
+    #[test]
+    fn test_sync_to_tempo() {
+        let mut params = LfoParameters {
+            key_retrigger: false,
+            ..Default::default()
+        };
+        params.sync_to_tempo(SyncDivision::Eighth, 120.0, Some(4.75));
+        assert_relative_eq!(params.rate_hz, 4.0);
+        assert_eq!(params.sync_phase, Some(0.5));
+        // A voice LFO starts with its note instead
+        params.scope = LfoScope::Voice;
+        params.sync_to_tempo(SyncDivision::OneBar, 120.0, Some(4.75));
+        assert_relative_eq!(params.rate_hz, 0.5);
+        assert_eq!(params.sync_phase, None);
+    }
+
+    #[test]
+    fn test_modulation_gain_and_index() {
+        let modulation = LfoModulation {
+            amplitude: 0.5,
+            index: -1.5,
+            ..Default::default()
+        } + LfoModulation {
+            amplitude: 0.25,
+            ..Default::default()
+        };
+        assert_relative_eq!(modulation.gain(1.0), 0.25);
+        assert_relative_eq!(modulation.gain(0.0), 1.0);
+        assert_relative_eq!(modulation.index_factor(), 0.0);
+    }
+}
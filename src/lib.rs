@@ -1,28 +1,85 @@
 use nih_plug::prelude::*;
+use nih_plug::wrapper::state::{ParamValue, PluginState};
 
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use voice_utils::{NoteExpression, PolyModulationTarget};
 
+mod analog_eg;
 mod clock;
 mod consts;
+mod dx7_sysex;
+mod dx_eg;
+mod filter;
+mod fm_algorithm;
 mod fm_core;
 mod fm_operator;
 mod fm_voice;
+mod key_scaling;
+mod lfo;
 mod linear_eg;
-mod sin_osc;
+mod mono;
+mod mpe;
+mod multi_mode_eg;
+mod oversampling;
+mod pan;
 mod sin_voice;
+mod sysex;
+mod sysex_dump;
 mod voice_group;
 mod voice_utils;
+mod wavetable;
 
 /// The maximum size of an audio block. We'll split up the audio in blocks and render smoothed
 /// values to buffers since these values may need to be reused for multiple voices.
 const MAX_BLOCK_SIZE: usize = 64;
+/// The time it takes the pitch bend to reach a new position of the wheel, so the steps of a 7 bit
+/// pitch wheel can't be heard.
+const PITCH_BEND_SMOOTHING_MSEC: f32 = 10.0;
+/// The time it takes the mod wheel and the expression pedal to reach a new position.
+const CONTROLLER_SMOOTHING_MSEC: f32 = 10.0;
 
 pub struct FmSynth {
     params: Arc<FmSynthParams>,
     // used to store the state of one fm operator
     voices: voice_group::VoiceGroup<fm_voice::FmVoice>,
     voice_params: voice_utils::Parameters,
+    /// The DX7 voice received over MIDI and its parameters, if any. While Play DX7 Voice is on,
+    /// it replaces the envelopes, the operators and LFO 1 of the plugin.
+    dx7_voice: Option<(dx7_sysex::Dx7Voice, voice_utils::Parameters)>,
+    /// The position of the pitch wheel, from -1 (all the way down) to 1 (all the way up)
+    pitch_bend: Smoother<f32>,
+    /// The pitch bend of each member channel of the MPE zone, in semitones
+    mpe_pitch_bends: [Smoother<f32>; 16],
+    /// The MPE zone of the last block. The expressions of the channels are cleared when it
+    /// changes.
+    mpe_zone: mpe::MpeZone,
+    /// The position of the mod wheel (CC1), from 0 to 1
+    mod_wheel: Smoother<f32>,
+    /// The output gain set by the expression pedal (CC11), from 0 to 1
+    expression: Smoother<f32>,
     sample_rate: f32,
+    /// The tempo of the host in beats per minute, and its position at the start of the block
+    /// in quarter notes while it plays, for the LFOs that are synced to the tempo
+    tempo: Option<f64>,
+    position_beats: Option<f64>,
+    /// The waveforms the voices play
+    wavetables: Arc<wavetable::Wavetables>,
+    /// The waveforms built by the background task after a user wavetable was loaded, until the
+    /// audio thread picks them up
+    next_wavetables: Arc<Mutex<Option<Arc<wavetable::Wavetables>>>>,
+    /// The samples of the user wavetables that were received, until the background task stores
+    /// them. The room for them is made in `initialize()`.
+    user_wavetable_dumps: Arc<Mutex<[Vec<f32>; wavetable::NUM_USER_WAVETABLES]>>,
+}
+
+/// The work that is too slow or allocates too much for the audio thread.
+pub enum Task {
+    /// Stores the user wavetable that was received for a slot with the state of the plugin and
+    /// builds the waveforms with it
+    LoadUserWavetable(usize),
+    /// Drops waveforms the voices no longer play, so they aren't freed on the audio thread
+    DropWavetables(Arc<wavetable::Wavetables>),
 }
 
 #[derive(Params)]
@@ -33,6 +90,163 @@ struct FmSynthParams {
     /// gain parameter is stored as linear gain while the values are displayed in decibels.
     #[id = "gain"]
     pub gain: FloatParam,
+    /// The amplitude envelope of the voices
+    #[nested(group = "Envelope")]
+    pub eg: EnvelopeParams,
+    /// The filter of the voices
+    #[nested(id_prefix = "filter", group = "Filter")]
+    pub filter: FilterParams,
+    /// The LFOs, which are global or per voice
+    #[nested(id_prefix = "lfo_1", group = "LFO 1")]
+    pub lfo_1: LfoParams,
+    #[nested(id_prefix = "lfo_2", group = "LFO 2")]
+    pub lfo_2: LfoParams,
+    #[id = "num_voices"]
+    pub num_voices: IntParam,
+    // Every note is played by `unison` voices, detuned and panned symmetrically around the note.
+    // The voices of a note count towards the number of voices.
+    #[id = "unison"]
+    pub unison: IntParam,
+    #[id = "unison_detune"]
+    pub unison_detune: FloatParam,
+    #[id = "unison_spread"]
+    pub unison_spread: FloatParam,
+    // Places the notes in the stereo field by key or left and right in turn
+    #[id = "voice_pan_mode"]
+    pub voice_pan_mode: EnumParam<pan::VoicePanMode>,
+    #[id = "voice_pan_width"]
+    pub voice_pan_width: FloatParam,
+    // The voices run at a multiple of the sample rate, so bright patches don't alias
+    #[id = "oversampling"]
+    pub oversampling: EnumParam<oversampling::Oversampling>,
+    // The accuracy of the oscillators, against CPU
+    #[id = "interpolation"]
+    pub interpolation: EnumParam<wavetable::Interpolation>,
+    #[id = "table_size"]
+    pub table_size: EnumParam<wavetable::TableSize>,
+    // In the mono modes a single voice plays the held key with the highest priority
+    #[id = "voice_mode"]
+    pub voice_mode: EnumParam<mono::VoiceMode>,
+    #[id = "note_priority"]
+    pub note_priority: EnumParam<mono::NotePriority>,
+    // The portamento of the mono modes
+    #[id = "glide_time"]
+    pub glide_time: FloatParam,
+    #[id = "glide_mode"]
+    pub glide_mode: EnumParam<mono::GlideMode>,
+    #[id = "algorithm"]
+    pub algorithm: EnumParam<fm_algorithm::FmAlgorithm>,
+    // Plays the DX7 voice received over MIDI instead of the envelopes, operators and LFO 1 above.
+    // Changing any of those goes back to them.
+    #[id = "play_dx7_voice"]
+    pub play_dx7_voice: BoolParam,
+    // How far the pitch wheel bends the voices up and down, in semitones
+    #[id = "bend_range_up"]
+    pub bend_range_up: IntParam,
+    #[id = "bend_range_down"]
+    pub bend_range_down: IntParam,
+    // In MPE mode, the member channels of the zone bend their notes by up to the MPE bend range
+    #[id = "mpe_zone"]
+    pub mpe_zone: EnumParam<mpe::MpeZone>,
+    #[id = "mpe_member_channels"]
+    pub mpe_member_channels: IntParam,
+    #[id = "mpe_bend_range"]
+    pub mpe_bend_range: IntParam,
+    /// The wavetables of the user waveforms, one cycle of any length each. They are stored with
+    /// the state of the plugin and loaded when the plugin is initialized, or when a user
+    /// wavetable dump is received.
+    #[persist = "user_wavetables"]
+    pub user_wavetables: RwLock<Vec<Vec<f32>>>,
+    /// Set when the host changes one of the parameters a DX7 voice overrides, so that the
+    /// plugin stops playing the voice
+    pub overridden_params_changed: Arc<AtomicBool>,
+    /// The last DX7 bank received over MIDI, in the packed format of a bank dump. Program changes
+    /// pick its voices.
+    #[persist = "dx7_bank"]
+    pub dx7_bank: RwLock<Vec<u8>>,
+    /// The DX7 voice that plays, in the unpacked format of a voice dump, or nothing before one
+    /// was received or after one of the parameters it overrides was changed
+    #[persist = "dx7_voice"]
+    pub dx7_voice: RwLock<Vec<u8>>,
+    #[nested(id_prefix = "operator_a", group = "Operator A")]
+    pub operator_a: OperatorParams,
+    #[nested(id_prefix = "operator_b", group = "Operator B")]
+    pub operator_b: OperatorParams,
+    #[nested(id_prefix = "operator_c", group = "Operator C")]
+    pub operator_c: OperatorParams,
+    #[nested(id_prefix = "operator_d", group = "Operator D")]
+    pub operator_d: OperatorParams,
+}
+
+/// The parameters of a single operator. This is nested into `FmSynthParams` once per operator
+/// with an `operator_x` ID prefix.
+#[derive(Params)]
+struct OperatorParams {
+    #[id = "waveform"]
+    pub waveform: EnumParam<wavetable::Waveform>,
+    #[id = "index"]
+    pub index: FloatParam,
+    // How much the mod wheel adds to the index and to the routes of the modulation matrix
+    #[id = "mod_wheel_index"]
+    pub mod_wheel_index: FloatParam,
+    // The frequency ratio is `coarse * (1 + fine)`, detuned in cents
+    #[id = "coarse"]
+    pub coarse: IntParam,
+    #[id = "fine"]
+    pub fine: FloatParam,
+    #[id = "detune"]
+    pub detune: FloatParam,
+    // In fixed mode the operator plays at `frequency` instead of following the note
+    #[id = "fixed"]
+    pub fixed: BoolParam,
+    #[id = "frequency"]
+    pub frequency: FloatParam,
+    // How much the note velocity affects the output level of the operator
+    #[id = "velocity_sensitivity"]
+    pub velocity_sensitivity: FloatParam,
+    #[id = "velocity_curve"]
+    pub velocity_curve: EnumParam<fm_core::VelocityCurve>,
+    // Keyboard level scaling, with a depth and a curve for each side of the breakpoint
+    #[id = "breakpoint"]
+    pub breakpoint: IntParam,
+    #[id = "left_depth"]
+    pub left_depth: IntParam,
+    #[id = "right_depth"]
+    pub right_depth: IntParam,
+    #[id = "left_curve"]
+    pub left_curve: EnumParam<key_scaling::KeyScalingCurve>,
+    #[id = "right_curve"]
+    pub right_curve: EnumParam<key_scaling::KeyScalingCurve>,
+    #[id = "mix"]
+    pub mix: FloatParam,
+    #[id = "pan"]
+    pub pan: FloatParam,
+    // How much the amplitude modulation of the LFOs lowers the level of the operator
+    #[id = "amp_mod_sensitivity"]
+    pub amp_mod_sensitivity: FloatParam,
+    #[id = "feedback"]
+    pub feedback: IntParam,
+    // The envelope of this operator
+    #[nested(group = "Envelope")]
+    pub eg: EnvelopeParams,
+    // The modulation matrix row of this operator. Each parameter is how much this operator
+    // phase modulates the named operator, on top of the routes of the algorithm.
+    #[id = "to_a"]
+    pub to_a: FloatParam,
+    #[id = "to_b"]
+    pub to_b: FloatParam,
+    #[id = "to_c"]
+    pub to_c: FloatParam,
+    #[id = "to_d"]
+    pub to_d: FloatParam,
+}
+
+/// The parameters of an envelope generator. This is nested into `FmSynthParams` for the envelope
+/// of the voices and into `OperatorParams` for the envelope of every operator.
+#[derive(Params)]
+struct EnvelopeParams {
+    #[id = "eg_mode"]
+    pub mode: EnumParam<linear_eg::EGMode>,
     #[id = "attack_time"]
     pub attack_time: FloatParam,
     // TODO: Make it so that if decay time is less that a certain value, sustain level is not used.
@@ -42,35 +256,83 @@ struct FmSynthParams {
     pub sustain_level: FloatParam,
     #[id = "release_time"]
     pub release_time: FloatParam,
-    #[id = "num_voices"]
-    pub num_voices: IntParam,
-    // idex
-    #[id = "operator_a_index"]
-    pub operator_a_index: FloatParam,
-    #[id = "operator_b_index"]
-    pub operator_b_index: FloatParam,
-    #[id = "operator_c_index"]
-    pub operator_c_index: FloatParam,
-    #[id = "operator_d_index"]
-    pub operator_d_index: FloatParam,
-    // ratio
-    #[id = "operator_a_ratio"]
-    pub operator_a_ratio: FloatParam,
-    #[id = "operator_b_ratio"]
-    pub operator_b_ratio: FloatParam,
-    #[id = "operator_c_ratio"]
-    pub operator_c_ratio: FloatParam,
-    #[id = "operator_d_ratio"]
-    pub operator_d_ratio: FloatParam,
-    // mix
-    #[id = "operator_a_mix"]
-    pub operator_a_mix: FloatParam,
-    #[id = "operator_b_mix"]
-    pub operator_b_mix: FloatParam,
-    #[id = "operator_c_mix"]
-    pub operator_c_mix: FloatParam,
-    #[id = "operator_d_mix"]
-    pub operator_d_mix: FloatParam,
+    // The curvature of the segments in analog mode
+    #[id = "attack_curve"]
+    pub attack_curve: FloatParam,
+    #[id = "decay_curve"]
+    pub decay_curve: FloatParam,
+    #[id = "release_curve"]
+    pub release_curve: FloatParam,
+    // The rates and levels of the DX7 mode
+    #[id = "rate_1"]
+    pub rate_1: IntParam,
+    #[id = "rate_2"]
+    pub rate_2: IntParam,
+    #[id = "rate_3"]
+    pub rate_3: IntParam,
+    #[id = "rate_4"]
+    pub rate_4: IntParam,
+    #[id = "level_1"]
+    pub level_1: IntParam,
+    #[id = "level_2"]
+    pub level_2: IntParam,
+    #[id = "level_3"]
+    pub level_3: IntParam,
+    #[id = "level_4"]
+    pub level_4: IntParam,
+    // How much faster the envelope runs on higher notes, in every mode
+    #[id = "rate_scaling"]
+    pub rate_scaling: IntParam,
+}
+
+/// The parameters of the filter of the voices. This is nested into `FmSynthParams` with a
+/// `filter` ID prefix.
+#[derive(Params)]
+struct FilterParams {
+    #[id = "mode"]
+    pub mode: EnumParam<filter::FilterMode>,
+    #[id = "cutoff"]
+    pub cutoff: FloatParam,
+    #[id = "resonance"]
+    pub resonance: FloatParam,
+    // How much the cutoff follows the note, around C4
+    #[id = "key_tracking"]
+    pub key_tracking: FloatParam,
+    // How many octaves the envelope moves the cutoff at its peak
+    #[id = "env_amount"]
+    pub env_amount: FloatParam,
+    #[nested(group = "Envelope")]
+    pub eg: EnvelopeParams,
+}
+
+/// The parameters of an LFO. This is nested into `FmSynthParams` once per LFO with an `lfo_x`
+/// ID prefix.
+#[derive(Params)]
+struct LfoParams {
+    #[id = "scope"]
+    pub scope: EnumParam<lfo::LfoScope>,
+    #[id = "waveform"]
+    pub waveform: EnumParam<lfo::LfoWaveform>,
+    #[id = "rate"]
+    pub rate: FloatParam,
+    // When synced, the rate follows the tempo of the host instead
+    #[id = "tempo_sync"]
+    pub tempo_sync: BoolParam,
+    #[id = "division"]
+    pub division: EnumParam<lfo::SyncDivision>,
+    #[id = "delay"]
+    pub delay: FloatParam,
+    #[id = "fade_in"]
+    pub fade_in: FloatParam,
+    #[id = "key_retrigger"]
+    pub key_retrigger: BoolParam,
+    // The depths of the vibrato, the tremolo and the modulation of the indexes
+    #[id = "pitch_depth"]
+    pub pitch_depth: FloatParam,
+    #[id = "amp_depth"]
+    pub amp_depth: FloatParam,
+    #[id = "index_depth"]
+    pub index_depth: FloatParam,
 }
 
 impl Default for FmSynth {
@@ -79,7 +341,20 @@ impl Default for FmSynth {
             params: Arc::new(FmSynthParams::default()),
             voices: voice_group::VoiceGroup::new(),
             voice_params: voice_utils::Parameters::default(),
+            dx7_voice: None,
+            pitch_bend: Smoother::new(SmoothingStyle::Linear(PITCH_BEND_SMOOTHING_MSEC)),
+            mpe_pitch_bends: std::array::from_fn(|_| {
+                Smoother::new(SmoothingStyle::Linear(PITCH_BEND_SMOOTHING_MSEC))
+            }),
+            mpe_zone: mpe::MpeZone::default(),
+            mod_wheel: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MSEC)),
+            expression: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MSEC)),
             sample_rate: 0.0,
+            tempo: None,
+            position_beats: None,
+            wavetables: wavetable::Wavetables::default_shared(),
+            next_wavetables: Arc::new(Mutex::new(None)),
+            user_wavetable_dumps: Arc::new(Mutex::new(Default::default())),
         }
     }
 }
@@ -87,6 +362,7 @@ impl Default for FmSynth {
 #[allow(clippy::too_many_lines)]
 impl Default for FmSynthParams {
     fn default() -> Self {
+        let overridden_params_changed = Arc::new(AtomicBool::new(false));
         Self {
             // This gain is stored as linear gain. NIH-plug comes with useful conversion functions
             // to treat these kinds of parameters as if we were dealing with decibels. Storing this
@@ -105,6 +381,7 @@ impl Default for FmSynthParams {
             // Because the gain parameter is stored as linear gain instead of storing the value as
             // decibels, we need logarithmic smoothing
             .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_poly_modulation_id(PolyModulationTarget::Gain.id())
             .with_unit(" dB")
             // There are many predefined formatters we can use here. If the gain was stored as
             // decibels instead of as a linear gain value, we could have also used the
@@ -112,94 +389,568 @@ impl Default for FmSynthParams {
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
 
-            operator_a_index: FloatParam::new(
-                "Operator A Index",
-                0.0,
-                FloatRange::Linear {
-                    min: 0.0,
-                    max: 10.0,
+            user_wavetables: RwLock::new(Vec::new()),
+            overridden_params_changed: Arc::clone(&overridden_params_changed),
+            dx7_bank: RwLock::new(Vec::with_capacity(dx7_sysex::BANK_DATA_SIZE)),
+            dx7_voice: RwLock::new(Vec::with_capacity(dx7_sysex::VOICE_SIZE)),
+            operator_a: OperatorParams::new("A", 0, &overridden_params_changed),
+            operator_b: OperatorParams::new("B", 1, &overridden_params_changed),
+            operator_c: OperatorParams::new("C", 2, &overridden_params_changed),
+            operator_d: OperatorParams::new("D", 3, &overridden_params_changed),
+
+            eg: EnvelopeParams::new("", Some(&overridden_params_changed)),
+            filter: FilterParams::new(),
+            lfo_1: LfoParams::new(
+                "LFO 1",
+                lfo::LfoScope::Global,
+                Some(&overridden_params_changed),
+            ),
+            lfo_2: LfoParams::new("LFO 2", lfo::LfoScope::Voice, None),
+
+            num_voices: IntParam::new(
+                "Number of Voices",
+                4,
+                IntRange::Linear {
+                    min: 1,
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                    max: consts::MAX_VOICES as i32,
                 },
             ),
-            operator_b_index: FloatParam::new(
-                "Operator B Index",
-                0.0,
-                FloatRange::Linear {
-                    min: 0.0,
-                    max: 10.0,
+            unison: IntParam::new(
+                "Unison",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                    max: consts::MAX_UNISON as i32,
                 },
             ),
-            operator_c_index: FloatParam::new(
-                "Operator C Index",
-                0.0,
+            unison_detune: FloatParam::new(
+                "Unison Detune",
+                10.0,
                 FloatRange::Linear {
                     min: 0.0,
-                    max: 10.0,
+                    max: 100.0,
                 },
+            )
+            .with_unit(" cents"),
+            unison_spread: FloatParam::new(
+                "Unison Spread",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            voice_pan_mode: EnumParam::new("Voice Pan Mode", pan::VoicePanMode::default()),
+            voice_pan_width: FloatParam::new(
+                "Voice Pan Width",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            oversampling: EnumParam::new("Oversampling", oversampling::Oversampling::default()),
+            interpolation: EnumParam::new(
+                "Oscillator Interpolation",
+                wavetable::Interpolation::default(),
             ),
-            operator_d_index: FloatParam::new(
-                "Operator D Index",
+            table_size: EnumParam::new("Oscillator Table Size", wavetable::TableSize::default()),
+            voice_mode: EnumParam::new("Voice Mode", mono::VoiceMode::default()),
+            note_priority: EnumParam::new("Note Priority", mono::NotePriority::default()),
+            glide_time: FloatParam::new(
+                "Glide Time",
                 0.0,
-                FloatRange::Linear {
+                FloatRange::Skewed {
                     min: 0.0,
-                    max: 10.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
                 },
+            )
+            .with_unit(" ms"),
+            glide_mode: EnumParam::new("Glide Mode", mono::GlideMode::default()),
+            algorithm: EnumParam::new("Algorithm", fm_algorithm::FmAlgorithm::default())
+                .with_callback(value_changed(Some(&overridden_params_changed))),
+            play_dx7_voice: BoolParam::new("Play DX7 Voice (Overrides Operators)", false),
+            bend_range_up: IntParam::new("Bend Range Up", 2, IntRange::Linear { min: 0, max: 48 })
+                .with_unit(" semitones"),
+            bend_range_down: IntParam::new(
+                "Bend Range Down",
+                2,
+                IntRange::Linear { min: 0, max: 48 },
+            )
+            .with_unit(" semitones"),
+            mpe_zone: EnumParam::new("MPE Zone", mpe::MpeZone::default()),
+            mpe_member_channels: IntParam::new(
+                "MPE Member Channels",
+                15,
+                IntRange::Linear { min: 1, max: 15 },
             ),
-            operator_a_ratio: FloatParam::new(
-                "Operator A ratio",
-                1.0,
+            mpe_bend_range: IntParam::new(
+                "MPE Bend Range",
+                48,
+                IntRange::Linear { min: 0, max: 96 },
+            )
+            .with_unit(" semitones"),
+        }
+    }
+}
+
+/// The callback of the parameters a DX7 voice overrides, which sets `changed` when the host
+/// changes them
+fn value_changed<T>(changed: Option<&Arc<AtomicBool>>) -> Arc<dyn Fn(T) + Send + Sync> {
+    let changed = changed.cloned();
+    Arc::new(move |_| {
+        if let Some(changed) = &changed {
+            changed.store(true, Ordering::Relaxed);
+        }
+    })
+}
+
+impl FmSynthParams {
+    /// The parameter that a poly modulation target modulates.
+    const fn poly_modulated(&self, target: PolyModulationTarget) -> &FloatParam {
+        match target {
+            PolyModulationTarget::Gain => &self.gain,
+            PolyModulationTarget::Index(operator) => &self.operator(operator).index,
+            PolyModulationTarget::Fine(operator) => &self.operator(operator).fine,
+            PolyModulationTarget::Mix(operator) => &self.operator(operator).mix,
+        }
+    }
+
+    /// The parameters of the operator at `operator` in rendering order.
+    const fn operator(&self, operator: usize) -> &OperatorParams {
+        match operator {
+            0 => &self.operator_a,
+            1 => &self.operator_b,
+            2 => &self.operator_c,
+            _ => &self.operator_d,
+        }
+    }
+
+    /// Builds the waveforms with the user wavetables. This allocates, so it is not done on the
+    /// audio thread.
+    fn wavetables(&self) -> wavetable::Wavetables {
+        self.user_wavetables.read().map_or_else(
+            |_| wavetable::Wavetables::new(&[]),
+            |user_wavetables| wavetable::Wavetables::new(&user_wavetables),
+        )
+    }
+}
+
+impl OperatorParams {
+    /// Creates the parameters of the operator at `operator` in rendering order. A DX7 voice
+    /// overrides all of them, so they set `changed` when the host changes them.
+    #[allow(clippy::too_many_lines)]
+    fn new(name: &str, operator: usize, changed: &Arc<AtomicBool>) -> Self {
+        Self {
+            waveform: EnumParam::new(
+                format!("Operator {name} Waveform"),
+                wavetable::Waveform::default(),
+            )
+            .with_callback(value_changed(Some(changed))),
+            index: FloatParam::new(
+                format!("Operator {name} Index"),
+                0.0,
                 FloatRange::Linear {
                     min: 0.0,
                     max: 10.0,
                 },
-            ),
-            operator_b_ratio: FloatParam::new(
-                "Operator B ratio",
-                1.0,
+            )
+            .with_poly_modulation_id(PolyModulationTarget::Index(operator).id())
+            .with_callback(value_changed(Some(changed))),
+            mod_wheel_index: FloatParam::new(
+                format!("Operator {name} Mod Wheel Index"),
+                0.0,
                 FloatRange::Linear {
                     min: 0.0,
                     max: 10.0,
                 },
-            ),
-            operator_c_ratio: FloatParam::new(
-                "Operator C ratio",
-                1.0,
+            )
+            .with_callback(value_changed(Some(changed))),
+            coarse: IntParam::new(
+                format!("Operator {name} Coarse"),
+                1,
+                IntRange::Linear { min: 0, max: 31 },
+            )
+            .with_value_to_string(Arc::new(|coarse| fm_core::coarse_ratio(coarse).to_string()))
+            .with_callback(value_changed(Some(changed))),
+            fine: FloatParam::new(
+                format!("Operator {name} Fine"),
+                0.0,
                 FloatRange::Linear {
                     min: 0.0,
-                    max: 10.0,
+                    max: 0.99,
                 },
-            ),
-            operator_d_ratio: FloatParam::new(
-                "Operator D ratio",
-                1.0,
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_poly_modulation_id(PolyModulationTarget::Fine(operator).id())
+            .with_step_size(0.01)
+            .with_callback(value_changed(Some(changed))),
+            detune: FloatParam::new(
+                format!("Operator {name} Detune"),
+                0.0,
                 FloatRange::Linear {
-                    min: 0.0,
-                    max: 10.0,
+                    min: -100.0,
+                    max: 100.0,
                 },
-            ),
-
-            operator_a_mix: FloatParam::new(
-                "Operator A Mix",
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" cents")
+            .with_callback(value_changed(Some(changed))),
+            fixed: BoolParam::new(format!("Operator {name} Fixed"), false)
+                .with_callback(value_changed(Some(changed))),
+            frequency: FloatParam::new(
+                format!("Operator {name} Frequency"),
+                440.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 10_000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" Hz")
+            .with_callback(value_changed(Some(changed))),
+            velocity_sensitivity: FloatParam::new(
+                format!("Operator {name} Velocity Sensitivity"),
                 1.0,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
-            ),
-            operator_b_mix: FloatParam::new(
-                "Operator B Mix",
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_callback(value_changed(Some(changed))),
+            velocity_curve: EnumParam::new(
+                format!("Operator {name} Velocity Curve"),
+                fm_core::VelocityCurve::default(),
+            )
+            .with_callback(value_changed(Some(changed))),
+            breakpoint: IntParam::new(
+                format!("Operator {name} Breakpoint"),
+                60,
+                IntRange::Linear { min: 0, max: 127 },
+            )
+            .with_value_to_string(formatters::v2s_i32_note_formatter())
+            .with_string_to_value(formatters::s2v_i32_note_formatter())
+            .with_callback(value_changed(Some(changed))),
+            left_depth: IntParam::new(
+                format!("Operator {name} Left Depth"),
+                0,
+                IntRange::Linear { min: 0, max: 99 },
+            )
+            .with_callback(value_changed(Some(changed))),
+            right_depth: IntParam::new(
+                format!("Operator {name} Right Depth"),
+                0,
+                IntRange::Linear { min: 0, max: 99 },
+            )
+            .with_callback(value_changed(Some(changed))),
+            left_curve: EnumParam::new(
+                format!("Operator {name} Left Curve"),
+                key_scaling::KeyScalingCurve::default(),
+            )
+            .with_callback(value_changed(Some(changed))),
+            right_curve: EnumParam::new(
+                format!("Operator {name} Right Curve"),
+                key_scaling::KeyScalingCurve::default(),
+            )
+            .with_callback(value_changed(Some(changed))),
+            mix: FloatParam::new(
+                format!("Operator {name} Mix"),
                 1.0,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
-            ),
-            operator_c_mix: FloatParam::new(
-                "Operator C Mix",
+            )
+            .with_poly_modulation_id(PolyModulationTarget::Mix(operator).id())
+            .with_callback(value_changed(Some(changed))),
+            pan: FloatParam::new(
+                format!("Operator {name} Pan"),
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_value_to_string(formatters::v2s_f32_panning())
+            .with_string_to_value(formatters::s2v_f32_panning())
+            .with_callback(value_changed(Some(changed))),
+            amp_mod_sensitivity: FloatParam::new(
+                format!("Operator {name} Amp Mod Sensitivity"),
                 1.0,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_callback(value_changed(Some(changed))),
+            feedback: IntParam::new(
+                format!("Operator {name} Feedback"),
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: fm_operator::MAX_FEEDBACK,
+                },
+            )
+            .with_callback(value_changed(Some(changed))),
+            eg: EnvelopeParams::new(&format!("Operator {name} "), Some(changed)),
+            to_a: Self::modulation_depth(name, "A").with_callback(value_changed(Some(changed))),
+            to_b: Self::modulation_depth(name, "B").with_callback(value_changed(Some(changed))),
+            to_c: Self::modulation_depth(name, "C").with_callback(value_changed(Some(changed))),
+            to_d: Self::modulation_depth(name, "D").with_callback(value_changed(Some(changed))),
+        }
+    }
+
+    fn modulation_depth(name: &str, target: &str) -> FloatParam {
+        FloatParam::new(
+            format!("Operator {name} to {target} Depth"),
+            0.0,
+            FloatRange::Linear {
+                min: 0.0,
+                max: 10.0,
+            },
+        )
+    }
+
+    /// Steps the smoothers of the operator's parameters.
+    fn next_step(&self, num_samples_to_process_u32: u32) -> voice_utils::OperatorParameters {
+        voice_utils::OperatorParameters {
+            waveform: self.waveform.value(),
+            coarse: fm_core::coarse_ratio(
+                self.coarse.smoothed.next_step(num_samples_to_process_u32),
             ),
-            operator_d_mix: FloatParam::new(
-                "Operator D Mix",
-                1.0,
-                FloatRange::Linear { min: 0.0, max: 1.0 },
+            fine: self.fine.smoothed.next_step(num_samples_to_process_u32),
+            detune: self.detune.smoothed.next_step(num_samples_to_process_u32),
+            fixed_frequency: {
+                let frequency = self
+                    .frequency
+                    .smoothed
+                    .next_step(num_samples_to_process_u32);
+                self.fixed.value().then_some(frequency)
+            },
+            index: self.index.smoothed.next_step(num_samples_to_process_u32),
+            mod_wheel_index: self
+                .mod_wheel_index
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            velocity_sensitivity: self.velocity_sensitivity.value(),
+            velocity_curve: self.velocity_curve.value(),
+            key_scaling: key_scaling::KeyScaling {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                breakpoint: self.breakpoint.value() as u8,
+                left_depth: self.left_depth.value(),
+                right_depth: self.right_depth.value(),
+                left_curve: self.left_curve.value(),
+                right_curve: self.right_curve.value(),
+            },
+            mix: self.mix.smoothed.next_step(num_samples_to_process_u32),
+            pan: self.pan.smoothed.next_step(num_samples_to_process_u32),
+            amp_mod_sensitivity: self.amp_mod_sensitivity.value(),
+            modulation: [
+                self.to_a.smoothed.next_step(num_samples_to_process_u32),
+                self.to_b.smoothed.next_step(num_samples_to_process_u32),
+                self.to_c.smoothed.next_step(num_samples_to_process_u32),
+                self.to_d.smoothed.next_step(num_samples_to_process_u32),
+            ],
+            feedback: fm_operator::feedback_depth(
+                self.feedback.smoothed.next_step(num_samples_to_process_u32),
             ),
+            eg_params: self.eg.next_step(num_samples_to_process_u32),
+        }
+    }
+}
 
+impl FilterParams {
+    fn new() -> Self {
+        Self {
+            mode: EnumParam::new("Filter Mode", filter::FilterMode::default()),
+            cutoff: FloatParam::new(
+                "Filter Cutoff",
+                20_000.0,
+                FloatRange::Skewed {
+                    min: 20.0,
+                    max: 20_000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(20.0))
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            resonance: FloatParam::new(
+                "Filter Resonance",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            key_tracking: FloatParam::new(
+                "Filter Key Tracking",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            env_amount: FloatParam::new(
+                "Filter Envelope Amount",
+                0.0,
+                FloatRange::Linear {
+                    min: -8.0,
+                    max: 8.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_unit(" oct"),
+            eg: EnvelopeParams::new("Filter ", None),
+        }
+    }
+
+    /// Steps the smoothers of the filter's parameters.
+    fn next_step(&self, num_samples_to_process_u32: u32) -> filter::FilterParameters {
+        filter::FilterParameters {
+            mode: self.mode.value(),
+            cutoff_hz: self.cutoff.smoothed.next_step(num_samples_to_process_u32),
+            resonance: self
+                .resonance
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            key_tracking: self.key_tracking.value(),
+            env_amount: self
+                .env_amount
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            eg_params: self.eg.next_step(num_samples_to_process_u32),
+        }
+    }
+}
+
+impl LfoParams {
+    /// Creates the parameters of an LFO. `name` is put in front of the name of every
+    /// parameter, e.g. "LFO 1". The parameters set `changed`, if any, when the host changes
+    /// them.
+    fn new(name: &str, scope: lfo::LfoScope, changed: Option<&Arc<AtomicBool>>) -> Self {
+        Self {
+            scope: EnumParam::new(format!("{name} Scope"), scope)
+                .with_callback(value_changed(changed)),
+            waveform: EnumParam::new(format!("{name} Waveform"), lfo::LfoWaveform::default())
+                .with_callback(value_changed(changed)),
+            rate: FloatParam::new(
+                format!("{name} Rate"),
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 50.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(20.0))
+            .with_unit(" Hz")
+            .with_callback(value_changed(changed)),
+            tempo_sync: BoolParam::new(format!("{name} Tempo Sync"), false)
+                .with_callback(value_changed(changed)),
+            division: EnumParam::new(
+                format!("{name} Sync Division"),
+                lfo::SyncDivision::default(),
+            )
+            .with_callback(value_changed(changed)),
+            delay: Self::time(name, "Delay").with_callback(value_changed(changed)),
+            fade_in: Self::time(name, "Fade In").with_callback(value_changed(changed)),
+            key_retrigger: BoolParam::new(format!("{name} Key Retrigger"), true)
+                .with_callback(value_changed(changed)),
+            pitch_depth: FloatParam::new(
+                format!("{name} Pitch Depth"),
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 12.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_unit(" semitones")
+            .with_callback(value_changed(changed)),
+            amp_depth: Self::depth(name, "Amp Depth").with_callback(value_changed(changed)),
+            index_depth: Self::depth(name, "Index Depth").with_callback(value_changed(changed)),
+        }
+    }
+
+    fn time(name: &str, segment: &str) -> FloatParam {
+        FloatParam::new(
+            format!("{name} {segment}"),
+            0.0,
+            FloatRange::Skewed {
+                min: 0.0,
+                max: 5000.0,
+                factor: FloatRange::skew_factor(-2.0),
+            },
+        )
+        .with_unit(" ms")
+    }
+
+    fn depth(name: &str, target: &str) -> FloatParam {
+        FloatParam::new(
+            format!("{name} {target}"),
+            0.0,
+            FloatRange::Linear { min: 0.0, max: 1.0 },
+        )
+        .with_smoother(SmoothingStyle::Linear(20.0))
+        .with_unit("%")
+        .with_value_to_string(formatters::v2s_f32_percentage(0))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+    }
+
+    /// Steps the smoothers of the LFO's parameters. A synced LFO follows `tempo` and, while the
+    /// host plays, `position_beats`.
+    fn next_step(
+        &self,
+        num_samples_to_process_u32: u32,
+        tempo: Option<f64>,
+        position_beats: Option<f64>,
+    ) -> lfo::LfoParameters {
+        let mut params = lfo::LfoParameters {
+            scope: self.scope.value(),
+            waveform: self.waveform.value(),
+            rate_hz: self.rate.smoothed.next_step(num_samples_to_process_u32),
+            sync_phase: None,
+            delay_msec: self.delay.value(),
+            fade_in_msec: self.fade_in.value(),
+            key_retrigger: self.key_retrigger.value(),
+            pitch_depth: self
+                .pitch_depth
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            amp_depth: self
+                .amp_depth
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            index_depth: self
+                .index_depth
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+        };
+        if let Some(tempo) = tempo.filter(|_| self.tempo_sync.value()) {
+            params.sync_to_tempo(self.division.value(), tempo, position_beats);
+        }
+        params
+    }
+}
+
+impl EnvelopeParams {
+    /// Creates the envelope parameters. `name_prefix` is put in front of the name of every
+    /// parameter, e.g. "Operator A ". The parameters set `changed`, if any, when the host
+    /// changes them.
+    fn new(name_prefix: &str, changed: Option<&Arc<AtomicBool>>) -> Self {
+        Self {
+            mode: EnumParam::new(
+                format!("{name_prefix}EG Mode"),
+                linear_eg::EGMode::default(),
+            )
+            .with_callback(value_changed(changed)),
             attack_time: FloatParam::new(
-                "Attack Time",
+                format!("{name_prefix}Attack Time"),
                 10.0,
                 FloatRange::Linear {
                     min: 1.0,
@@ -207,9 +958,10 @@ impl Default for FmSynthParams {
                 },
             )
             .with_smoother(SmoothingStyle::Logarithmic(50.0))
-            .with_unit(" ms"),
+            .with_unit(" ms")
+            .with_callback(value_changed(changed)),
             decay_time: FloatParam::new(
-                "Decay Time",
+                format!("{name_prefix}Decay Time"),
                 100.0,
                 FloatRange::Linear {
                     min: 1.0,
@@ -217,30 +969,105 @@ impl Default for FmSynthParams {
                 },
             )
             .with_smoother(SmoothingStyle::Logarithmic(50.0))
-            .with_unit(" ms"),
+            .with_unit(" ms")
+            .with_callback(value_changed(changed)),
             sustain_level: FloatParam::new(
-                "Sustain Level",
+                format!("{name_prefix}Sustain Level"),
                 1.0,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
             )
-            .with_smoother(SmoothingStyle::Linear(50.0)),
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_callback(value_changed(changed)),
             release_time: FloatParam::new(
-                "Release Time",
+                format!("{name_prefix}Release Time"),
                 100.0,
                 FloatRange::Linear {
                     min: 1.0,
                     max: 1000.0,
                 },
-            ),
-            num_voices: IntParam::new(
-                "Number of Voices",
-                4,
-                IntRange::Linear {
-                    min: 1,
-                    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
-                    max: consts::MAX_VOICES as i32,
-                },
-            ),
+            )
+            .with_unit(" ms")
+            .with_callback(value_changed(changed)),
+            attack_curve: Self::curve(name_prefix, "Attack", 0.45)
+                .with_callback(value_changed(changed)),
+            decay_curve: Self::curve(name_prefix, "Decay", 0.7)
+                .with_callback(value_changed(changed)),
+            release_curve: Self::curve(name_prefix, "Release", 0.7)
+                .with_callback(value_changed(changed)),
+            rate_1: Self::dx_setting(name_prefix, "Rate 1", 99, 99)
+                .with_callback(value_changed(changed)),
+            rate_2: Self::dx_setting(name_prefix, "Rate 2", 99, 99)
+                .with_callback(value_changed(changed)),
+            rate_3: Self::dx_setting(name_prefix, "Rate 3", 99, 99)
+                .with_callback(value_changed(changed)),
+            rate_4: Self::dx_setting(name_prefix, "Rate 4", 99, 99)
+                .with_callback(value_changed(changed)),
+            level_1: Self::dx_setting(name_prefix, "Level 1", 99, 99)
+                .with_callback(value_changed(changed)),
+            level_2: Self::dx_setting(name_prefix, "Level 2", 99, 99)
+                .with_callback(value_changed(changed)),
+            level_3: Self::dx_setting(name_prefix, "Level 3", 99, 99)
+                .with_callback(value_changed(changed)),
+            level_4: Self::dx_setting(name_prefix, "Level 4", 0, 99)
+                .with_callback(value_changed(changed)),
+            rate_scaling: Self::dx_setting(name_prefix, "Rate Scaling", 0, 7)
+                .with_callback(value_changed(changed)),
+        }
+    }
+
+    fn curve(name_prefix: &str, segment: &str, default: f32) -> FloatParam {
+        FloatParam::new(
+            format!("{name_prefix}{segment} Curve"),
+            default,
+            FloatRange::Linear { min: 0.0, max: 1.0 },
+        )
+    }
+
+    fn dx_setting(name_prefix: &str, name: &str, default: i32, max: i32) -> IntParam {
+        IntParam::new(
+            format!("{name_prefix}{name}"),
+            default,
+            IntRange::Linear { min: 0, max },
+        )
+    }
+
+    /// Steps the smoothers of the envelope's parameters.
+    fn next_step(&self, num_samples_to_process_u32: u32) -> linear_eg::EGParameters {
+        linear_eg::EGParameters {
+            mode: self.mode.value(),
+            attack_time_msec: self
+                .attack_time
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            decay_time_msec: self
+                .decay_time
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            release_time_msec: self
+                .release_time
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            start_level: 0.0,
+            sustain_level: self
+                .sustain_level
+                .smoothed
+                .next_step(num_samples_to_process_u32),
+            attack_curve: self.attack_curve.value(),
+            decay_curve: self.decay_curve.value(),
+            release_curve: self.release_curve.value(),
+            rates: [
+                self.rate_1.value(),
+                self.rate_2.value(),
+                self.rate_3.value(),
+                self.rate_4.value(),
+            ],
+            levels: [
+                self.level_1.value(),
+                self.level_2.value(),
+                self.level_3.value(),
+                self.level_4.value(),
+            ],
+            rate_scaling: self.rate_scaling.value(),
         }
     }
 }
@@ -269,50 +1096,133 @@ impl Plugin for FmSynth {
         // },
     ];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     // const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     // If the plugin can send or receive SysEx messages, it can define a type to wrap around those
     // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
-    // from plain byte buffers.
-    type SysExMessage = ();
+    // from plain byte buffers. DX7 voice and bank dumps and parameter changes are received, so
+    // hardware editors and librarians can drive the plugin, and so are user wavetable dumps.
+    type SysExMessage = sysex::SysEx;
     // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // documentation for more information. The waveforms are rebuilt in the background when a
+    // user wavetable is loaded.
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = Arc::clone(&self.params);
+        let next_wavetables = Arc::clone(&self.next_wavetables);
+        let user_wavetable_dumps = Arc::clone(&self.user_wavetable_dumps);
+        Box::new(move |task| match task {
+            Task::LoadUserWavetable(slot) => {
+                if let (Ok(mut user_wavetables), Ok(dumps)) =
+                    (params.user_wavetables.write(), user_wavetable_dumps.lock())
+                {
+                    if user_wavetables.len() <= slot {
+                        user_wavetables.resize(slot + 1, Vec::new());
+                    }
+                    user_wavetables[slot].clone_from(&dumps[slot]);
+                }
+                let wavetables = Arc::new(params.wavetables());
+                if let Ok(mut next_wavetables) = next_wavetables.lock() {
+                    *next_wavetables = Some(wavetables);
+                }
+            }
+            Task::DropWavetables(wavetables) => drop(wavetables),
+        })
+    }
+
     fn initialize(
         &mut self,
         audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
         // Resize buffers and perform other potentially expensive initialization operations here.
         // The `reset()` function is always called right after this function. You can remove this
         // function if you do not need it.
         self.sample_rate = buffer_config.sample_rate;
+        // Banks and voices are stored on the audio thread, so there must be room for them
+        if let Ok(mut dx7_bank) = self.params.dx7_bank.write() {
+            let len = dx7_bank.len();
+            dx7_bank.reserve_exact(dx7_sysex::BANK_DATA_SIZE.saturating_sub(len));
+        }
+        let dx7_voice = self
+            .params
+            .dx7_voice
+            .write()
+            .ok()
+            .and_then(|mut dx7_voice| {
+                let len = dx7_voice.len();
+                dx7_voice.reserve_exact(dx7_sysex::VOICE_SIZE.saturating_sub(len));
+                let data: &[u8; dx7_sysex::VOICE_SIZE] = dx7_voice.as_slice().try_into().ok()?;
+                Some(dx7_sysex::Dx7Voice::from_unpacked(data))
+            });
+        self.dx7_voice = dx7_voice.map(|voice| (voice, voice.to_parameters(|_| {})));
+        // Restoring the state sets the parameters, but that doesn't replace the voice
+        self.params
+            .overridden_params_changed
+            .store(false, Ordering::Relaxed);
         // get the number of output channels
         let num_channels = audio_io_layout
             .main_output_channels
             .map_or(2, NonZeroU32::get);
+        // The voices never render more than a block at a time
         self.voices.initialize(
             4,
             num_channels as usize,
-            buffer_config.max_buffer_size as usize,
+            (buffer_config.max_buffer_size as usize).min(MAX_BLOCK_SIZE),
         );
+        let oversampling = self.params.oversampling.value();
+        self.voices
+            .set_oversampling(oversampling, &self.voice_params);
+        context.set_latency_samples(oversampling.latency_samples());
+        self.wavetables = Arc::new(self.params.wavetables());
+        self.voices.set_wavetables(&self.wavetables);
+        if let Ok(mut dumps) = self.user_wavetable_dumps.lock() {
+            for samples in dumps.iter_mut() {
+                samples.reserve_exact(sysex::MAX_DUMP_SAMPLES);
+            }
+        }
         true
     }
 
+    // Sessions from before the ratio of the operators was split into coarse, fine and detune have
+    // a single ratio, which is converted to the closest coarse and fine settings
+    fn filter_state(state: &mut PluginState) {
+        for operator in ["operator_a", "operator_b", "operator_c", "operator_d"] {
+            let Some(ParamValue::F32(ratio)) = state.params.remove(&format!("{operator}_ratio"))
+            else {
+                continue;
+            };
+            let (coarse, fine) = fm_core::coarse_and_fine(ratio);
+            state
+                .params
+                .entry(format!("{operator}_coarse"))
+                .or_insert(ParamValue::I32(coarse));
+            state
+                .params
+                .entry(format!("{operator}_fine"))
+                .or_insert(ParamValue::F32(fine));
+        }
+    }
+
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
         self.voices.reset(&self.voice_params);
+        self.pitch_bend.reset(0.0);
+        for bend in &self.mpe_pitch_bends {
+            bend.reset(0.0);
+        }
+        self.mod_wheel.reset(0.0);
+        self.expression.reset(1.0);
     }
     #[allow(clippy::cast_possible_truncation)]
     fn process(
@@ -335,7 +1245,18 @@ impl Plugin for FmSynth {
                 .smoothed
                 .next_step(num_samples as u32) as usize,
         );
-        self.sample_rate = context.transport().sample_rate;
+        let transport = context.transport();
+        self.sample_rate = transport.sample_rate;
+        self.tempo = transport.tempo;
+        let position_beats = transport.pos_beats().filter(|_| transport.playing);
+        let oversampling = self.params.oversampling.value();
+        if oversampling != self.voices.oversampling() {
+            self.voices
+                .set_oversampling(oversampling, &self.voice_params);
+            context.set_latency_samples(oversampling.latency_samples());
+        }
+        self.update_mpe_zone();
+        self.update_wavetables(context);
         let output = buffer.as_slice();
 
         let mut next_event = context.next_event();
@@ -351,37 +1272,7 @@ impl Plugin for FmSynth {
                 match next_event {
                     Some(event) if (event.timing() as usize) <= block_start => {
                         nih_dbg!(event);
-                        match event {
-                            NoteEvent::NoteOn {
-                                note,
-                                velocity,
-                                voice_id,
-                                channel,
-                                ..
-                            } => {
-                                self.voices.note_on(
-                                    note,
-                                    velocity,
-                                    voice_id,
-                                    channel,
-                                    &self.voice_params,
-                                    self.sample_rate,
-                                );
-                            }
-                            NoteEvent::NoteOff {
-                                note,
-                                voice_id,
-                                channel,
-                                ..
-                            } => self.voices.note_off(
-                                voice_id,
-                                channel,
-                                note,
-                                &self.voice_params,
-                                self.sample_rate,
-                            ),
-                            _ => {}
-                        };
+                        self.receive_event(&event, context);
 
                         next_event = context.next_event();
                     }
@@ -392,17 +1283,26 @@ impl Plugin for FmSynth {
                     _ => break 'events,
                 }
             }
+            self.send_ended_notes(context, block_start);
 
             let num_samples_to_process = block_end.checked_sub(block_start);
             let num_samples_to_process_u32 = num_samples_to_process.unwrap_or(0) as u32;
+            #[allow(clippy::cast_precision_loss)]
+            let block_start_sec = block_start as f64 / f64::from(self.sample_rate);
+            self.position_beats = position_beats
+                .zip(self.tempo)
+                .map(|(beats, tempo)| (block_start_sec * tempo).mul_add(1.0 / 60.0, beats));
             self.set_parameters(num_samples_to_process_u32);
+            self.update_pitch_bend(num_samples_to_process_u32);
             self.voices.render(
                 output,
                 &self.voice_params,
-                self.sample_rate,
+                self.voice_sample_rate(),
                 block_start,
                 block_end,
             );
+            self.send_ended_notes(context, block_end - 1);
+            self.apply_expression(output, block_start, block_end);
             // And then just keep processing blocks until we've run out of buffer to fill
             block_start = block_end;
             block_end = (block_start + MAX_BLOCK_SIZE).min(num_samples);
@@ -413,92 +1313,420 @@ impl Plugin for FmSynth {
 }
 
 impl FmSynth {
-    fn set_parameters(&mut self, num_samples_to_process_u32: u32) {
-        self.voice_params.eg_params = linear_eg::EGParameters {
-            attack_time_msec: self
-                .params
-                .attack_time
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            decay_time_msec: self
-                .params
-                .decay_time
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            release_time_msec: self
-                .params
-                .release_time
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            start_level: 0.0,
-            sustain_level: self
-                .params
-                .sustain_level
-                .smoothed
-                .next_step(num_samples_to_process_u32),
+    /// Handles a note event at the start of the block it belongs to.
+    #[allow(clippy::too_many_lines)]
+    fn receive_event(
+        &mut self,
+        event: &PluginNoteEvent<Self>,
+        context: &impl ProcessContext<Self>,
+    ) {
+        match *event {
+            NoteEvent::NoteOn {
+                note,
+                velocity,
+                voice_id,
+                channel,
+                ..
+            } => {
+                // MPE controllers bend the channel before the note, which starts at the bend
+                // instead of gliding to it
+                let bend = &self.mpe_pitch_bends[usize::from(channel)];
+                if bend.is_smoothing() {
+                    #[allow(clippy::cast_sign_loss)]
+                    let semitones = bend.next_step(bend.steps_left() as u32);
+                    self.voices
+                        .set_channel_expression(channel, NoteExpression::Tuning(semitones));
+                }
+                self.voices.note_on(
+                    note,
+                    velocity,
+                    voice_id,
+                    channel,
+                    &self.voice_params,
+                    self.voice_sample_rate(),
+                );
+            }
+            NoteEvent::NoteOff {
+                note,
+                voice_id,
+                channel,
+                ..
+            } => self.voices.note_off(
+                voice_id,
+                channel,
+                note,
+                &self.voice_params,
+                self.voice_sample_rate(),
+            ),
+            NoteEvent::MidiSysEx {
+                message: sysex::SysEx::Dx7(message),
+                ..
+            } => self.receive_dx7_sysex(message),
+            NoteEvent::MidiSysEx {
+                message: sysex::SysEx::UserWavetable(dump),
+                ..
+            } => self.receive_user_wavetable(dump, context),
+            NoteEvent::MidiProgramChange { program, .. } => {
+                let voice = self
+                    .params
+                    .dx7_bank
+                    .try_read()
+                    .ok()
+                    .and_then(|dx7_bank| dx7_sysex::bank_voice(&dx7_bank, program));
+                if let Some(voice) = voice {
+                    self.load_dx7_voice(voice);
+                }
+            }
+            // The wheel is centered at 0.5
+            NoteEvent::MidiPitchBend { channel, value, .. } => {
+                let bend = value.mul_add(2.0, -1.0);
+                if self.is_mpe_member_channel(channel) {
+                    #[allow(clippy::cast_precision_loss)]
+                    let semitones = bend * self.params.mpe_bend_range.value() as f32;
+                    self.mpe_pitch_bends[usize::from(channel)]
+                        .set_target(self.sample_rate, semitones);
+                } else {
+                    self.pitch_bend.set_target(self.sample_rate, bend);
+                }
+            }
+            NoteEvent::MidiChannelPressure {
+                channel, pressure, ..
+            } if self.is_mpe_member_channel(channel) => {
+                self.voices
+                    .set_channel_expression(channel, NoteExpression::Pressure(pressure));
+            }
+            NoteEvent::MidiCC {
+                channel, cc, value, ..
+            } => {
+                if cc == mpe::TIMBRE_CC && self.is_mpe_member_channel(channel) {
+                    self.voices
+                        .set_channel_expression(channel, NoteExpression::Brightness(value));
+                } else {
+                    self.receive_cc(cc, value);
+                }
+            }
+            NoteEvent::PolyModulation {
+                voice_id,
+                poly_modulation_id,
+                normalized_offset,
+                ..
+            } => {
+                if let Some(target) = PolyModulationTarget::from_id(poly_modulation_id) {
+                    let value = self
+                        .params
+                        .poly_modulated(target)
+                        .preview_modulated(normalized_offset);
+                    self.voices.set_poly_modulation(
+                        voice_id,
+                        target,
+                        normalized_offset,
+                        value,
+                        self.voice_sample_rate(),
+                    );
+                }
+            }
+            // The modulated voices follow the automation of the parameter
+            NoteEvent::MonoAutomation {
+                poly_modulation_id,
+                normalized_value,
+                ..
+            } => {
+                if let Some(target) = PolyModulationTarget::from_id(poly_modulation_id) {
+                    let param = self.params.poly_modulated(target);
+                    self.voices.update_poly_modulation(
+                        target,
+                        &|normalized_offset| {
+                            param.preview_plain(normalized_value + normalized_offset)
+                        },
+                        self.voice_sample_rate(),
+                    );
+                }
+            }
+            NoteEvent::PolyTuning {
+                voice_id,
+                channel,
+                note,
+                tuning,
+                ..
+            } => self.voices.set_note_expression(
+                voice_id,
+                channel,
+                note,
+                NoteExpression::Tuning(tuning),
+            ),
+            NoteEvent::PolyPressure {
+                voice_id,
+                channel,
+                note,
+                pressure,
+                ..
+            } => self.voices.set_note_expression(
+                voice_id,
+                channel,
+                note,
+                NoteExpression::Pressure(pressure),
+            ),
+            NoteEvent::PolyBrightness {
+                voice_id,
+                channel,
+                note,
+                brightness,
+                ..
+            } => self.voices.set_note_expression(
+                voice_id,
+                channel,
+                note,
+                NoteExpression::Brightness(brightness),
+            ),
+            _ => {}
+        }
+    }
+
+    /// Loads a DX7 voice or bank, or changes one of the settings of the voice. A parameter change
+    /// without a voice dump before it changes the DX7's INIT VOICE. A bank replaces the stored
+    /// bank and plays its first voice.
+    fn receive_dx7_sysex(&mut self, message: dx7_sysex::Dx7SysExMessage) {
+        let mut voice = self.dx7_voice.map(|(voice, _)| voice).unwrap_or_default();
+        match message {
+            // The dumps are read in the block they arrive in, so their data is still there
+            dx7_sysex::Dx7SysExMessage::VoiceDump { data, .. } => {
+                let Some(dump) = data
+                    .read(|data| data.try_into().ok().map(dx7_sysex::Dx7Voice::from_unpacked))
+                    .flatten()
+                else {
+                    return;
+                };
+                voice = dump;
+            }
+            dx7_sysex::Dx7SysExMessage::BankDump { data, .. } => {
+                let Some(first_voice) = data.read(|data| {
+                    // The room for the bank was made in `initialize()`. If the host is saving the
+                    // state right now, the bank still plays but isn't stored.
+                    if let Ok(mut dx7_bank) = self.params.dx7_bank.try_write() {
+                        dx7_bank.clear();
+                        dx7_bank.extend_from_slice(data);
+                    }
+                    dx7_sysex::bank_voice(data, 0).unwrap_or_default()
+                }) else {
+                    return;
+                };
+                voice = first_voice;
+            }
+            dx7_sysex::Dx7SysExMessage::ParameterChange {
+                parameter, value, ..
+            } => voice.set_parameter(parameter, value),
+        }
+        self.load_dx7_voice(voice);
+    }
+
+    /// Plays a DX7 voice and stores it with the state of the plugin. The settings that can't be
+    /// converted exactly are approximated as well as possible, and logged.
+    fn load_dx7_voice(&mut self, voice: dx7_sysex::Dx7Voice) {
+        let parameters = voice.to_parameters(|approximation| {
+            nih_log!("DX7 voice {}: {approximation}", voice.name());
+        });
+        self.dx7_voice = Some((voice, parameters));
+        // The room for the voice was made in `initialize()`
+        if let Ok(mut dx7_voice) = self.params.dx7_voice.try_write() {
+            dx7_voice.clear();
+            dx7_voice.extend_from_slice(&voice.to_unpacked());
+        }
+    }
+
+    /// Keeps the samples of a user wavetable for the background task, which stores them and
+    /// builds the waveforms with them, since that allocates. If the background task is storing
+    /// the samples of an earlier dump right now, the dump is lost.
+    fn receive_user_wavetable(
+        &self,
+        dump: sysex::UserWavetableDump,
+        context: &impl ProcessContext<Self>,
+    ) {
+        let Ok(mut dumps) = self.user_wavetable_dumps.try_lock() else {
+            return;
+        };
+        let samples = &mut dumps[dump.slot];
+        samples.clear();
+        // The dump is read in the block it arrives in, so its samples are still there
+        if dump.read_samples(samples) {
+            drop(dumps);
+            context.execute_background(Task::LoadUserWavetable(dump.slot));
+        }
+    }
+
+    /// Stops playing the DX7 voice when one of the parameters it overrides was changed, so the
+    /// plugin's own operators play again. The bank stays.
+    fn clear_dx7_voice(&mut self) {
+        self.dx7_voice = None;
+        if let Ok(mut dx7_voice) = self.params.dx7_voice.try_write() {
+            dx7_voice.clear();
+        }
+    }
+
+    /// Tells the host which notes the voices stopped playing, so it can end the voices it
+    /// modulates. The notes ended by `timing`.
+    #[allow(clippy::cast_possible_truncation)]
+    fn send_ended_notes(&mut self, context: &mut impl ProcessContext<Self>, timing: usize) {
+        for note in self.voices.take_ended_notes() {
+            context.send_event(NoteEvent::VoiceTerminated {
+                timing: timing as u32,
+                voice_id: note.voice_id,
+                channel: note.channel,
+                note: note.note,
+            });
+        }
+    }
+
+    /// Hands the waveforms the background task built with a new user wavetable to the voices.
+    /// The waveforms they played before are dropped by the background thread.
+    fn update_wavetables(&mut self, context: &impl ProcessContext<Self>) {
+        let wavetables = self
+            .next_wavetables
+            .try_lock()
+            .ok()
+            .and_then(|mut next_wavetables| next_wavetables.take());
+        if let Some(wavetables) = wavetables {
+            let previous = std::mem::replace(&mut self.wavetables, wavetables);
+            self.voices.set_wavetables(&self.wavetables);
+            context.execute_background(Task::DropWavetables(previous));
+        }
+    }
+
+    /// Whether `channel` only controls the note played on it, because it is a member channel of
+    /// the MPE zone.
+    fn is_mpe_member_channel(&self, channel: u8) -> bool {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let num_member_channels = self.params.mpe_member_channels.value() as u8;
+        self.params
+            .mpe_zone
+            .value()
+            .is_member_channel(channel, num_member_channels)
+    }
+
+    /// Handles the MIDI CCs the synth responds to. The others are ignored.
+    fn receive_cc(&mut self, cc: u8, value: f32) {
+        match cc {
+            control_change::MODULATION_MSB => self.mod_wheel.set_target(self.sample_rate, value),
+            control_change::EXPRESSION_CONTROLLER_MSB => {
+                self.expression.set_target(self.sample_rate, value);
+            }
+            // Like most keyboards, the pedal is down from the middle of its range
+            control_change::DAMPER_PEDAL => {
+                self.voices.set_sustain_pedal(
+                    value >= 0.5,
+                    &self.voice_params,
+                    self.voice_sample_rate(),
+                );
+            }
+            control_change::ALL_SOUND_OFF => self.voices.all_sound_off(&self.voice_params),
+            control_change::ALL_NOTES_OFF => {
+                self.voices
+                    .all_notes_off(&self.voice_params, self.voice_sample_rate());
+            }
+            _ => {}
+        }
+    }
+
+    /// Scales a block of the output by the expression pedal, one sample at a time.
+    fn apply_expression(&self, output: &mut [&mut [f32]], block_start: usize, block_end: usize) {
+        for sample_index in block_start..block_end {
+            let expression = self.expression.next();
+            for channel in output.iter_mut() {
+                channel[sample_index] *= expression;
+            }
+        }
+    }
+
+    /// The sample rate the voices run at, with oversampling.
+    #[allow(clippy::cast_precision_loss)]
+    fn voice_sample_rate(&self) -> f32 {
+        self.sample_rate * self.voices.oversampling().factor() as f32
+    }
+
+    /// Steps the pitch bend smoother and bends all the voices by the wheel's position scaled by
+    /// the bend range in the direction of the bend. The pitch bends of the MPE member channels
+    /// are smoothed the same way.
+    fn update_pitch_bend(&mut self, num_samples_to_process_u32: u32) {
+        let bend = self.pitch_bend.next_step(num_samples_to_process_u32);
+        let range = if bend >= 0.0 {
+            self.params.bend_range_up.value()
+        } else {
+            self.params.bend_range_down.value()
         };
+        #[allow(clippy::cast_precision_loss)]
+        self.voices.set_pitch_bend(bend * range as f32);
+        for (channel, bend) in (0..).zip(&self.mpe_pitch_bends) {
+            if bend.is_smoothing() {
+                let semitones = bend.next_step(num_samples_to_process_u32);
+                self.voices
+                    .set_channel_expression(channel, NoteExpression::Tuning(semitones));
+            }
+        }
+    }
+
+    /// Clears the expressions of the channels when the MPE zone changes, so the notes on what
+    /// used to be member channels stop being bent, pressed or brightened.
+    fn update_mpe_zone(&mut self) {
+        let mpe_zone = self.params.mpe_zone.value();
+        if mpe_zone != self.mpe_zone {
+            self.mpe_zone = mpe_zone;
+            for bend in &self.mpe_pitch_bends {
+                bend.reset(0.0);
+            }
+            self.voices.clear_channel_expressions();
+        }
+    }
+
+    fn set_parameters(&mut self, num_samples_to_process_u32: u32) {
+        if self
+            .params
+            .overridden_params_changed
+            .swap(false, Ordering::Relaxed)
+            && self.params.play_dx7_voice.value()
+        {
+            self.clear_dx7_voice();
+        }
+        self.voice_params.eg_params = self.params.eg.next_step(num_samples_to_process_u32);
         self.voice_params.fm_params = voice_utils::FmParams {
-            op_a_ratio: self
-                .params
-                .operator_a_ratio
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_b_ratio: self
-                .params
-                .operator_b_ratio
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_c_ratio: self
-                .params
-                .operator_c_ratio
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_d_ratio: self
-                .params
-                .operator_d_ratio
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_a_index: self
-                .params
-                .operator_a_index
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_b_index: self
-                .params
-                .operator_b_index
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_c_index: self
-                .params
-                .operator_c_index
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_d_index: self
-                .params
-                .operator_d_index
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_a_mix: self
-                .params
-                .operator_a_mix
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_b_mix: self
-                .params
-                .operator_b_mix
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_c_mix: self
-                .params
-                .operator_c_mix
-                .smoothed
-                .next_step(num_samples_to_process_u32),
-            op_d_mix: self
-                .params
-                .operator_d_mix
-                .smoothed
-                .next_step(num_samples_to_process_u32),
+            algorithm: self.params.algorithm.value(),
+            operators: [
+                self.params.operator_a.next_step(num_samples_to_process_u32),
+                self.params.operator_b.next_step(num_samples_to_process_u32),
+                self.params.operator_c.next_step(num_samples_to_process_u32),
+                self.params.operator_d.next_step(num_samples_to_process_u32),
+            ],
         };
+        self.voice_params.lfos = [&self.params.lfo_1, &self.params.lfo_2]
+            .map(|lfo| lfo.next_step(num_samples_to_process_u32, self.tempo, self.position_beats));
+        // The voice only brings the settings of a DX7 voice. The rest stay with the plugin.
+        if let Some((_, parameters)) = self
+            .dx7_voice
+            .as_ref()
+            .filter(|_| self.params.play_dx7_voice.value())
+        {
+            self.voice_params.eg_params = parameters.eg_params;
+            self.voice_params.fm_params = parameters.fm_params;
+            self.voice_params.lfos[0] = parameters.lfos[0];
+        }
+        self.voice_params.mod_wheel = self.mod_wheel.next_step(num_samples_to_process_u32);
+        self.voice_params.filter = self.params.filter.next_step(num_samples_to_process_u32);
+        self.voice_params.voice_mode = self.params.voice_mode.value();
+        self.voice_params.note_priority = self.params.note_priority.value();
+        self.voice_params.glide_time_msec = self.params.glide_time.value();
+        self.voice_params.glide_mode = self.params.glide_mode.value();
+        #[allow(clippy::cast_sign_loss)]
+        let unison = self.params.unison.value() as usize;
+        self.voice_params.unison = unison;
+        self.voice_params.unison_detune = self.params.unison_detune.value();
+        self.voice_params.unison_spread = self.params.unison_spread.value();
+        self.voice_params.voice_pan_mode = self.params.voice_pan_mode.value();
+        self.voice_params.voice_pan_width = self.params.voice_pan_width.value();
+        self.voice_params.interpolation = self.params.interpolation.value();
+        self.voice_params.table_size = self.params.table_size.value();
+        self.voice_params.gain = self
+            .params
+            .gain
+            .smoothed
+            .next_step(num_samples_to_process_u32);
     }
 }
 
@@ -515,6 +1743,13 @@ impl ClapPlugin for FmSynth {
         ClapFeature::Stereo,
         // ClapFeature::Mono,
     ];
+    // The gain, index, fine ratio and mix can be modulated per voice. The host is told when a
+    // voice ends, see `send_ended_notes()`.
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        #[allow(clippy::cast_possible_truncation)]
+        max_voice_capacity: consts::MAX_VOICES as u32,
+        supports_overlapping_voices: true,
+    });
 }
 
 impl Vst3Plugin for FmSynth {
@@ -1,29 +1,63 @@
-use nih_plug::nih_debug_assert;
+use nih_plug::{nih_debug_assert, prelude::Enum};
 
 use crate::consts::{MAX_EG_LEVEL, MIN_EG_LEVEL, SHUTDOWN_TIME_MSEC};
 
-#[derive(Clone)]
+/// Selects the shape of the envelope segments.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum EGMode {
+    /// Straight line segments, see `LinearEG`
+    #[default]
+    #[id = "linear"]
+    #[name = "Linear"]
+    Linear,
+    /// RC charge and discharge curves, see `AnalogEG`
+    #[id = "analog"]
+    #[name = "Analog"]
+    Analog,
+    /// The four rates and four levels of the DX7, see `DxEG`
+    #[id = "dx"]
+    #[name = "DX7"]
+    Dx,
+}
+
+#[derive(Clone, Copy)]
 pub struct EGParameters {
+    pub mode: EGMode,
     // ADSR times from user
-    pub attack_time_msec: f32, // from GUI control
-    pub decay_time_msec: f32,  // from GUI control
-    // slope_time_msec: f32,   // from GUI control
+    pub attack_time_msec: f32,  // from GUI control
+    pub decay_time_msec: f32,   // from GUI control
     pub release_time_msec: f32, // from GUI control
 
-    // For DXEG
-    pub start_level: f32, // from GUI control
-    // end_level: f32,          // from GUI control
-    // decay_level: f32,        // from GUI control
+    pub start_level: f32,   // from GUI control
     pub sustain_level: f32, // from GUI control
+
+    // For DXEG, in the 0-99 range of the DX7. R1/L1 is the attack, R2/L2 and R3/L3 decay to the
+    // sustain level L3 and R4/L4 is the release.
+    pub rates: [i32; 4],   // from GUI control
+    pub levels: [i32; 4],  // from GUI control
+    pub rate_scaling: i32, // from GUI control, 0-7, in every mode
+
+    // The curvature of the analog segments, from 0 (almost straight) to 1 (strongly curved)
+    pub attack_curve: f32,  // from GUI control
+    pub decay_curve: f32,   // from GUI control
+    pub release_curve: f32, // from GUI control
 }
 impl Default for EGParameters {
     fn default() -> Self {
         Self {
+            mode: EGMode::Linear,
             attack_time_msec: 10.0,
             decay_time_msec: 50.0,
             release_time_msec: 100.0,
             start_level: 0.0,
             sustain_level: 0.4,
+            // The envelope of the DX7 init voice
+            rates: [99, 99, 99, 99],
+            levels: [99, 99, 99, 0],
+            rate_scaling: 0,
+            attack_curve: 0.45,
+            decay_curve: 0.7,
+            release_curve: 0.7,
         }
     }
 }
@@ -39,19 +73,15 @@ pub trait EnvelopeGenerator {
     /// Updates the envelope generator with the given parameters.
     fn update(&mut self, parameters: &EGParameters);
 
-    /// Renders the envelope generator output for the specified number of samples.
-    fn render(
-        &mut self,
-        parameters: &EGParameters,
-        num_samples_to_process: usize,
-        sample_rate: f32,
-    ) -> f32;
+    /// Renders the envelope generator output into `output`, one value for every sample.
+    fn render(&mut self, parameters: &EGParameters, output: &mut [f32], sample_rate: f32);
 
     /// Notifies the envelope generator that a note has been turned off.
     fn note_off(&mut self, parameters: &EGParameters, sample_rate: f32);
 
-    /// Notifies the envelope generator that a note has been turned on.
-    fn note_on(&mut self, parameters: &EGParameters, sample_rate: f32);
+    /// Notifies the envelope generator that a note has been turned on. `note` is the MIDI note
+    /// number, which the envelope can use to scale its rates.
+    fn note_on(&mut self, parameters: &EGParameters, note: u8, sample_rate: f32);
 
     /// Shuts down the envelope generator. Used for voice stealing.
     fn shutdown(&mut self, parameters: &EGParameters, sample_rate: f32);
@@ -61,7 +91,7 @@ pub trait EnvelopeGenerator {
 
 /// Represents the state of the envelope generator.
 #[derive(Debug, PartialEq, Clone)]
-enum EnvelopeState {
+pub enum EnvelopeState {
     Off,
     Attack,
     Decay,
@@ -122,21 +152,13 @@ impl EnvelopeGenerator for LinearEG {
         // This is where we would do any work that needs to be done when the parameters change.
     }
 
-    /// Renders the output of the linear envelope generator for the specified number of samples.
-    /// We only return the output value for the first sample.
-    fn render(
-        &mut self,
-        parameters: &EGParameters,
-        num_samples_to_process: usize,
-        sample_rate: f32,
-    ) -> f32 {
-        // TODO: Implement the render method
-        let mut output = 0.0;
-        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
-        for i in 0..(num_samples_to_process as i32) {
+    /// Renders the output of the linear envelope generator into `output`, one value for every
+    /// sample.
+    fn render(&mut self, parameters: &EGParameters, output: &mut [f32], sample_rate: f32) {
+        for output_value in output {
             match self.state {
                 EnvelopeState::Off => {
-                    // TODO: This changes if we are in legato mode
+                    // Legato notes keep the envelope going, so it is only off between notes
                     self.output_value = parameters.start_level;
                 }
                 EnvelopeState::Attack => {
@@ -175,11 +197,8 @@ impl EnvelopeGenerator for LinearEG {
                     }
                 }
             }
-            if i == 0 {
-                output = self.output_value;
-            }
+            *output_value = self.output_value;
         }
-        output
     }
 
     /// Notifies the linear envelope generator that a note has been turned off.
@@ -195,7 +214,7 @@ impl EnvelopeGenerator for LinearEG {
     }
 
     /// Notifies the linear envelope generator that a note has been turned on.
-    fn note_on(&mut self, parameters: &EGParameters, sample_rate: f32) {
+    fn note_on(&mut self, parameters: &EGParameters, _note: u8, sample_rate: f32) {
         self.step_increase = calc_step_increase(parameters.attack_time_msec, 1.0, sample_rate);
         nih_debug_assert!(self.step_increase > 0.0);
         self.state = EnvelopeState::Attack;
@@ -203,7 +222,9 @@ impl EnvelopeGenerator for LinearEG {
     }
 
     fn shutdown(&mut self, _parameters: &EGParameters, sample_rate: f32) {
-        self.shutdown_increment = -(1000.0 * self.output_value) / SHUTDOWN_TIME_MSEC / sample_rate;
+        // A note that has not been rendered yet starts just below zero
+        let output_value = self.output_value.max(0.0);
+        self.shutdown_increment = -(1000.0 * output_value) / SHUTDOWN_TIME_MSEC / sample_rate;
         nih_debug_assert!(self.shutdown_increment <= 0.0);
         self.state = EnvelopeState::Shutdown;
     }
@@ -227,17 +248,20 @@ mod tests {
             decay_time_msec: 100.0,
             sustain_level: 0.5,
             release_time_msec: 200.0,
+            ..Default::default()
         };
-        let num_samples_to_process = 50;
+        let mut output = [0.0; 50];
 
         eg.reset(&parameters);
         eg.state = EnvelopeState::Attack;
         eg.step_increase = 0.01;
         // We should be able to render twice
-        let output = eg.render(&parameters, num_samples_to_process, 1000.0);
+        eg.render(&parameters, &mut output, 1000.0);
 
-        // Assert that the output value increases during the attack phase
-        assert!(output.eq(&0.01));
+        // Assert that the output value increases during the attack phase, one step per sample
+        assert!(output[0].eq(&0.01));
+        assert!(output.windows(2).all(|pair| pair[1] > pair[0]));
+        assert_relative_eq!(output[49], 0.5, epsilon = 1e-5);
         // THE FOLLOWING SHOULD WORK BUT IT DOESN'T BECAUSE OF FLOATING POINT ERRORS
         // assert_relative_eq!(eg.output_value, 0.5);
         // let output2 = eg.render(&parameters, num_samples_to_process);
@@ -294,7 +318,7 @@ mod tests {
         ] {
             eg.state = state;
             eg.output_value = 0.5;
-            eg.note_on(&parameters, 1000.0);
+            eg.note_on(&parameters, 60, 1000.0);
             assert_eq!(eg.state, EnvelopeState::Attack);
             assert!(eg.output_value < 0.0);
         }
@@ -0,0 +1,182 @@
+use nih_plug::prelude::Enum;
+
+/// The number of keys the mono modes keep track of. When more keys are held, the oldest one is
+/// forgotten.
+const MAX_HELD_NOTES: usize = 32;
+
+/// How the voices are assigned to the played notes.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum VoiceMode {
+    /// Every note gets a voice of its own
+    #[default]
+    #[id = "poly"]
+    #[name = "Poly"]
+    Poly,
+    /// A single voice plays the held note with the highest priority, and every note change
+    /// restarts the envelopes
+    #[id = "mono"]
+    #[name = "Mono"]
+    Mono,
+    /// Like `Mono`, but moving from one held note to another keeps the envelopes going
+    #[id = "legato"]
+    #[name = "Legato"]
+    Legato,
+}
+
+/// Which of the held notes a mono voice plays.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum NotePriority {
+    /// The last note played
+    #[default]
+    #[id = "last"]
+    #[name = "Last"]
+    Last,
+    #[id = "low"]
+    #[name = "Low"]
+    Low,
+    #[id = "high"]
+    #[name = "High"]
+    High,
+}
+
+/// When the pitch of a mono voice glides to the new note.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum GlideMode {
+    /// On every note
+    #[default]
+    #[id = "always"]
+    #[name = "Always"]
+    Always,
+    /// Only when a note is played while another one is held
+    #[id = "legato"]
+    #[name = "Legato Only"]
+    Legato,
+}
+
+/// A key that is held down.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HeldNote {
+    pub voice_id: Option<i32>,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: f32,
+}
+
+/// The keys that are held down, in the order they were played. This has a fixed size so the
+/// audio thread never allocates.
+#[derive(Debug, Clone)]
+pub struct NoteStack {
+    notes: [HeldNote; MAX_HELD_NOTES],
+    len: usize,
+}
+
+impl NoteStack {
+    pub fn new() -> Self {
+        Self {
+            notes: [HeldNote::default(); MAX_HELD_NOTES],
+            len: 0,
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Adds a key that was pressed. A key that is pressed again moves to the top.
+    pub fn push(&mut self, held_note: HeldNote) {
+        self.remove(None, held_note.channel, held_note.note);
+        if self.len == MAX_HELD_NOTES {
+            self.remove_at(0);
+        }
+        self.notes[self.len] = held_note;
+        self.len += 1;
+    }
+
+    /// Removes the key that was released, if it is held. Returns whether it was.
+    pub fn remove(&mut self, voice_id: Option<i32>, channel: u8, note: u8) -> bool {
+        let position = self.notes[..self.len].iter().position(|held_note| {
+            (voice_id.is_some() && held_note.voice_id == voice_id)
+                || (held_note.channel == channel && held_note.note == note)
+        });
+        if let Some(index) = position {
+            self.remove_at(index);
+        }
+        position.is_some()
+    }
+
+    /// The held note that a mono voice should play.
+    pub fn select(&self, priority: NotePriority) -> Option<HeldNote> {
+        let notes = self.notes[..self.len].iter();
+        match priority {
+            NotePriority::Last => notes.last(),
+            // The last one played wins between notes on different channels
+            NotePriority::Low => notes.rev().min_by_key(|held_note| held_note.note),
+            NotePriority::High => notes.rev().max_by_key(|held_note| held_note.note),
+        }
+        .copied()
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        self.notes.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(note: u8) -> HeldNote {
+        HeldNote {
+            note,
+            velocity: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_note_priority() {
+        let mut stack = NoteStack::new();
+        assert_eq!(stack.select(NotePriority::Last), None);
+        for note in [60, 48, 72, 64] {
+            stack.push(held(note));
+        }
+        assert_eq!(stack.select(NotePriority::Last), Some(held(64)));
+        assert_eq!(stack.select(NotePriority::Low), Some(held(48)));
+        assert_eq!(stack.select(NotePriority::High), Some(held(72)));
+        assert!(stack.remove(None, 0, 64));
+        assert!(!stack.remove(None, 0, 64));
+        assert_eq!(stack.select(NotePriority::Last), Some(held(72)));
+    }
+
+    #[test]
+    fn test_pressed_again_moves_to_top() {
+        let mut stack = NoteStack::new();
+        stack.push(held(60));
+        stack.push(held(62));
+        stack.push(held(60));
+        assert_eq!(stack.select(NotePriority::Last), Some(held(60)));
+        stack.remove(None, 0, 60);
+        assert!(!stack.is_empty());
+        stack.remove(None, 0, 62);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn test_full_stack_forgets_oldest() {
+        let mut stack = NoteStack::new();
+        for note in 0..=MAX_HELD_NOTES as u8 {
+            stack.push(held(note));
+        }
+        assert_eq!(stack.select(NotePriority::Low), Some(held(1)));
+        assert_eq!(
+            stack.select(NotePriority::High),
+            Some(held(MAX_HELD_NOTES as u8))
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use nih_plug::prelude::Enum;
+
+/// The CC that MPE controllers send the timbre of a note on, usually from the vertical position
+/// of the finger on the key.
+pub const TIMBRE_CC: u8 = 74;
+
+/// The MPE zone the synth listens to. In a zone, every note is played on a member channel of its
+/// own, so the pitch bend, pressure and timbre of a member channel only move that note. The
+/// master channel of the zone controls all the notes.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MpeZone {
+    /// MPE is off, and every channel controls all the notes
+    #[default]
+    #[id = "off"]
+    #[name = "Off"]
+    Off,
+    /// Channel 1 is the master channel, and the member channels count up from channel 2
+    #[id = "lower"]
+    #[name = "Lower Zone"]
+    Lower,
+    /// Channel 16 is the master channel, and the member channels count down from channel 15
+    #[id = "upper"]
+    #[name = "Upper Zone"]
+    Upper,
+}
+
+impl MpeZone {
+    /// Whether `channel`, in `0..16`, is one of the `num_member_channels` member channels of the
+    /// zone. Channels outside of the zone behave like MPE is off.
+    pub fn is_member_channel(self, channel: u8, num_member_channels: u8) -> bool {
+        let num_member_channels = num_member_channels.min(15);
+        match self {
+            Self::Off => false,
+            Self::Lower => (1..=num_member_channels).contains(&channel),
+            Self::Upper => (15 - num_member_channels..15).contains(&channel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_channels() {
+        assert!(!MpeZone::Off.is_member_channel(1, 15));
+        // The master channels are never member channels
+        assert!(!MpeZone::Lower.is_member_channel(0, 15));
+        assert!(!MpeZone::Upper.is_member_channel(15, 15));
+        assert!(MpeZone::Lower.is_member_channel(1, 15));
+        assert!(MpeZone::Lower.is_member_channel(15, 15));
+        assert!(MpeZone::Upper.is_member_channel(0, 15));
+        assert!(MpeZone::Upper.is_member_channel(14, 15));
+        // A smaller zone leaves the other channels out
+        assert!(MpeZone::Lower.is_member_channel(7, 7));
+        assert!(!MpeZone::Lower.is_member_channel(8, 7));
+        assert!(MpeZone::Upper.is_member_channel(8, 7));
+        assert!(!MpeZone::Upper.is_member_channel(7, 7));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::analog_eg::AnalogEG;
+use crate::dx_eg::{rate_scaling_time_scale, DxEG};
+use crate::linear_eg::{EGMode, EGParameters, EnvelopeGenerator, LinearEG};
+
+/// An envelope generator that can switch between the other envelope generators at runtime.
+/// The mode is taken from the parameters on every note on, so changing it never cuts a note
+/// short.
+///
+/// The DX7 envelope scales its own rates with the note. For the other modes, the times of the
+/// envelope are shortened by the same amount.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MultiModeEG {
+    mode: EGMode,
+    linear: LinearEG,
+    analog: AnalogEG,
+    dx: DxEG,
+    /// The factor of the times of the linear and analog envelopes for the current note
+    time_scale: f32,
+}
+
+impl EnvelopeGenerator for MultiModeEG {
+    fn new() -> Self {
+        Self {
+            mode: EGMode::default(),
+            linear: LinearEG::new(),
+            analog: AnalogEG::new(),
+            dx: DxEG::new(),
+            time_scale: 1.0,
+        }
+    }
+
+    fn reset(&mut self, parameters: &EGParameters) {
+        self.mode = parameters.mode;
+        self.linear.reset(parameters);
+        self.analog.reset(parameters);
+        self.dx.reset(parameters);
+    }
+
+    fn update(&mut self, parameters: &EGParameters) {
+        match self.mode {
+            EGMode::Linear => self.linear.update(&self.scaled(parameters)),
+            EGMode::Analog => self.analog.update(&self.scaled(parameters)),
+            EGMode::Dx => self.dx.update(parameters),
+        }
+    }
+
+    fn render(&mut self, parameters: &EGParameters, output: &mut [f32], sample_rate: f32) {
+        match self.mode {
+            EGMode::Linear => self
+                .linear
+                .render(&self.scaled(parameters), output, sample_rate),
+            EGMode::Analog => self
+                .analog
+                .render(&self.scaled(parameters), output, sample_rate),
+            EGMode::Dx => self.dx.render(parameters, output, sample_rate),
+        }
+    }
+
+    fn note_off(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        match self.mode {
+            EGMode::Linear => self.linear.note_off(&self.scaled(parameters), sample_rate),
+            EGMode::Analog => self.analog.note_off(&self.scaled(parameters), sample_rate),
+            EGMode::Dx => self.dx.note_off(parameters, sample_rate),
+        }
+    }
+
+    fn note_on(&mut self, parameters: &EGParameters, note: u8, sample_rate: f32) {
+        if self.mode != parameters.mode {
+            // Make sure the envelope we switch away from does not keep the voice alive
+            self.reset(parameters);
+        }
+        self.time_scale = rate_scaling_time_scale(note, parameters.rate_scaling);
+        match self.mode {
+            EGMode::Linear => self
+                .linear
+                .note_on(&self.scaled(parameters), note, sample_rate),
+            EGMode::Analog => self
+                .analog
+                .note_on(&self.scaled(parameters), note, sample_rate),
+            EGMode::Dx => self.dx.note_on(parameters, note, sample_rate),
+        }
+    }
+
+    fn shutdown(&mut self, parameters: &EGParameters, sample_rate: f32) {
+        match self.mode {
+            EGMode::Linear => self.linear.shutdown(&self.scaled(parameters), sample_rate),
+            EGMode::Analog => self.analog.shutdown(&self.scaled(parameters), sample_rate),
+            EGMode::Dx => self.dx.shutdown(parameters, sample_rate),
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        match self.mode {
+            EGMode::Linear => self.linear.is_playing(),
+            EGMode::Analog => self.analog.is_playing(),
+            EGMode::Dx => self.dx.is_playing(),
+        }
+    }
+}
+
+impl MultiModeEG {
+    /// The parameters with the times of the linear and analog envelopes scaled for the note.
+    fn scaled(&self, parameters: &EGParameters) -> EGParameters {
+        EGParameters {
+            attack_time_msec: parameters.attack_time_msec * self.time_scale,
+            decay_time_msec: parameters.decay_time_msec * self.time_scale,
+            release_time_msec: parameters.release_time_msec * self.time_scale,
+            ..*parameters
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_switches_on_note_on() {
+        let mut eg = MultiModeEG::new();
+        let linear = EGParameters::default();
+        let analog = EGParameters {
+            mode: EGMode::Analog,
+            ..Default::default()
+        };
+        eg.note_on(&linear, 60, 1000.0);
+        assert_eq!(eg.mode, EGMode::Linear);
+        assert!(eg.linear.is_playing());
+        // Changing the mode during a note does not affect the note
+        let mut output = [0.0; 10];
+        eg.render(&analog, &mut output, 1000.0);
+        assert_eq!(eg.mode, EGMode::Linear);
+        eg.note_on(&analog, 60, 1000.0);
+        assert_eq!(eg.mode, EGMode::Analog);
+        assert!(eg.analog.is_playing());
+        assert!(!eg.linear.is_playing());
+    }
+
+    #[test]
+    fn test_rate_scaling_shortens_linear_envelope() {
+        let parameters = EGParameters {
+            attack_time_msec: 100.0,
+            rate_scaling: 7,
+            ..Default::default()
+        };
+        let mut levels = [0.0; 2];
+        for (note, level) in [36, 96].into_iter().zip(&mut levels) {
+            let mut eg = MultiModeEG::new();
+            eg.note_on(&parameters, note, 1000.0);
+            let mut output = [0.0; 10];
+            eg.render(&parameters, &mut output, 1000.0);
+            *level = output[9];
+        }
+        assert!(levels[1] > levels[0]);
+    }
+}
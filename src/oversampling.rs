@@ -0,0 +1,271 @@
+use std::f64::consts::PI;
+use std::sync::LazyLock;
+
+use nih_plug::prelude::Enum;
+
+/// The number of nonzero coefficients on each side of the center of the half-band filter. The
+/// filter has `4 * HALF_BAND_SIDE_TAPS - 1` taps.
+const HALF_BAND_SIDE_TAPS: usize = 16;
+/// The delay of the half-band filter, in samples at its input rate.
+const HALF_BAND_DELAY: usize = 2 * HALF_BAND_SIDE_TAPS - 1;
+/// The beta of the Kaiser window of the half-band filter. It attenuates the stopband by about
+/// 80 dB.
+const KAISER_BETA: f64 = 8.0;
+/// The number of half-band stages of the highest oversampling factor.
+pub const MAX_OVERSAMPLING_STAGES: usize = 3;
+/// The highest oversampling factor.
+pub const MAX_OVERSAMPLING: usize = 1 << MAX_OVERSAMPLING_STAGES;
+
+/// How many times faster than the host the voices run. The sidebands of bright FM sounds that
+/// reach above the Nyquist frequency of the host are filtered out instead of folding back down.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Oversampling {
+    #[default]
+    #[id = "1x"]
+    #[name = "1x"]
+    X1,
+    #[id = "2x"]
+    #[name = "2x"]
+    X2,
+    #[id = "4x"]
+    #[name = "4x"]
+    X4,
+    #[id = "8x"]
+    #[name = "8x"]
+    X8,
+}
+
+impl Oversampling {
+    /// The number of half-band stages that bring the voices back to the rate of the host.
+    pub const fn num_stages(self) -> usize {
+        match self {
+            Self::X1 => 0,
+            Self::X2 => 1,
+            Self::X4 => 2,
+            Self::X8 => MAX_OVERSAMPLING_STAGES,
+        }
+    }
+
+    /// The factor of the sample rate of the voices.
+    pub const fn factor(self) -> usize {
+        1 << self.num_stages()
+    }
+
+    /// The delay of the decimation filters in samples at the rate of the host, rounded to the
+    /// nearest sample. This is the latency reported to the host.
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn latency_samples(self) -> u32 {
+        // Every stage delays by `HALF_BAND_DELAY` samples at its input rate, so the last stage
+        // counts most. The sum is kept in units of the fastest rate to stay exact.
+        let factor = self.factor();
+        let mut delay = 0;
+        let mut stage_factor = factor;
+        while stage_factor > 1 {
+            delay += HALF_BAND_DELAY * (factor / stage_factor);
+            stage_factor /= 2;
+        }
+        ((delay + factor / 2) / factor) as u32
+    }
+}
+
+/// The coefficients of the odd taps of the half-band filter, from the center outwards. The
+/// even taps are all zero except for the center, which is 0.5.
+static HALF_BAND_COEFFICIENTS: LazyLock<[f32; HALF_BAND_SIDE_TAPS]> = LazyLock::new(|| {
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+    std::array::from_fn(|k| {
+        let offset = (2 * k + 1) as f64;
+        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+        let sinc = sign / (PI * offset);
+        let position = offset / HALF_BAND_DELAY as f64;
+        let window = bessel_i0(KAISER_BETA * position.mul_add(-position, 1.0).sqrt())
+            / bessel_i0(KAISER_BETA);
+        (sinc * window) as f32
+    })
+});
+
+/// The modified Bessel function of the first kind of order 0, for the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    // The terms fall off quickly for the small arguments of the window
+    for k in 1..32 {
+        let factor = x / (2.0 * f64::from(k));
+        term *= factor * factor;
+        sum += term;
+    }
+    sum
+}
+
+/// Halves the sample rate of a signal with a half-band lowpass filter. The filter is split into
+/// its two polyphase branches: the even input samples only meet the center tap, which is a plain
+/// delay, and the odd ones meet the symmetric taps.
+#[derive(Debug, Clone)]
+pub struct HalfBandDecimator {
+    /// The last even input samples, newest first
+    even: [f32; HALF_BAND_SIDE_TAPS],
+    /// The last odd input samples, newest first
+    odd: [f32; 2 * HALF_BAND_SIDE_TAPS],
+}
+
+impl HalfBandDecimator {
+    pub const fn new() -> Self {
+        Self {
+            even: [0.0; HALF_BAND_SIDE_TAPS],
+            odd: [0.0; 2 * HALF_BAND_SIDE_TAPS],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.even.fill(0.0);
+        self.odd.fill(0.0);
+    }
+
+    /// Takes the next two input samples and returns one output sample.
+    pub fn process(&mut self, even: f32, odd: f32) -> f32 {
+        self.even.copy_within(..HALF_BAND_SIDE_TAPS - 1, 1);
+        self.even[0] = even;
+        self.odd.copy_within(..2 * HALF_BAND_SIDE_TAPS - 1, 1);
+        self.odd[0] = odd;
+        let (newer, older) = self.odd.split_at(HALF_BAND_SIDE_TAPS);
+        HALF_BAND_COEFFICIENTS
+            .iter()
+            .zip(newer.iter().rev().zip(older))
+            .fold(
+                0.5 * self.even[HALF_BAND_SIDE_TAPS - 1],
+                |sum, (coefficient, (newer, older))| coefficient.mul_add(newer + older, sum),
+            )
+    }
+
+    /// Decimates the samples in place. The first half of `samples` holds the output.
+    pub fn process_in_place(&mut self, samples: &mut [f32]) {
+        for index in 0..samples.len() / 2 {
+            samples[index] = self.process(samples[2 * index], samples[2 * index + 1]);
+        }
+    }
+}
+
+/// Brings the oversampled output of the voices back to the rate of the host, with a cascade of
+/// half-band filters for every channel.
+#[derive(Debug, Clone)]
+pub struct Decimator {
+    oversampling: Oversampling,
+    stages: Vec<[HalfBandDecimator; MAX_OVERSAMPLING_STAGES]>,
+}
+
+impl Decimator {
+    pub fn new() -> Self {
+        Self {
+            oversampling: Oversampling::default(),
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn initialize(&mut self, num_channels: usize) {
+        self.stages = vec![std::array::from_fn(|_| HalfBandDecimator::new()); num_channels];
+    }
+
+    pub fn reset(&mut self) {
+        for stage in self.stages.iter_mut().flatten() {
+            stage.reset();
+        }
+    }
+
+    pub const fn oversampling(&self) -> Oversampling {
+        self.oversampling
+    }
+
+    /// Switches the oversampling factor. The filters start over.
+    pub fn set_oversampling(&mut self, oversampling: Oversampling) {
+        self.oversampling = oversampling;
+        self.reset();
+    }
+
+    /// Decimates one oversampled channel in place. The first `samples.len() / factor` samples
+    /// hold the output.
+    pub fn process(&mut self, channel: usize, samples: &mut [f32]) {
+        let mut len = samples.len();
+        for stage in &mut self.stages[channel][..self.oversampling.num_stages()] {
+            stage.process_in_place(&mut samples[..len]);
+            len /= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rstest::rstest;
+
+    /// The amplitude of the output of a decimator for a sine at `frequency`, relative to the
+    /// input rate, after the filter has settled.
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+    fn decimated_amplitude(frequency: f64) -> f32 {
+        let mut decimator = HalfBandDecimator::new();
+        let mut samples: Vec<f32> = (0..4096)
+            .map(|i| (std::f64::consts::TAU * frequency * f64::from(i)).sin() as f32)
+            .collect();
+        decimator.process_in_place(&mut samples);
+        let settled = &samples[HALF_BAND_DELAY..2048];
+        let power =
+            settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32;
+        (2.0 * power).sqrt()
+    }
+
+    #[test]
+    fn test_dc_passes() {
+        let mut decimator = HalfBandDecimator::new();
+        let mut output = 0.0;
+        for _ in 0..HALF_BAND_DELAY {
+            output = decimator.process(1.0, 1.0);
+        }
+        assert_relative_eq!(output, 1.0, epsilon = 1e-4);
+    }
+
+    #[rstest]
+    #[case(0.02)]
+    #[case(0.1)]
+    #[case(0.18)]
+    fn test_passband(#[case] frequency: f64) {
+        assert_relative_eq!(decimated_amplitude(frequency), 1.0, epsilon = 0.01);
+    }
+
+    #[rstest]
+    #[case(0.32)]
+    #[case(0.4)]
+    #[case(0.48)]
+    fn test_stopband(#[case] frequency: f64) {
+        // Anything above the new Nyquist frequency would alias
+        let amplitude = decimated_amplitude(frequency);
+        assert!(20.0 * amplitude.log10() < -80.0, "{frequency}: {amplitude}");
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(Oversampling::X1.latency_samples(), 0);
+        // 15.5, 23.25 and 27.125 samples
+        assert_eq!(Oversampling::X2.latency_samples(), 16);
+        assert_eq!(Oversampling::X4.latency_samples(), 23);
+        assert_eq!(Oversampling::X8.latency_samples(), 27);
+        assert_eq!(Oversampling::X8.factor(), 8);
+    }
+
+    #[test]
+    fn test_cascade_delays_impulse_by_latency() {
+        for oversampling in [Oversampling::X2, Oversampling::X4, Oversampling::X8] {
+            let mut decimator = Decimator::new();
+            decimator.initialize(1);
+            decimator.set_oversampling(oversampling);
+            let mut samples = vec![0.0; 64 * oversampling.factor()];
+            samples[0] = 1.0;
+            decimator.process(0, &mut samples);
+            let peak = samples[..64]
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index);
+            let latency = oversampling.latency_samples() as usize;
+            assert!(peak.is_some_and(|peak| peak.abs_diff(latency) <= 1));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use nih_plug::prelude::Enum;
+
+/// The gains of the left and right channels for `pan`, from -1 (left) to 1 (right). The pan law
+/// is constant power, so a sound is as loud wherever it is placed, and both channels are at unity
+/// gain in the center.
+pub fn constant_power_gains(pan: f32) -> [f32; 2] {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    [
+        angle.cos() * std::f32::consts::SQRT_2,
+        angle.sin() * std::f32::consts::SQRT_2,
+    ]
+}
+
+/// How the voices are placed in the stereo field.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum VoicePanMode {
+    /// Every voice is in the center
+    #[default]
+    #[id = "center"]
+    #[name = "Center"]
+    Center,
+    /// Low notes are on the left and high notes on the right, like on a piano
+    #[id = "key"]
+    #[name = "By Key"]
+    Key,
+    /// The notes are placed left and right in turn
+    #[id = "alternating"]
+    #[name = "Alternating"]
+    Alternating,
+}
+
+impl VoicePanMode {
+    /// The pan of a voice that plays `note`, with a `width` from 0 to 1. `is_right` says which
+    /// side the alternating mode places the voice on.
+    pub fn pan(self, note: u8, is_right: bool, width: f32) -> f32 {
+        let position = match self {
+            Self::Center => 0.0,
+            // C-1 to G9, around the center of the keyboard
+            Self::Key => (f32::from(note) - 63.5) / 63.5,
+            Self::Alternating => {
+                if is_right {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        };
+        position * width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_pan_is_constant_power() {
+        let [left, right] = constant_power_gains(0.0);
+        assert_relative_eq!(left, 1.0);
+        assert_relative_eq!(right, 1.0);
+        for pan in [-1.0, -0.3, 0.7, 1.0] {
+            let [left, right] = constant_power_gains(pan);
+            assert_relative_eq!(left.mul_add(left, right * right), 2.0, epsilon = 1e-6);
+        }
+        let [left, right] = constant_power_gains(-1.0);
+        assert_relative_eq!(right, 0.0, epsilon = 1e-6);
+        assert!(left > 1.0);
+    }
+
+    #[test]
+    fn test_voice_pan_modes() {
+        assert_relative_eq!(VoicePanMode::Center.pan(0, true, 1.0), 0.0);
+        assert_relative_eq!(VoicePanMode::Key.pan(0, false, 1.0), -1.0);
+        assert_relative_eq!(VoicePanMode::Key.pan(127, false, 0.5), 0.5);
+        assert!(VoicePanMode::Key.pan(60, false, 1.0) < 0.0);
+        assert_relative_eq!(VoicePanMode::Alternating.pan(60, false, 0.5), -0.5);
+        assert_relative_eq!(VoicePanMode::Alternating.pan(60, true, 0.5), 0.5);
+    }
+}
@@ -1,59 +1,109 @@
+use std::sync::Arc;
+
 use nih_plug::nih_log;
 
+use crate::filter::VoiceFilter;
 use crate::fm_core::FmCore;
 // A voice should contain an oscillator, an envelope, and a filter
 // The voice should handle note on and note off events. It needs a render function,
 // an initialize function, and an reset function.
 use crate::linear_eg::EnvelopeGenerator;
-use crate::linear_eg::LinearEG;
-use crate::voice_utils::{MidiEvent, Parameters, Voice};
+use crate::mono::HeldNote;
+use crate::multi_mode_eg::MultiModeEG;
+use crate::pan::constant_power_gains;
+use crate::voice_utils::{
+    MidiEvent, NoteExpression, Parameters, PolyModulationTarget, UnisonVoice, Voice, VoiceNote,
+};
+use crate::wavetable::Wavetables;
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct SinVoice {
     core: FmCore,
-    eg: LinearEG,
-    // TODO: Add a filter
+    eg: MultiModeEG,
+    filter: VoiceFilter,
     _id: Option<i32>,
     // TODO: decide if there should be some other way to handle the output
     is_stealing: bool,
     current_midi_event: Option<MidiEvent>,
     next_midi_event: Option<MidiEvent>,
+    /// The note the voice plays, which stays after the note off until the release is done
+    played_note: Option<VoiceNote>,
+    /// The pitch bend of all the voices, in semitones
+    pitch_bend: f32,
+    /// The detune, pan and gain of the voice among the voices of its note
+    unison: UnisonVoice,
+    /// The unison of the note that waits for a steal to finish
+    next_unison: UnisonVoice,
     output_buffer: Vec<Vec<f32>>, // 2D output buffer for stereo
-                                  // TODO: Add gain
-                                  // gain: Smoother<f32>,
+    eg_buffer: Vec<f32>,
+    // TODO: Add gain
+    // gain: Smoother<f32>,
 }
 
 impl Voice for SinVoice {
     fn new() -> Self {
         Self {
             core: FmCore::new(),
-            eg: LinearEG::new(),
+            eg: MultiModeEG::new(),
+            filter: VoiceFilter::new(),
             _id: None,
             is_stealing: false,
             current_midi_event: None,
             next_midi_event: None,
+            played_note: None,
+            pitch_bend: 0.0,
+            unison: UnisonVoice::default(),
+            next_unison: UnisonVoice::default(),
             output_buffer: vec![vec![0.0; 1]; 2],
+            eg_buffer: vec![0.0; 1],
             // gain: Smoother::new(SmoothingStyle::Linear(1.0)),
         }
     }
     fn initialize(&mut self, num_channels: usize, max_samples_per_channel: usize) {
         self.output_buffer = vec![vec![0.0; max_samples_per_channel]; num_channels];
+        self.eg_buffer = vec![0.0; max_samples_per_channel];
+        self.filter.initialize(max_samples_per_channel);
     }
 
-    fn render(&mut self, num_samples_to_process: usize, params: &Parameters, sample_rate: f32) {
-        // get the length of the audio buffer
-        let eg_value = self
-            .eg
-            .render(&params.eg_params, num_samples_to_process, sample_rate);
+    fn render(
+        &mut self,
+        num_samples_to_process: usize,
+        params: &Parameters,
+        sample_rate: f32,
+    ) -> Option<VoiceNote> {
+        self.eg.render(
+            &params.eg_params,
+            &mut self.eg_buffer[..num_samples_to_process],
+            sample_rate,
+        );
 
+        self.core
+            .set_quality(params.interpolation, params.table_size);
+        let pan_gains = if self.output_buffer.len() == 2 {
+            constant_power_gains(self.unison.pan)
+        } else {
+            [1.0; 2]
+        };
         // add the core output to the audio_buffer
         for sample_index in 0..num_samples_to_process {
-            let core_output = self.core.render(sample_rate);
+            let core_output =
+                self.core.render(sample_rate) * self.eg_buffer[sample_index] * self.unison.gain;
             // add the core output to the different channels
-            for channel in &mut self.output_buffer {
-                channel[sample_index] = core_output * eg_value;
+            for (channel, pan_gain) in self.output_buffer.iter_mut().zip(pan_gains) {
+                channel[sample_index] = core_output * pan_gain;
             }
         }
+        self.filter.process(
+            &mut self.output_buffer,
+            num_samples_to_process,
+            &params.filter,
+            sample_rate,
+        );
+        let ended_note = if self.eg.is_playing() {
+            None
+        } else {
+            self.played_note.take()
+        };
         // Check the stealPending flag to see if the voice is being stolen, and if so:
         if self.is_stealing && !self.eg.is_playing() {
             // Call the voice’s note-off handler – this was never called because the event was stolen
@@ -72,6 +122,7 @@ impl Voice for SinVoice {
             // Note: it is possible we received the `note_off` event for the `next_midi_event` during the steal
             // operation and so there is nothing to be done here.
             self.current_midi_event = self.next_midi_event.take();
+            self.set_unison(self.next_unison);
 
             if let Some(midi_event) = &self.current_midi_event {
                 self.note_on(
@@ -84,10 +135,13 @@ impl Voice for SinVoice {
                 );
             }
         }
+        ended_note
     }
     fn reset(&mut self, params: &Parameters) {
         self.core.reset();
         self.eg.reset(&params.eg_params);
+        self.filter.reset(&params.filter);
+        self.played_note = None;
     }
     /// This function is called when a note on event is received. There should never be two calls to ``note_on`` without a
     /// call to render in between.
@@ -120,10 +174,66 @@ impl Voice for SinVoice {
                 note,
                 velocity,
             });
+            self.played_note = Some(VoiceNote {
+                voice_id,
+                channel,
+                note,
+            });
             self.core
                 .note_on(note, velocity, sample_rate, voice_id, channel);
-            self.eg.note_on(&params.eg_params, sample_rate);
+            self.eg.note_on(&params.eg_params, note, sample_rate);
+            self.filter.note_on(&params.filter, note, sample_rate);
+        }
+    }
+    fn change_note(
+        &mut self,
+        held_note: HeldNote,
+        legato: bool,
+        glide_time_msec: f32,
+        params: &Parameters,
+        sample_rate: f32,
+    ) {
+        if self.is_stealing {
+            self.note_on(
+                held_note.note,
+                held_note.velocity,
+                held_note.voice_id,
+                held_note.channel,
+                params,
+                sample_rate,
+            );
+            return;
         }
+        let pitch = self.core.pitch();
+        if legato {
+            self.core.change_note(held_note.note);
+            self.filter.change_note(held_note.note);
+        } else {
+            self.core.note_on(
+                held_note.note,
+                held_note.velocity,
+                sample_rate,
+                held_note.voice_id,
+                held_note.channel,
+            );
+            self.eg
+                .note_on(&params.eg_params, held_note.note, sample_rate);
+            self.filter
+                .note_on(&params.filter, held_note.note, sample_rate);
+        }
+        self.core.glide(pitch, glide_time_msec, sample_rate);
+        self.current_midi_event = Some(MidiEvent {
+            timing: 0,
+            voice_id: held_note.voice_id,
+            channel: held_note.channel,
+            note: held_note.note,
+            velocity: held_note.velocity,
+        });
+        self.played_note = Some(VoiceNote {
+            voice_id: held_note.voice_id,
+            channel: held_note.channel,
+            note: held_note.note,
+        });
     }
     /// This function is called when a note off event is received.
     /// There are a few cases to consider:
@@ -154,6 +264,7 @@ impl Voice for SinVoice {
             {
                 nih_log!("Note off matches current midi event");
                 self.eg.note_off(&params.eg_params, sample_rate);
+                self.filter.note_off(&params.filter, sample_rate);
                 self.core.note_off();
                 self.current_midi_event = None;
             }
@@ -163,6 +274,79 @@ impl Voice for SinVoice {
         self.eg.is_playing()
     }
 
+    fn note(&self) -> Option<VoiceNote> {
+        self.played_note
+    }
+
+    /// A note waiting for a steal to finish is dropped, and the stolen note is already on its
+    /// way out.
+    fn release(&mut self, params: &Parameters, sample_rate: f32) {
+        self.next_midi_event = None;
+        if self.is_stealing {
+            return;
+        }
+        if let Some(midi_event) = &self.current_midi_event {
+            self.note_off(
+                midi_event.voice_id,
+                midi_event.channel,
+                midi_event.note,
+                params,
+                sample_rate,
+            );
+        }
+    }
+
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        self.core.pitch_bend = self.pitch_bend + self.unison.detune / 100.0;
+    }
+
+    // The sine voice always plays a sine, but it shares the bank like the other voices
+    fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        self.core.set_wavetables(wavetables);
+    }
+
+    /// While the voice is being stolen, the stolen note keeps its place and the unison waits for
+    /// the new note.
+    fn set_unison(&mut self, unison_voice: UnisonVoice) {
+        if self.is_stealing {
+            self.next_unison = unison_voice;
+            return;
+        }
+        self.unison = unison_voice;
+        self.set_pitch_bend(self.pitch_bend);
+    }
+
+    // The sine voice has no parameters that can be modulated per voice
+    fn set_poly_modulation(
+        &mut self,
+        _voice_id: i32,
+        _target: PolyModulationTarget,
+        _normalized_offset: f32,
+        _value: f32,
+        _sample_rate: f32,
+    ) {
+    }
+
+    fn update_poly_modulation(
+        &mut self,
+        _target: PolyModulationTarget,
+        _value: &dyn Fn(f32) -> f32,
+        _sample_rate: f32,
+    ) {
+    }
+
+    fn set_channel_expression(&mut self, _channel: u8, _expression: NoteExpression) {}
+
+    fn set_note_expression(
+        &mut self,
+        _voice_id: Option<i32>,
+        _channel: u8,
+        _note: u8,
+        _expression: NoteExpression,
+    ) {
+    }
+
     fn accumulate_output(
         &mut self,
         audio_buffer: &mut [&mut [f32]],
@@ -199,6 +383,7 @@ mod tests {
                 release_time_msec: 10.0,
                 start_level: 0.0,
                 sustain_level: 0.1,
+                ..Default::default()
             },
             ..Default::default()
         }
@@ -301,6 +486,7 @@ mod tests {
                 release_time_msec: 10.0,
                 start_level: 0.0,
                 sustain_level: 0.1,
+                ..Default::default()
             },
             ..Default::default()
         };
@@ -317,7 +503,9 @@ mod tests {
 
         voice.render(audio_buffer.len(), &params, SAMPLE_RATE);
         assert!(!voice.eg.is_playing());
-        assert_relative_eq!(voice.eg.render(&params.eg_params, 1, SAMPLE_RATE), 0.0);
+        let eg_output = &mut [1.0; 1];
+        voice.eg.render(&params.eg_params, eg_output, SAMPLE_RATE);
+        assert_relative_eq!(eg_output[0], 0.0);
         let audio_buffer = &mut [0.0; NUM_SAMPLES_TO_PROCESS];
         voice.render(audio_buffer.len(), &params, SAMPLE_RATE);
         // assert that the audio buffer is zero
@@ -339,6 +527,7 @@ mod tests {
                 release_time_msec: 10.0,
                 start_level: 0.0,
                 sustain_level: 0.1,
+                ..Default::default()
             },
             ..Default::default()
         };
@@ -386,4 +575,67 @@ mod tests {
         assert!(voice.is_stealing);
         assert!(voice.next_midi_event.is_none());
     }
+
+    #[rstest]
+    fn test_change_note_does_not_steal(mut voice: SinVoice, params: Parameters) {
+        const SAMPLE_RATE: f32 = 1000.0;
+        let held_note = |note| HeldNote {
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.5,
+        };
+        voice.initialize(2, 10);
+        voice.note_on(60, 0.5, None, 0, &params, SAMPLE_RATE);
+        voice.render(5, &params, SAMPLE_RATE);
+        // Legato keeps the phase of the clock, and the glide starts from the previous note
+        voice.change_note(held_note(64), true, 10.0, &params, SAMPLE_RATE);
+        assert!(!voice.is_stealing);
+        assert!(voice.core.clock.mcounter > 0.0);
+        assert_relative_eq!(voice.core.pitch(), 60.0);
+        assert_eq!(
+            voice.current_midi_event.as_ref().map(|event| event.note),
+            Some(64)
+        );
+        // Without legato the note starts over, still without a steal
+        voice.render(5, &params, SAMPLE_RATE);
+        voice.change_note(held_note(67), false, 0.0, &params, SAMPLE_RATE);
+        assert!(!voice.is_stealing);
+        assert_relative_eq!(voice.core.clock.mcounter, 0.0);
+        assert_relative_eq!(voice.core.pitch(), 67.0);
+    }
+
+    #[rstest]
+    fn test_unison_detunes_and_scales_the_voice(params: Parameters) {
+        const SAMPLE_RATE: f32 = 44100.0;
+        let render = |unison: UnisonVoice| {
+            let mut voice = SinVoice::new();
+            voice.initialize(2, 64);
+            voice.note_on(60, 0.5, None, 0, &params, SAMPLE_RATE);
+            voice.set_pitch_bend(1.0);
+            voice.set_unison(unison);
+            voice.render(64, &params, SAMPLE_RATE);
+            (voice.core.pitch_bend, voice.output_buffer)
+        };
+        let (pitch_bend, single) = render(UnisonVoice::default());
+        assert_relative_eq!(pitch_bend, 1.0);
+        // The detune adds to the pitch bend in cents, and the gain scales the output
+        let (pitch_bend, detuned) = render(UnisonVoice {
+            detune: 50.0,
+            pan: 0.0,
+            gain: 0.5,
+        });
+        assert_relative_eq!(pitch_bend, 1.5);
+        let (_, scaled) = render(UnisonVoice {
+            gain: 0.5,
+            ..Default::default()
+        });
+        for (single, scaled) in single[0].iter().zip(&scaled[0]) {
+            assert_relative_eq!(*scaled, single * 0.5);
+        }
+        assert!(single[0]
+            .iter()
+            .zip(&detuned[0])
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+    }
 }
@@ -0,0 +1,235 @@
+use nih_plug::prelude::SysExMessage;
+
+use crate::dx7_sysex::{self, Dx7SysExMessage};
+use crate::sysex_dump::SysExDump;
+use crate::wavetable::NUM_USER_WAVETABLES;
+
+/// The most samples a user wavetable dump can hold
+pub const MAX_DUMP_SAMPLES: usize = 2048;
+/// The start of a user wavetable dump: system exclusive, the non-commercial manufacturer ID,
+/// "FM", format 1. The slot and the number of samples follow.
+const WAVETABLE_HEADER: [u8; 5] = [0xF0, 0x7D, 0x46, 0x4D, 0x01];
+/// The size of a user wavetable dump without its samples, including the header, the slot, the
+/// number of samples, the checksum and the end byte
+const WAVETABLE_DUMP_OVERHEAD: usize = WAVETABLE_HEADER.len() + 5;
+/// The size of the largest message the synth understands
+const MAX_MESSAGE_SIZE: usize = {
+    let max_wavetable_dump = WAVETABLE_DUMP_OVERHEAD + 2 * MAX_DUMP_SAMPLES;
+    if max_wavetable_dump > dx7_sysex::BANK_SIZE {
+        max_wavetable_dump
+    } else {
+        dx7_sysex::BANK_SIZE
+    }
+};
+/// The 14 bit value of a sample of 0. The samples are sent as offset binary.
+const SAMPLE_OFFSET: u16 = 0x2000;
+
+/// The system exclusive messages the synth understands: the DX7's and the dumps of the user
+/// wavetables.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SysEx {
+    Dx7(Dx7SysExMessage),
+    UserWavetable(UserWavetableDump),
+}
+
+/// A single cycle for one of the user waveforms:
+///
+/// `F0 7D 46 4D 01 ss nh nl <samples> cs F7`
+///
+/// `ss` is the slot (0-3), `nh nl` the number of samples (up to `MAX_DUMP_SAMPLES`) in two 7 bit
+/// bytes, and every sample is a 14 bit offset binary value in two 7 bit bytes, high byte first.
+/// The checksum is the DX7's, over the samples. A dump without samples clears the slot, which
+/// then plays a sine again.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UserWavetableDump {
+    pub slot: usize,
+    /// The samples as they are sent
+    data: SysExDump,
+}
+
+impl UserWavetableDump {
+    /// A dump of `samples` in `[-1, 1]` for `slot`. Samples beyond `MAX_DUMP_SAMPLES` are cut
+    /// off.
+    #[cfg(test)]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn new(slot: usize, samples: &[f32]) -> Self {
+        let data: Vec<u8> = samples
+            .iter()
+            .take(MAX_DUMP_SAMPLES)
+            .flat_map(|sample| {
+                let value = sample
+                    .clamp(-1.0, 1.0)
+                    .mul_add(f32::from(SAMPLE_OFFSET), f32::from(SAMPLE_OFFSET))
+                    .round()
+                    .min(f32::from(2 * SAMPLE_OFFSET - 1)) as u16;
+                [(value >> 7) as u8, value as u8 & 0x7F]
+            })
+            .collect();
+        Self {
+            slot,
+            data: SysExDump::store(&data).expect("the dump was not stored"),
+        }
+    }
+
+    /// Adds the samples of the cycle, from -1 to just below 1, to `samples`. Returns `false` when
+    /// the dump has been replaced by later dumps.
+    pub fn read_samples(self, samples: &mut impl Extend<f32>) -> bool {
+        self.data
+            .read(|data| {
+                samples.extend(data.chunks_exact(2).map(|bytes| {
+                    let value = (u16::from(bytes[0] & 0x7F) << 7) | u16::from(bytes[1] & 0x7F);
+                    (f32::from(value) - f32::from(SAMPLE_OFFSET)) / f32::from(SAMPLE_OFFSET)
+                }));
+            })
+            .is_some()
+    }
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        let (header, rest) = buffer.split_at_checked(WAVETABLE_HEADER.len())?;
+        let (&[slot, len_high, len_low], rest) = rest.split_first_chunk()?;
+        let (data, &[checksum, 0xF7]) = rest.split_last_chunk()? else {
+            return None;
+        };
+        let slot = usize::from(slot);
+        let len = (usize::from(len_high) << 7) | usize::from(len_low);
+        if header != WAVETABLE_HEADER
+            || slot >= NUM_USER_WAVETABLES
+            || len > MAX_DUMP_SAMPLES
+            || data.len() != 2 * len
+            || dx7_sysex::checksum(data) != checksum
+        {
+            return None;
+        }
+        let data = SysExDump::store(data)?;
+        Some(Self { slot, data })
+    }
+
+    /// Writes the dump into `buffer`, and returns its size. A dump that has been replaced is not
+    /// written.
+    #[allow(clippy::cast_possible_truncation)]
+    fn to_buffer(self, buffer: &mut [u8]) -> usize {
+        let data_start = WAVETABLE_HEADER.len() + 3;
+        self.data
+            .read(|data| {
+                let len = data.len() / 2;
+                let data_end = data_start + data.len();
+                buffer[..WAVETABLE_HEADER.len()].copy_from_slice(&WAVETABLE_HEADER);
+                buffer[WAVETABLE_HEADER.len()..data_start].copy_from_slice(&[
+                    self.slot as u8 & 0x7F,
+                    (len >> 7) as u8 & 0x7F,
+                    len as u8 & 0x7F,
+                ]);
+                buffer[data_start..data_end].copy_from_slice(data);
+                buffer[data_end] = dx7_sysex::checksum(data);
+                buffer[data_end + 1] = 0xF7;
+                data_end + 2
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl SysExMessage for SysEx {
+    type Buffer = [u8; MAX_MESSAGE_SIZE];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        if buffer.starts_with(&WAVETABLE_HEADER[..2]) {
+            UserWavetableDump::from_buffer(buffer).map(Self::UserWavetable)
+        } else {
+            Dx7SysExMessage::from_buffer(buffer).map(Self::Dx7)
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = match self {
+            Self::Dx7(message) => {
+                let (dx7_buffer, len) = message.to_buffer();
+                buffer[..len].copy_from_slice(&dx7_buffer[..len]);
+                len
+            }
+            Self::UserWavetable(dump) => dump.to_buffer(&mut buffer),
+        };
+        (buffer, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nih_plug::prelude::PluginNoteEvent;
+
+    #[test]
+    fn test_user_wavetable_dump() {
+        let buffer = [
+            0xF0, 0x7D, 0x46, 0x4D, 0x01, 0x02, 0x00, 0x04, 0x40, 0x00, 0x60, 0x00, 0x40, 0x00,
+            0x00, 0x00, 0x20, 0xF7,
+        ];
+        let Some(SysEx::UserWavetable(dump)) = SysEx::from_buffer(&buffer) else {
+            panic!("the dump was not read");
+        };
+        assert_eq!(dump.slot, 2);
+        let mut samples = Vec::new();
+        assert!(dump.read_samples(&mut samples));
+        assert_eq!(samples.len(), 4);
+        for (sample, expected) in samples.into_iter().zip([0.0, 0.5, 0.0, -1.0]) {
+            assert_relative_eq!(sample, expected);
+        }
+        let (written, len) = SysEx::UserWavetable(dump).to_buffer();
+        assert_eq!(written[..len], buffer);
+    }
+
+    #[test]
+    fn test_invalid_user_wavetable_dumps() {
+        let (buffer, len) =
+            SysEx::UserWavetable(UserWavetableDump::new(0, &[0.5, -0.5])).to_buffer();
+        assert!(SysEx::from_buffer(&buffer[..len]).is_some());
+        // The wrong checksum
+        let mut wrong_checksum = buffer;
+        wrong_checksum[len - 2] ^= 0x01;
+        assert_eq!(SysEx::from_buffer(&wrong_checksum[..len]), None);
+        // A slot without a user waveform
+        let mut wrong_slot = buffer;
+        wrong_slot[5] = 4;
+        assert_eq!(SysEx::from_buffer(&wrong_slot[..len]), None);
+        // A sample missing
+        let mut short = buffer;
+        short[len - 4..len - 2].copy_from_slice(&buffer[len - 2..len]);
+        assert_eq!(SysEx::from_buffer(&short[..len - 2]), None);
+        // A truncated message
+        assert_eq!(SysEx::from_buffer(&buffer[..6]), None);
+    }
+
+    #[test]
+    fn test_user_wavetable_dump_round_trip() {
+        let samples = [0.0, 0.25, 1.0, -0.75, -1.0];
+        let dump = UserWavetableDump::new(3, &samples);
+        let (buffer, len) = SysEx::UserWavetable(dump).to_buffer();
+        let Some(SysEx::UserWavetable(read)) = SysEx::from_buffer(&buffer[..len]) else {
+            panic!("the dump was not read");
+        };
+        assert_eq!(read.slot, 3);
+        let mut read_samples = Vec::new();
+        assert!(read.read_samples(&mut read_samples));
+        assert_eq!(read_samples.len(), samples.len());
+        for (sample, expected) in read_samples.into_iter().zip(samples) {
+            assert_relative_eq!(sample, expected, epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_note_events_are_small() {
+        // The dumps would make every note event of the plugin as large as themselves
+        assert!(size_of::<PluginNoteEvent<crate::FmSynth>>() <= 32);
+    }
+
+    #[test]
+    fn test_dx7_messages_are_wrapped() {
+        let buffer = [0xF0, 0x43, 0x10, 0x00, 0x10, 0x05, 0xF7];
+        let Some(message @ SysEx::Dx7(_)) = SysEx::from_buffer(&buffer) else {
+            panic!("the parameter change was not read");
+        };
+        let (written, len) = message.to_buffer();
+        assert_eq!(written[..len], buffer);
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+/// The size of the largest dump that can be stored, the voice data of a DX7 bank
+pub const MAX_DUMP_SIZE: usize = 4096;
+/// The number of dumps that are kept at once. A dump is read in the block it arrives in, so this
+/// only needs to hold the dumps of one block.
+const NUM_SLOTS: usize = 16;
+
+struct Slot {
+    ticket: u32,
+    len: usize,
+    data: [u8; MAX_DUMP_SIZE],
+}
+
+/// The storage for the dumps, allocated once so that no memory is allocated on the audio thread
+static SLOTS: [Mutex<Slot>; NUM_SLOTS] = [const {
+    Mutex::new(Slot {
+        ticket: u32::MAX,
+        len: 0,
+        data: [0; MAX_DUMP_SIZE],
+    })
+}; NUM_SLOTS];
+static NEXT_TICKET: AtomicU32 = AtomicU32::new(0);
+
+/// The data of a system exclusive dump. Every note event is as large as the largest system
+/// exclusive message, so the data is kept in preallocated storage and the message only carries
+/// this handle to it. The storage is reused, so the data has to be read in the block the message
+/// arrives in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SysExDump {
+    ticket: u32,
+}
+
+impl SysExDump {
+    /// Stores `data`. Returns `None` when it is larger than `MAX_DUMP_SIZE`, or when its slot is
+    /// being read right now.
+    pub fn store(data: &[u8]) -> Option<Self> {
+        if data.len() > MAX_DUMP_SIZE {
+            return None;
+        }
+        let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+        let mut slot = SLOTS[ticket as usize % NUM_SLOTS].try_lock().ok()?;
+        slot.ticket = ticket;
+        slot.len = data.len();
+        slot.data[..data.len()].copy_from_slice(data);
+        drop(slot);
+        Some(Self { ticket })
+    }
+
+    /// Calls `f` with the data of the dump. Returns `None` when the data has been replaced by
+    /// later dumps, or when its slot is being written right now.
+    pub fn read<T>(self, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let slot = SLOTS[self.ticket as usize % NUM_SLOTS].try_lock().ok()?;
+        (slot.ticket == self.ticket).then(|| f(&slot.data[..slot.len]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_is_read_back() {
+        let data: Vec<u8> = (0..=127).cycle().take(MAX_DUMP_SIZE).collect();
+        let dump = SysExDump::store(&data).expect("the dump was not stored");
+        assert_eq!(dump.read(<[u8]>::to_vec), Some(data));
+        assert_eq!(SysExDump::store(&[0; MAX_DUMP_SIZE + 1]), None);
+    }
+
+    #[test]
+    fn test_replaced_dump_is_not_read() {
+        let dump = SysExDump::store(&[1, 2, 3]).expect("the dump was not stored");
+        // The dump that was in the slot before
+        #[allow(clippy::cast_possible_truncation)]
+        let replaced = SysExDump {
+            ticket: dump.ticket.wrapping_sub(NUM_SLOTS as u32),
+        };
+        assert_eq!(replaced.read(<[u8]>::to_vec), None);
+    }
+}
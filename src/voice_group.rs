@@ -1,14 +1,66 @@
+use std::sync::Arc;
+
 use nih_plug::nih_log;
 
-use crate::consts::MAX_VOICES;
+use crate::consts::{MAX_CHANNELS, MAX_VOICES};
+use crate::lfo::{Lfo, LfoModulation, LfoScope, NUM_LFOS};
+use crate::mono::{GlideMode, HeldNote, NoteStack, VoiceMode};
+use crate::oversampling::{Decimator, Oversampling, MAX_OVERSAMPLING};
 /// A container for multiple voices. Used to achieve polyphony.
-use crate::voice_utils::{Parameters, Voice};
+use crate::voice_utils::{
+    NoteExpression, Parameters, PolyModulationTarget, UnisonVoice, Voice, VoiceNote,
+};
+use crate::wavetable::Wavetables;
+
+/// The most ended notes that are kept between two blocks. Every voice ends at most one note in a
+/// block, but the events before a block can end more.
+const MAX_ENDED_NOTES: usize = 8 * MAX_VOICES;
+
+/// The last expressions received on a channel in MPE mode. A note that starts on the channel
+/// starts with them, since MPE controllers send them ahead of the note.
+#[derive(Clone, Copy)]
+struct ChannelExpressions {
+    tuning: NoteExpression,
+    pressure: NoteExpression,
+    brightness: NoteExpression,
+}
+
+impl Default for ChannelExpressions {
+    fn default() -> Self {
+        Self {
+            tuning: NoteExpression::Tuning(0.0),
+            pressure: NoteExpression::Pressure(0.0),
+            brightness: NoteExpression::Brightness(0.0),
+        }
+    }
+}
 
 pub struct VoiceGroup<T: Voice> {
     // TODO: Identify if using an active an inactive vec is the best approach
     active_voices: Vec<Box<T>>,
     inactive_voices: Vec<Box<T>>,
     voice_timings: Vec<i32>,
+    /// Whether the sustain pedal is down
+    sustain_pedal: bool,
+    /// The notes that were released while the sustain pedal was down, by channel and note
+    sustained_notes: [[bool; 128]; 16],
+    channel_expressions: [ChannelExpressions; 16],
+    /// The keys that are held down in the mono modes
+    held_notes: NoteStack,
+    /// The note the mono voices play, or played last. New notes glide from it.
+    mono_note: Option<HeldNote>,
+    /// The number of voices that play `mono_note`. These are the first active voices.
+    mono_unison: usize,
+    /// The side the next note is placed on when the voices alternate left and right
+    pan_right: bool,
+    /// Brings the output of the voices back to the rate of the host when they are oversampled
+    decimator: Decimator,
+    /// The output of the voices at the oversampled rate
+    oversampled_buffers: Vec<Vec<f32>>,
+    /// The global LFOs, which modulate all the voices alike
+    lfos: [Lfo; NUM_LFOS],
+    /// The notes the voices stopped playing, until the plugin tells the host about them
+    ended_notes: Vec<VoiceNote>,
 }
 
 impl<T: Voice> VoiceGroup<T> {
@@ -21,6 +73,17 @@ impl<T: Voice> VoiceGroup<T> {
             active_voices,
             inactive_voices,
             voice_timings,
+            sustain_pedal: false,
+            sustained_notes: [[false; 128]; 16],
+            channel_expressions: [ChannelExpressions::default(); 16],
+            held_notes: NoteStack::new(),
+            mono_note: None,
+            mono_unison: 0,
+            pan_right: false,
+            decimator: Decimator::new(),
+            oversampled_buffers: Vec::new(),
+            lfos: std::array::from_fn(|_| Lfo::new()),
+            ended_notes: Vec::with_capacity(MAX_ENDED_NOTES),
         }
     }
 
@@ -33,9 +96,11 @@ impl<T: Voice> VoiceGroup<T> {
         self.active_voices.clear();
         self.voice_timings.clear();
         assert!(num_voices <= MAX_VOICES, "num_voices must be <= MAX_VOICES");
+        // The voices render the samples of a block at up to the highest oversampling factor
+        let max_oversampled_samples = max_samples_per_channel * MAX_OVERSAMPLING;
         for _ in 0..MAX_VOICES {
             let mut voice = T::new();
-            voice.initialize(num_channels, max_samples_per_channel);
+            voice.initialize(num_channels, max_oversampled_samples);
             self.inactive_voices.push(Box::new(voice));
         }
         for _ in 0..num_voices {
@@ -46,8 +111,26 @@ impl<T: Voice> VoiceGroup<T> {
             );
             self.voice_timings.push(0);
         }
+        let num_channels = num_channels.min(MAX_CHANNELS);
+        self.decimator.initialize(num_channels);
+        self.oversampled_buffers = vec![vec![0.0; max_oversampled_samples]; num_channels];
+    }
+
+    pub const fn oversampling(&self) -> Oversampling {
+        self.decimator.oversampling()
+    }
+
+    /// Switches the oversampling factor. The voices are silenced, since their envelopes were set
+    /// up for the previous sample rate.
+    pub fn set_oversampling(&mut self, oversampling: Oversampling, params: &Parameters) {
+        if oversampling != self.oversampling() {
+            self.decimator.set_oversampling(oversampling);
+            self.reset(params);
+        }
     }
 
+    /// Renders the voices into `audio_buffer`. `sample_rate` is the rate the voices run at, which
+    /// is the rate of the host times the oversampling factor.
     pub fn render(
         &mut self,
         audio_buffer: &mut [&mut [f32]],
@@ -58,21 +141,81 @@ impl<T: Voice> VoiceGroup<T> {
     ) {
         // Accumulate the outputs from all voices
         let block_size = block_end - block_start;
+        let oversampled_size = block_size * self.oversampling().factor();
+        let global_lfo = self
+            .lfos
+            .iter_mut()
+            .zip(&params.lfos)
+            .filter(|(_, lfo_params)| lfo_params.scope == LfoScope::Global)
+            .fold(LfoModulation::default(), |modulation, (lfo, lfo_params)| {
+                modulation + lfo.next_block(lfo_params, oversampled_size, sample_rate)
+            });
+        let params = &Parameters {
+            global_lfo,
+            ..*params
+        };
 
-        for voice in &mut self.active_voices {
+        for voice_index in 0..self.active_voices.len() {
             // Render the voice into the temporary buffer
-            voice.render(block_size, params, sample_rate);
+            if let Some(note) =
+                self.active_voices[voice_index].render(oversampled_size, params, sample_rate)
+            {
+                self.end_note(note);
+            }
+        }
+        if self.oversampling() == Oversampling::X1 {
+            // Accumulate the outputs from all voices
+            for voice in &mut self.active_voices {
+                voice.accumulate_output(audio_buffer, block_start, block_end);
+            }
+            return;
+        }
+        let num_channels = audio_buffer.len().min(self.oversampled_buffers.len());
+        // The voices accumulate into slices of the channels, which are gathered in an array so
+        // the audio thread does not allocate
+        let mut buffers = self.oversampled_buffers.iter_mut();
+        let mut channels: [&mut [f32]; MAX_CHANNELS] = std::array::from_fn(|_| {
+            buffers
+                .next()
+                .map(|buffer| &mut buffer[..oversampled_size])
+                .unwrap_or_default()
+        });
+        for channel in &mut channels {
+            channel.fill(0.0);
         }
-        // Accumulate the outputs from all voices
         for voice in &mut self.active_voices {
-            voice.accumulate_output(audio_buffer, block_start, block_end);
+            voice.accumulate_output(&mut channels[..num_channels], 0, oversampled_size);
+        }
+        for (channel, (oversampled, output)) in channels.iter_mut().zip(audio_buffer).enumerate() {
+            self.decimator.process(channel, oversampled);
+            for (sample, decimated) in output[block_start..block_end]
+                .iter_mut()
+                .zip(&**oversampled)
+            {
+                *sample += decimated;
+            }
         }
     }
     pub fn reset(&mut self, params: &Parameters) {
-        self.active_voices
-            .iter_mut()
-            .for_each(|voice| voice.reset(params));
+        for voice_index in 0..self.active_voices.len() {
+            let note = self.active_voices[voice_index].note();
+            self.active_voices[voice_index].reset(params);
+            if let Some(note) = note {
+                self.end_note(note);
+            }
+        }
         self.voice_timings.iter_mut().for_each(|timing| *timing = 0);
+        self.sustain_pedal = false;
+        self.sustained_notes = [[false; 128]; 16];
+        self.channel_expressions = [ChannelExpressions::default(); 16];
+        self.held_notes.clear();
+        self.mono_note = None;
+        self.mono_unison = 0;
+        self.pan_right = false;
+        self.decimator.reset();
+        for lfo in &mut self.lfos {
+            lfo.reset();
+        }
     }
     pub fn note_on(
         &mut self,
@@ -83,21 +226,40 @@ impl<T: Voice> VoiceGroup<T> {
         params: &Parameters,
         sample_rate: f32,
     ) {
-        // get a voice index. If get_free_voice return None, get the index of the oldest voice
-        let voice_index = self.get_free_voice().unwrap_or_else(|| {
-            nih_log!("No free voice, using the oldest one");
-            self.get_oldest_voice()
-                .unwrap_or_else(|| Self::compute_fallback_voice_id(note, channel))
-        });
-        nih_log!("voice_index chosen: {}", voice_index);
-        self.active_voices[voice_index].note_on(
-            note,
-            velocity,
-            voice_id,
-            channel,
-            params,
-            sample_rate,
-        );
+        // The note is held by its key again, so the pedal must not release it
+        self.sustained_notes[usize::from(channel)][usize::from(note)] = false;
+        self.trigger_lfos(params);
+        if params.voice_mode != VoiceMode::Poly {
+            let held_note = HeldNote {
+                voice_id,
+                channel,
+                note,
+                velocity,
+            };
+            self.mono_note_on(held_note, params, sample_rate);
+            return;
+        }
+        self.held_notes.clear();
+        self.mono_note = None;
+        self.mono_unison = 0;
+        // A note never takes more voices than there are, so its voices don't steal each other
+        let unison = self.unison(params);
+        let pan = self.next_pan(note, params);
+        for unison_index in 0..unison {
+            // get a voice index. If get_free_voice return None, get the index of the oldest voice
+            let voice_index = self.get_free_voice().unwrap_or_else(|| {
+                nih_log!("No free voice, using the oldest one");
+                self.get_oldest_voice()
+                    .unwrap_or_else(|| Self::compute_fallback_voice_id(note, channel))
+            });
+            nih_log!("voice_index chosen: {}", voice_index);
+            // The voice is the youngest now, so it is stolen last
+            self.voice_timings[voice_index] = 0;
+            let voice = &mut self.active_voices[voice_index];
+            voice.note_on(note, velocity, voice_id, channel, params, sample_rate);
+            voice.set_unison(UnisonVoice::new(unison_index, unison, pan, params));
+            self.apply_channel_expressions(voice_index, channel);
+        }
         // for all voice that are currently playing, increment the timing
         self.voice_timings
             .iter_mut()
@@ -116,11 +278,148 @@ impl<T: Voice> VoiceGroup<T> {
         params: &Parameters,
         sample_rate: f32,
     ) {
+        if self.sustain_pedal {
+            self.sustained_notes[usize::from(channel)][usize::from(note)] = true;
+            return;
+        }
+        if params.voice_mode != VoiceMode::Poly
+            && self.mono_note_off(voice_id, channel, note, params, sample_rate)
+        {
+            return;
+        }
         for voice in &mut self.active_voices {
             voice.note_off(voice_id, channel, note, params, sample_rate);
         }
     }
 
+    /// Starts a note on the global LFOs. Their delay starts over when no voice was playing.
+    fn trigger_lfos(&mut self, params: &Parameters) {
+        let first_note = !self.active_voices.iter().any(|voice| voice.is_playing());
+        for (lfo, lfo_params) in self.lfos.iter_mut().zip(&params.lfos) {
+            if lfo_params.scope == LfoScope::Global {
+                lfo.note_on(lfo_params, first_note);
+            }
+        }
+    }
+
+    /// Presses or releases the sustain pedal. While the pedal is down, released notes keep
+    /// playing. Releasing the pedal releases them.
+    pub fn set_sustain_pedal(&mut self, down: bool, params: &Parameters, sample_rate: f32) {
+        self.sustain_pedal = down;
+        if down {
+            return;
+        }
+        for channel in 0..16 {
+            for note in 0..128 {
+                if self.sustained_notes[usize::from(channel)][usize::from(note)] {
+                    self.sustained_notes[usize::from(channel)][usize::from(note)] = false;
+                    self.note_off(None, channel, note, params, sample_rate);
+                }
+            }
+        }
+    }
+
+    /// Releases all the notes, including the ones held by the sustain pedal.
+    pub fn all_notes_off(&mut self, params: &Parameters, sample_rate: f32) {
+        self.sustained_notes = [[false; 128]; 16];
+        self.held_notes.clear();
+        for voice in &mut self.active_voices {
+            voice.release(params, sample_rate);
+        }
+    }
+
+    /// Silences all the voices immediately, without waiting for their release.
+    pub fn all_sound_off(&mut self, params: &Parameters) {
+        let sustain_pedal = self.sustain_pedal;
+        self.reset(params);
+        self.sustain_pedal = sustain_pedal;
+    }
+
+    /// Bends the pitch of all the active voices by `semitones`.
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        for voice in &mut self.active_voices {
+            voice.set_pitch_bend(semitones);
+        }
+    }
+
+    /// Modulates a parameter of the voice that plays `voice_id`.
+    pub fn set_poly_modulation(
+        &mut self,
+        voice_id: i32,
+        target: PolyModulationTarget,
+        normalized_offset: f32,
+        value: f32,
+        sample_rate: f32,
+    ) {
+        for voice in &mut self.active_voices {
+            voice.set_poly_modulation(voice_id, target, normalized_offset, value, sample_rate);
+        }
+    }
+
+    /// Moves the modulated parameter of all the voices when its automation changes.
+    pub fn update_poly_modulation(
+        &mut self,
+        target: PolyModulationTarget,
+        value: &dyn Fn(f32) -> f32,
+        sample_rate: f32,
+    ) {
+        for voice in &mut self.active_voices {
+            voice.update_poly_modulation(target, value, sample_rate);
+        }
+    }
+
+    /// Applies a note expression to the voices that play the note.
+    pub fn set_note_expression(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        expression: NoteExpression,
+    ) {
+        for voice in &mut self.active_voices {
+            voice.set_note_expression(voice_id, channel, note, expression);
+        }
+    }
+
+    /// Applies an expression to the voices that play on `channel`, and to the notes that start
+    /// on it later.
+    pub fn set_channel_expression(&mut self, channel: u8, expression: NoteExpression) {
+        let expressions = &mut self.channel_expressions[usize::from(channel)];
+        match expression {
+            NoteExpression::Tuning(_) => expressions.tuning = expression,
+            NoteExpression::Pressure(_) => expressions.pressure = expression,
+            NoteExpression::Brightness(_) => expressions.brightness = expression,
+        }
+        for voice in &mut self.active_voices {
+            voice.set_channel_expression(channel, expression);
+        }
+    }
+
+    /// Forgets the expressions of all the channels, and takes them off the voices.
+    pub fn clear_channel_expressions(&mut self) {
+        let expressions = ChannelExpressions::default();
+        for channel in 0..16 {
+            for expression in [
+                expressions.tuning,
+                expressions.pressure,
+                expressions.brightness,
+            ] {
+                self.set_channel_expression(channel, expression);
+            }
+        }
+    }
+
+    /// Shares a new bank of waveforms with all the voices, including the inactive ones.
+    pub fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        for voice in self
+            .active_voices
+            .iter_mut()
+            .chain(self.inactive_voices.iter_mut())
+        {
+            voice.set_wavetables(wavetables);
+        }
+    }
+
     pub fn update_num_voices(&mut self, new_num_voices: usize) {
         assert!(
             new_num_voices <= MAX_VOICES,
@@ -140,14 +439,166 @@ impl<T: Voice> VoiceGroup<T> {
             while new_num_voices < self.active_voices.len() {
                 // get the oldest voice
                 if let Some(oldest_voice) = self.get_oldest_voice() {
-                    self.inactive_voices
-                        .push(self.active_voices.remove(oldest_voice));
+                    let voice = self.active_voices.remove(oldest_voice);
+                    if let Some(note) = voice.note() {
+                        self.end_note(note);
+                    }
+                    self.inactive_voices.push(voice);
                     self.voice_timings.remove(oldest_voice);
                 }
             }
         }
     }
 
+    /// The notes the voices stopped playing since the last call, to tell the host about.
+    pub fn take_ended_notes(&mut self) -> impl Iterator<Item = VoiceNote> + '_ {
+        self.ended_notes.drain(..)
+    }
+
+    /// Keeps a note a voice stopped playing, unless another voice still plays it, like the
+    /// other voices of its unison until they end too.
+    fn end_note(&mut self, note: VoiceNote) {
+        let is_playing = self
+            .active_voices
+            .iter()
+            .any(|voice| voice.note() == Some(note));
+        // There is room for the notes that can end in a block, so this doesn't allocate
+        if !is_playing
+            && !self.ended_notes.contains(&note)
+            && self.ended_notes.len() < MAX_ENDED_NOTES
+        {
+            self.ended_notes.push(note);
+        }
+    }
+
+    /// Plays a key in the mono modes. The mono voice moves to the key unless a held key has a
+    /// higher priority.
+    fn mono_note_on(&mut self, held_note: HeldNote, params: &Parameters, sample_rate: f32) {
+        let is_legato = !self.held_notes.is_empty();
+        self.held_notes.push(held_note);
+        if self.held_notes.select(params.note_priority) == Some(held_note) {
+            self.play_mono_note(held_note, is_legato, params, sample_rate);
+        }
+    }
+
+    /// Releases a key in the mono modes. If the mono voice played it, the voice moves back to
+    /// the held key with the highest priority, or is released when no key is held. Returns
+    /// whether the key was held in a mono mode.
+    fn mono_note_off(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        params: &Parameters,
+        sample_rate: f32,
+    ) -> bool {
+        if !self.held_notes.remove(voice_id, channel, note) {
+            return false;
+        }
+        match self.held_notes.select(params.note_priority) {
+            Some(held_note) if self.mono_note != Some(held_note) => {
+                self.play_mono_note(held_note, true, params, sample_rate);
+            }
+            Some(_) => {}
+            None => {
+                if let Some(mono_note) = self.mono_note {
+                    for voice in &mut self.active_voices {
+                        voice.note_off(
+                            mono_note.voice_id,
+                            mono_note.channel,
+                            mono_note.note,
+                            params,
+                            sample_rate,
+                        );
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Moves the mono voices to `held_note`. `is_legato` is set when the voices go from one held
+    /// key to another, which keeps the envelopes going in legato mode.
+    fn play_mono_note(
+        &mut self,
+        held_note: HeldNote,
+        is_legato: bool,
+        params: &Parameters,
+        sample_rate: f32,
+    ) {
+        let unison = self.unison(params);
+        let pan = self.next_pan(held_note.note, params);
+        let glide_time_msec = if is_legato || params.glide_mode == GlideMode::Always {
+            params.glide_time_msec
+        } else {
+            0.0
+        };
+        for unison_index in 0..unison {
+            let voice = &mut self.active_voices[unison_index];
+            let previous_note = voice.note();
+            if unison_index < self.mono_unison {
+                voice.change_note(
+                    held_note,
+                    is_legato && params.voice_mode == VoiceMode::Legato,
+                    glide_time_msec,
+                    params,
+                    sample_rate,
+                );
+            } else {
+                // There is no previous note to glide from
+                voice.note_on(
+                    held_note.note,
+                    held_note.velocity,
+                    held_note.voice_id,
+                    held_note.channel,
+                    params,
+                    sample_rate,
+                );
+            }
+            voice.set_unison(UnisonVoice::new(unison_index, unison, pan, params));
+            self.apply_channel_expressions(unison_index, held_note.channel);
+            // The voice moved on from the previous key
+            if let Some(note) = previous_note {
+                self.end_note(note);
+            }
+        }
+        // The unison got smaller since the last note
+        let mono_unison = self.mono_unison.min(self.active_voices.len());
+        if mono_unison > unison {
+            for voice in &mut self.active_voices[unison..mono_unison] {
+                voice.release(params, sample_rate);
+            }
+        }
+        self.mono_note = Some(held_note);
+        self.mono_unison = unison;
+    }
+
+    /// The pan of a new note.
+    fn next_pan(&mut self, note: u8, params: &Parameters) -> f32 {
+        let pan = params
+            .voice_pan_mode
+            .pan(note, self.pan_right, params.voice_pan_width);
+        self.pan_right = !self.pan_right;
+        pan
+    }
+
+    /// The number of voices that play a note.
+    fn unison(&self, params: &Parameters) -> usize {
+        params.unison.clamp(1, self.active_voices.len())
+    }
+
+    /// Starts the voice at `voice_index` with the last expressions of the channel of its note.
+    fn apply_channel_expressions(&mut self, voice_index: usize, channel: u8) {
+        let expressions = self.channel_expressions[usize::from(channel)];
+        for expression in [
+            expressions.tuning,
+            expressions.pressure,
+            expressions.brightness,
+        ] {
+            self.active_voices[voice_index].set_channel_expression(channel, expression);
+        }
+    }
+
     fn get_oldest_voice(&self) -> Option<usize> {
         self.voice_timings
             .iter()
@@ -171,6 +622,8 @@ impl<T: Voice> VoiceGroup<T> {
 // setup tests
 #[cfg(test)]
 mod tests {
+    use crate::lfo::{LfoParameters, LfoWaveform};
+    use crate::mono::NotePriority;
     use crate::sin_voice::SinVoice;
     use approx::assert_relative_eq;
 
@@ -192,6 +645,18 @@ mod tests {
         assert_eq!(voice_group.get_oldest_voice(), None);
     }
 
+    #[test]
+    fn test_clear_channel_expressions() {
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(4, 2, 1024);
+        voice_group.set_channel_expression(3, NoteExpression::Tuning(2.0));
+        voice_group.set_channel_expression(3, NoteExpression::Pressure(0.5));
+        voice_group.clear_channel_expressions();
+        let expressions = voice_group.channel_expressions[3];
+        assert_eq!(expressions.tuning, NoteExpression::Tuning(0.0));
+        assert_eq!(expressions.pressure, NoteExpression::Pressure(0.0));
+    }
+
     #[test]
     fn test_update_num_voices() {
         let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
@@ -220,4 +685,298 @@ mod tests {
         assert_relative_eq!(audio_buffer_slices[0][0], 0.0);
         assert_relative_eq!(audio_buffer_slices[1][0], 0.0);
     }
+
+    /// Renders a block that is long enough for the short release of `short_release_params`
+    fn render_block(voice_group: &mut VoiceGroup<SinVoice>, params: &Parameters) {
+        let mut audio_buffer = [vec![0.0; 1024], vec![0.0; 1024]];
+        let audio_buffer_slices: &mut [&mut [f32]] = &mut audio_buffer
+            .iter_mut()
+            .map(Vec::as_mut_slice)
+            .collect::<Vec<_>>();
+        voice_group.render(audio_buffer_slices, params, 44100.0, 0, 1024);
+    }
+
+    fn short_release_params() -> Parameters {
+        let mut params = Parameters::default();
+        params.eg_params.attack_time_msec = 1.0;
+        params.eg_params.release_time_msec = 1.0;
+        params
+    }
+
+    #[test]
+    fn test_ended_notes_are_reported_once() {
+        let mut params = short_release_params();
+        params.unison = 2;
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(2, 2, 1024);
+        voice_group.note_on(60, 1.0, Some(1), 0, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert_eq!(voice_group.take_ended_notes().count(), 0);
+        // Both voices of the unison end, but the host only hears about the note once
+        voice_group.note_off(Some(1), 0, 60, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        let note_60 = VoiceNote {
+            voice_id: Some(1),
+            channel: 0,
+            note: 60,
+        };
+        assert_eq!(
+            voice_group.take_ended_notes().collect::<Vec<_>>(),
+            [note_60]
+        );
+        assert_eq!(voice_group.take_ended_notes().count(), 0);
+
+        // A stolen note ends with the steal, and silenced notes at once
+        params.unison = 1;
+        voice_group.update_num_voices(1);
+        voice_group.note_on(62, 1.0, Some(2), 0, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        voice_group.note_on(64, 1.0, Some(3), 0, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        let note_62 = VoiceNote {
+            voice_id: Some(2),
+            channel: 0,
+            note: 62,
+        };
+        assert_eq!(
+            voice_group.take_ended_notes().collect::<Vec<_>>(),
+            [note_62]
+        );
+        voice_group.all_sound_off(&params);
+        let note_64 = VoiceNote {
+            voice_id: Some(3),
+            channel: 0,
+            note: 64,
+        };
+        assert_eq!(
+            voice_group.take_ended_notes().collect::<Vec<_>>(),
+            [note_64]
+        );
+    }
+
+    #[test]
+    fn test_sustain_pedal_holds_released_notes() {
+        let params = short_release_params();
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(1, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.set_sustain_pedal(true, &params, 44100.0);
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(voice_group.active_voices[0].is_playing());
+
+        voice_group.set_sustain_pedal(false, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(!voice_group.active_voices[0].is_playing());
+    }
+
+    #[test]
+    fn test_sustain_pedal_keeps_struck_again_notes() {
+        let params = short_release_params();
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(2, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.set_sustain_pedal(true, &params, 44100.0);
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        // The key is held down again when the pedal comes up
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.set_sustain_pedal(false, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(voice_group
+            .active_voices
+            .iter()
+            .all(|voice| voice.is_playing()));
+    }
+
+    #[test]
+    fn test_all_notes_off_releases_sustained_notes() {
+        let params = short_release_params();
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(2, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.note_on(64, 1.0, None, 0, &params, 44100.0);
+        voice_group.set_sustain_pedal(true, &params, 44100.0);
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        voice_group.all_notes_off(&params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(!voice_group
+            .active_voices
+            .iter()
+            .any(|voice| voice.is_playing()));
+    }
+
+    fn mono_params(voice_mode: VoiceMode, note_priority: NotePriority) -> Parameters {
+        Parameters {
+            voice_mode,
+            note_priority,
+            ..short_release_params()
+        }
+    }
+
+    #[test]
+    fn test_mono_plays_one_voice() {
+        let params = mono_params(VoiceMode::Mono, NotePriority::Last);
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(4, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.note_on(64, 1.0, None, 0, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert_eq!(
+            voice_group
+                .active_voices
+                .iter()
+                .filter(|voice| voice.is_playing())
+                .count(),
+            1
+        );
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(64)
+        );
+        // Releasing the last key goes back to the one that is still held
+        voice_group.note_off(None, 0, 64, &params, 44100.0);
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(60)
+        );
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(!voice_group.active_voices[0].is_playing());
+    }
+
+    #[test]
+    fn test_mono_low_note_priority() {
+        let params = Parameters {
+            glide_time_msec: 10.0,
+            glide_mode: GlideMode::Legato,
+            ..mono_params(VoiceMode::Legato, NotePriority::Low)
+        };
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(4, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.note_on(64, 1.0, None, 0, &params, 44100.0);
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(60)
+        );
+        voice_group.note_on(55, 1.0, None, 0, &params, 44100.0);
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(55)
+        );
+        // Releasing a key that is not played changes nothing
+        voice_group.note_off(None, 0, 64, &params, 44100.0);
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(55)
+        );
+        voice_group.note_off(None, 0, 55, &params, 44100.0);
+        assert_eq!(
+            voice_group.mono_note.map(|held_note| held_note.note),
+            Some(60)
+        );
+        render_block(&mut voice_group, &params);
+        assert!(voice_group.active_voices[0].is_playing());
+    }
+
+    #[test]
+    fn test_unison_takes_voices_per_note() {
+        let params = Parameters {
+            unison: 2,
+            ..short_release_params()
+        };
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(4, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        voice_group.note_on(64, 1.0, None, 0, &params, 44100.0);
+        assert!(voice_group
+            .active_voices
+            .iter()
+            .all(|voice| voice.is_playing()));
+        assert_eq!(voice_group.voice_timings, vec![2, 2, 1, 1]);
+        // A third note steals both voices of the oldest note
+        voice_group.note_on(67, 1.0, None, 0, &params, 44100.0);
+        assert_eq!(voice_group.voice_timings, vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_unison_is_limited_to_the_voices() {
+        let params = Parameters {
+            unison: 8,
+            ..short_release_params()
+        };
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(2, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        assert_eq!(voice_group.voice_timings, vec![1, 1]);
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        assert!(!voice_group
+            .active_voices
+            .iter()
+            .any(|voice| voice.is_playing()));
+    }
+
+    #[test]
+    fn test_oversampled_render_keeps_pitch_and_level() {
+        fn render(oversampling: Oversampling) -> Vec<f32> {
+            let params = short_release_params();
+            #[allow(clippy::cast_precision_loss)]
+            let sample_rate = 44100.0 * oversampling.factor() as f32;
+            let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+            voice_group.initialize(1, 2, 1024);
+            voice_group.set_oversampling(oversampling, &params);
+            voice_group.note_on(69, 1.0, None, 0, &params, sample_rate);
+            let mut audio_buffer = [vec![0.0; 1024], vec![0.0; 1024]];
+            let audio_buffer_slices: &mut [&mut [f32]] = &mut audio_buffer
+                .iter_mut()
+                .map(Vec::as_mut_slice)
+                .collect::<Vec<_>>();
+            voice_group.render(audio_buffer_slices, &params, sample_rate, 0, 1024);
+            audio_buffer[1][512..].to_vec()
+        }
+        let zero_crossings = |output: &[f32]| {
+            output
+                .windows(2)
+                .filter(|pair| pair[0].signum() != pair[1].signum())
+                .count()
+        };
+        let peak = |output: &[f32]| output.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        let plain = render(Oversampling::X1);
+        for oversampling in [Oversampling::X2, Oversampling::X8] {
+            let oversampled = render(oversampling);
+            assert!(zero_crossings(&oversampled).abs_diff(zero_crossings(&plain)) <= 1);
+            assert_relative_eq!(peak(&oversampled), peak(&plain), epsilon = 0.01);
+        }
+    }
+
+    #[test]
+    fn test_global_lfo_delay_starts_with_the_first_note() {
+        let mut params = short_release_params();
+        params.lfos[0] = LfoParameters {
+            waveform: LfoWaveform::Square,
+            rate_hz: 0.1,
+            delay_msec: 10.0,
+            pitch_depth: 1.0,
+            ..Default::default()
+        };
+        let mut voice_group: VoiceGroup<SinVoice> = VoiceGroup::new();
+        voice_group.initialize(2, 2, 1024);
+        voice_group.note_on(60, 1.0, None, 0, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        // The delay is over, and a second note keeps the LFO going
+        voice_group.note_on(64, 1.0, None, 0, &params, 44100.0);
+        let lfo_pitch = |voice_group: &mut VoiceGroup<SinVoice>| {
+            voice_group.lfos[0]
+                .next_block(&params.lfos[0], 1, 44100.0)
+                .pitch
+        };
+        assert_relative_eq!(lfo_pitch(&mut voice_group), 1.0);
+        // Once all the notes are over, the next one starts the delay again
+        voice_group.note_off(None, 0, 60, &params, 44100.0);
+        voice_group.note_off(None, 0, 64, &params, 44100.0);
+        render_block(&mut voice_group, &params);
+        voice_group.note_on(67, 1.0, None, 0, &params, 44100.0);
+        assert_relative_eq!(lfo_pitch(&mut voice_group), 0.0);
+    }
 }
@@ -1,30 +1,244 @@
+use std::sync::Arc;
+
+use crate::consts::NUM_OPERATORS;
+use crate::filter::FilterParameters;
+use crate::fm_algorithm::FmAlgorithm;
+use crate::fm_core::VelocityCurve;
+use crate::key_scaling::KeyScaling;
+use crate::lfo::{LfoModulation, LfoParameters, NUM_LFOS};
 use crate::linear_eg::EGParameters;
-#[derive(Default)]
-/// Ratio is the ratio of the carrier frequency to the modulator frequency.
-/// Index is the value that we multiply the output of the modulator by.
-#[allow(clippy::struct_field_names)]
+use crate::mono::{GlideMode, HeldNote, NotePriority, VoiceMode};
+use crate::pan::VoicePanMode;
+use crate::wavetable::{Interpolation, TableSize, Waveform, Wavetables};
+/// The ratio of the operator frequency to the note frequency is `coarse * (1 + fine)`, detuned by
+/// `detune` cents.
+/// Index is the value that we multiply the output of the operator by when it modulates another operator.
+#[derive(Default, Clone, Copy)]
+pub struct OperatorParameters {
+    /// The waveform of the operator's oscillator.
+    pub waveform: Waveform,
+    pub coarse: f32,
+    pub fine: f32,
+    pub detune: f32,
+    /// The frequency of the operator in Hz when it ignores the played note, `None` when it
+    /// follows the note.
+    pub fixed_frequency: Option<f32>,
+    pub index: f32,
+    /// How much the mod wheel adds to the index, and to the routes of the modulation matrix that
+    /// are on, when it is all the way up.
+    pub mod_wheel_index: f32,
+    /// How much the note velocity affects the output level of the operator, from 0 to 1. On a
+    /// modulator this changes the brightness of the sound.
+    pub velocity_sensitivity: f32,
+    pub velocity_curve: VelocityCurve,
+    /// How the output level of the operator changes with the played key.
+    pub key_scaling: KeyScaling,
+    /// How much of the output of the operator is mixed into the output of the voice when the
+    /// operator is a carrier.
+    pub mix: f32,
+    /// Where a carrier is placed in the stereo field, from -1 (left) to 1 (right).
+    pub pan: f32,
+    /// How much the amplitude modulation of the LFOs lowers the level of the operator, from 0
+    /// to 1, like the AMS of the DX7.
+    pub amp_mod_sensitivity: f32,
+    /// The modulation matrix row of this operator: how much this operator phase modulates each
+    /// operator, itself included. This is added to the routes of the algorithm.
+    pub modulation: [f32; NUM_OPERATORS],
+    /// The depth of the operator's self modulation in cycles.
+    pub feedback: f32,
+    /// The envelope that shapes the output of the operator.
+    pub eg_params: EGParameters,
+}
+
+impl OperatorParameters {
+    /// The index of the operator when it modulates another operator, with the mod wheel at
+    /// `mod_wheel`.
+    pub const fn modulation_index(&self, mod_wheel: f32) -> f32 {
+        self.mod_wheel_index.mul_add(mod_wheel, self.index)
+    }
+
+    /// The depth of the modulation matrix route from the operator into operator
+    /// `carrier_index`, with the mod wheel at `mod_wheel`. A route that is off stays off.
+    pub fn route_depth(&self, carrier_index: usize, mod_wheel: f32) -> f32 {
+        let depth = self.modulation[carrier_index];
+        if depth == 0.0 {
+            return 0.0;
+        }
+        self.mod_wheel_index.mul_add(mod_wheel, depth)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
 pub struct FmParams {
-    pub op_a_ratio: f32,
-    pub op_b_ratio: f32,
-    pub op_c_ratio: f32,
-    pub op_d_ratio: f32,
-
-    pub op_a_index: f32,
-    pub op_b_index: f32,
-    pub op_c_index: f32,
-    pub op_d_index: f32,
-    /// How much of the output of each operator is mixed into the output of the voice.
-    pub op_a_mix: f32,
-    pub op_b_mix: f32,
-    pub op_c_mix: f32,
-    pub op_d_mix: f32,
+    pub algorithm: FmAlgorithm,
+    pub operators: [OperatorParameters; NUM_OPERATORS],
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy)]
 pub struct Parameters {
     pub eg_params: EGParameters,
     pub fm_params: FmParams,
+    /// The filter of the voices and its envelope
+    pub filter: FilterParameters,
+    /// The LFOs, and what the global ones do to all the voices during the block. The voice
+    /// group fills in `global_lfo` before it renders the voices.
+    pub lfos: [LfoParameters; NUM_LFOS],
+    pub global_lfo: LfoModulation,
+    /// The position of the mod wheel, in `[0, 1]`.
+    pub mod_wheel: f32,
+    /// The linear gain of the output of the voices.
+    pub gain: f32,
+    /// Whether the notes get voices of their own, and which note a mono voice plays
+    pub voice_mode: VoiceMode,
+    pub note_priority: NotePriority,
+    /// The time it takes the pitch of a mono voice to glide to a new note, and when it does
+    pub glide_time_msec: f32,
+    pub glide_mode: GlideMode,
+    /// The number of voices that play every note, the detune of the outermost ones in cents and
+    /// how far apart they are panned, from 0 to 1
+    pub unison: usize,
+    pub unison_detune: f32,
+    pub unison_spread: f32,
+    /// How the notes are placed in the stereo field, and how far apart, from 0 to 1
+    pub voice_pan_mode: VoicePanMode,
+    pub voice_pan_width: f32,
+    /// How accurately the oscillators compute their waveforms
+    pub interpolation: Interpolation,
+    pub table_size: TableSize,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            eg_params: EGParameters::default(),
+            fm_params: FmParams::default(),
+            filter: FilterParameters::default(),
+            lfos: [LfoParameters::default(); NUM_LFOS],
+            global_lfo: LfoModulation::default(),
+            mod_wheel: 0.0,
+            gain: 1.0,
+            voice_mode: VoiceMode::default(),
+            note_priority: NotePriority::default(),
+            glide_time_msec: 0.0,
+            glide_mode: GlideMode::default(),
+            unison: 1,
+            unison_detune: 0.0,
+            unison_spread: 0.0,
+            voice_pan_mode: VoicePanMode::default(),
+            voice_pan_width: 0.0,
+            interpolation: Interpolation::default(),
+            table_size: TableSize::default(),
+        }
+    }
+}
+
+/// The number of parameters that CLAP hosts can modulate per voice.
+pub const NUM_POLY_MODULATION_TARGETS: usize = 1 + 3 * NUM_OPERATORS;
+
+/// A parameter that CLAP hosts can modulate per voice. The ID of a target is the
+/// `poly_modulation_id` of its parameter.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PolyModulationTarget {
+    Gain,
+    /// The index of an operator
+    Index(usize),
+    /// The fine ratio of an operator
+    Fine(usize),
+    /// The mix of an operator
+    Mix(usize),
+}
+
+impl PolyModulationTarget {
+    /// The ID of the target, in `0..NUM_POLY_MODULATION_TARGETS`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn id(self) -> u32 {
+        (match self {
+            Self::Gain => 0,
+            Self::Index(operator) => 1 + operator,
+            Self::Fine(operator) => 1 + NUM_OPERATORS + operator,
+            Self::Mix(operator) => 1 + 2 * NUM_OPERATORS + operator,
+        }) as u32
+    }
+
+    pub const fn from_id(id: u32) -> Option<Self> {
+        let id = id as usize;
+        if id == 0 {
+            Some(Self::Gain)
+        } else if id <= NUM_OPERATORS {
+            Some(Self::Index(id - 1))
+        } else if id <= 2 * NUM_OPERATORS {
+            Some(Self::Fine(id - 1 - NUM_OPERATORS))
+        } else if id <= 3 * NUM_OPERATORS {
+            Some(Self::Mix(id - 1 - 2 * NUM_OPERATORS))
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the value of the target in `params`.
+    pub fn apply(self, params: &mut Parameters, value: f32) {
+        match self {
+            Self::Gain => params.gain = value,
+            Self::Index(operator) => params.fm_params.operators[operator].index = value,
+            Self::Fine(operator) => params.fm_params.operators[operator].fine = value,
+            Self::Mix(operator) => params.fm_params.operators[operator].mix = value,
+        }
+    }
+}
+
+/// A per-note expression sent by the host, like CLAP note expressions or MPE.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NoteExpression {
+    /// A pitch offset in semitones, on top of the pitch bend
+    Tuning(f32),
+    /// The pressure on the key, in `[0, 1]`. It drives the index like the mod wheel.
+    Pressure(f32),
+    /// The brightness of the note, in `[0, 1]`. It drives the index like the mod wheel.
+    Brightness(f32),
+}
+
+/// The place of a voice among the voices that play a note in unison, and in the stereo field.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UnisonVoice {
+    /// The detune of the voice in cents
+    pub detune: f32,
+    /// The position of the voice in the stereo field, from -1 (left) to 1 (right)
+    pub pan: f32,
+    /// The gain of the voice, so the copies together are about as loud as a single voice
+    pub gain: f32,
 }
+
+impl Default for UnisonVoice {
+    /// A note played by a single voice
+    fn default() -> Self {
+        Self {
+            detune: 0.0,
+            pan: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl UnisonVoice {
+    /// The voice at `index` of the `count` voices of a note. The voices are spread evenly and
+    /// symmetrically around the note and around `pan`, the pan of the note.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(index: usize, count: usize, pan: f32, params: &Parameters) -> Self {
+        if count <= 1 {
+            return Self {
+                pan,
+                ..Self::default()
+            };
+        }
+        let position = (2 * index) as f32 / (count - 1) as f32 - 1.0;
+        Self {
+            detune: position * params.unison_detune,
+            pan: position.mul_add(params.unison_spread, pan),
+            gain: (count as f32).sqrt().recip(),
+        }
+    }
+}
+
 /// This stores Midi information.
 #[derive(Debug, PartialEq, Clone)]
 pub struct MidiEvent {
@@ -41,10 +255,26 @@ pub struct MidiEvent {
     pub velocity: f32,
 }
 
+/// A note as the host knows it. The host is told when the voices stop playing a note, since it
+/// may modulate their parameters by its voice ID until then.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VoiceNote {
+    pub voice_id: Option<i32>,
+    pub channel: u8,
+    pub note: u8,
+}
+
 pub trait Voice {
     fn new() -> Self;
     fn initialize(&mut self, num_channels: usize, max_samples_per_channel: usize);
-    fn render(&mut self, num_samples_to_process: usize, params: &Parameters, sample_rate: f32);
+    /// Renders the next samples of the voice. Returns the note the voice stopped playing, if its
+    /// release or the steal of the voice ended during them.
+    fn render(
+        &mut self,
+        num_samples_to_process: usize,
+        params: &Parameters,
+        sample_rate: f32,
+    ) -> Option<VoiceNote>;
     fn reset(&mut self, params: &Parameters);
     fn note_on(
         &mut self,
@@ -64,6 +294,58 @@ pub trait Voice {
         sample_rate: f32,
     );
     fn is_playing(&self) -> bool;
+    /// The note the voice plays until its release is done, if any. A voice that is being stolen
+    /// still plays the stolen note.
+    fn note(&self) -> Option<VoiceNote>;
+    /// Moves the voice to another note without stealing it, like the mono modes do. Unless
+    /// `legato` is set, the envelopes start again from where they are. The pitch glides from the
+    /// previous note over `glide_time_msec`.
+    fn change_note(
+        &mut self,
+        held_note: HeldNote,
+        legato: bool,
+        glide_time_msec: f32,
+        params: &Parameters,
+        sample_rate: f32,
+    );
+    /// Releases the note the voice is playing, whichever it is.
+    fn release(&mut self, params: &Parameters, sample_rate: f32);
+    /// Bends the pitch of the voice by `semitones`, until the bend is changed again.
+    fn set_pitch_bend(&mut self, semitones: f32);
+    /// Shares a new bank of waveforms with the voice. The previous bank may be dropped, so on the
+    /// audio thread it has to be kept alive elsewhere until the background thread drops it.
+    fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>);
+    /// Places the note the voice just started among the voices that play it in unison.
+    fn set_unison(&mut self, unison_voice: UnisonVoice);
+    /// Modulates a parameter of the voice if the voice plays `voice_id`. `value` is the modulated
+    /// value of the parameter, and `normalized_offset` is kept to follow the automation of the
+    /// parameter.
+    fn set_poly_modulation(
+        &mut self,
+        voice_id: i32,
+        target: PolyModulationTarget,
+        normalized_offset: f32,
+        value: f32,
+        sample_rate: f32,
+    );
+    /// Moves a modulated parameter when its automation changes. `value` converts the normalized
+    /// offset of the voice to the new modulated value.
+    fn update_poly_modulation(
+        &mut self,
+        target: PolyModulationTarget,
+        value: &dyn Fn(f32) -> f32,
+        sample_rate: f32,
+    );
+    /// Applies an expression to the note the voice plays if it is on `channel`, like MPE does.
+    fn set_channel_expression(&mut self, channel: u8, expression: NoteExpression);
+    /// Applies a note expression if the voice plays the note.
+    fn set_note_expression(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        expression: NoteExpression,
+    );
     fn accumulate_output(
         &mut self,
         audio_buffer: &mut [&mut [f32]],
@@ -71,3 +353,31 @@ pub trait Voice {
         block_end: usize,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_unison_voices_are_symmetric() {
+        let params = Parameters {
+            unison_detune: 20.0,
+            unison_spread: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(UnisonVoice::new(0, 1, 0.0, &params), UnisonVoice::default());
+        let voices: Vec<_> = (0..3)
+            .map(|index| UnisonVoice::new(index, 3, 0.25, &params))
+            .collect();
+        assert_relative_eq!(voices[0].detune, -20.0);
+        assert_relative_eq!(voices[1].detune, 0.0);
+        assert_relative_eq!(voices[2].detune, 20.0);
+        assert_relative_eq!(voices[0].pan, -0.25);
+        assert_relative_eq!(voices[1].pan, 0.25);
+        assert_relative_eq!(voices[2].pan, 0.75);
+        // Together the voices are as loud as one
+        let power: f32 = voices.iter().map(|voice| voice.gain * voice.gain).sum();
+        assert_relative_eq!(power, 1.0, epsilon = 1e-6);
+    }
+}
@@ -0,0 +1,695 @@
+use std::f32::consts::PI;
+use std::sync::{Arc, LazyLock};
+
+use nih_plug::{nih_warn, prelude::Enum};
+
+use crate::consts::MAX_TABLE_SIZE;
+
+/// The number of wavetables that can be loaded by the user.
+pub const NUM_USER_WAVETABLES: usize = 4;
+
+/// The number of octaves the derived square falls per cycle. The OPL3 attenuates it by another
+/// 1/32 octave at every sample of its 1024 sample cycle.
+const DERIVED_SQUARE_DECAY: f32 = 32.0;
+
+#[inline]
+fn linear_interpolation(value1: f32, value2: f32, fraction: f32) -> f32 {
+    value1.mul_add(1.0 - fraction, value2 * fraction)
+}
+
+/// Interpolates between `value1` and `value2` with a Catmull-Rom spline through the values
+/// around them.
+#[inline]
+fn cubic_interpolation(value0: f32, value1: f32, value2: f32, value3: f32, fraction: f32) -> f32 {
+    let c1 = 0.5 * (value2 - value0);
+    let c2 = 2.5f32.mul_add(-value1, value0) + 2.0f32.mul_add(value2, -0.5 * value3);
+    let c3 = 0.5f32.mul_add(value3 - value0, 1.5 * (value1 - value2));
+    c3.mul_add(fraction, c2)
+        .mul_add(fraction, c1)
+        .mul_add(fraction, value1)
+}
+
+/// `sin(2 * PI * phase)`.
+fn exact_sine(phase: f32) -> f32 {
+    (2.0 * PI * phase).sin()
+}
+
+/// `sin(2 * PI * phase)` from the Taylor series up to the 9th power, after folding the phase
+/// into the quarter cycle around 0. The error is below 4e-6 (-108 dB).
+fn polynomial_sine(phase: f32) -> f32 {
+    let phase = phase - phase.round();
+    let phase = if phase > 0.25 {
+        0.5 - phase
+    } else if phase < -0.25 {
+        -0.5 - phase
+    } else {
+        phase
+    };
+    let x = 2.0 * PI * phase;
+    let x2 = x * x;
+    let series = x2
+        .mul_add(1.0 / 362_880.0, -1.0 / 5040.0)
+        .mul_add(x2, 1.0 / 120.0)
+        .mul_add(x2, -1.0 / 6.0)
+        .mul_add(x2, 1.0);
+    x * series
+}
+
+/// The waveform of an operator. Besides the sine, these are the shapes of the OPL chips and the
+/// TX81Z, which are made of pieces of a sine or of a squared sine, and the wavetables loaded by
+/// the user.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Waveform {
+    #[default]
+    #[id = "sine"]
+    #[name = "Sine"]
+    Sine,
+    /// The positive half of the sine, silent for the other half
+    #[id = "half_sine"]
+    #[name = "Half Sine"]
+    HalfSine,
+    /// Both halves of the sine are positive
+    #[id = "absolute_sine"]
+    #[name = "Absolute Sine"]
+    AbsoluteSine,
+    /// The rising quarters of the absolute sine, silent in between
+    #[id = "pulse_sine"]
+    #[name = "Pulse Sine"]
+    PulseSine,
+    /// A sine at twice the frequency for the first half of the cycle, silent for the second
+    #[id = "alternating_sine"]
+    #[name = "Alternating Sine"]
+    AlternatingSine,
+    /// The alternating sine with both of its halves positive
+    #[id = "camel_sine"]
+    #[name = "Camel Sine"]
+    CamelSine,
+    #[id = "square"]
+    #[name = "Square"]
+    Square,
+    /// The sine multiplied by its absolute value, which keeps its sign (TX81Z W2)
+    #[id = "squared_sine"]
+    #[name = "Squared Sine"]
+    SquaredSine,
+    /// The positive half of the squared sine (TX81Z W4)
+    #[id = "half_squared_sine"]
+    #[name = "Half Squared Sine"]
+    HalfSquaredSine,
+    /// The squared sine at twice the frequency for the first half of the cycle (TX81Z W6)
+    #[id = "alternating_squared_sine"]
+    #[name = "Alternating Squared Sine"]
+    AlternatingSquaredSine,
+    /// The alternating squared sine with both of its halves positive (TX81Z W8)
+    #[id = "camel_squared_sine"]
+    #[name = "Camel Squared Sine"]
+    CamelSquaredSine,
+    /// An exponential decay from 1 over the first half, mirrored below 0 over the second. The
+    /// OPL3 builds it from the exponent table of its square, and it sounds like a saw (OPL3 wave 7)
+    #[id = "derived_square"]
+    #[name = "Derived Square"]
+    DerivedSquare,
+    #[id = "user_1"]
+    #[name = "User 1"]
+    User1,
+    #[id = "user_2"]
+    #[name = "User 2"]
+    User2,
+    #[id = "user_3"]
+    #[name = "User 3"]
+    User3,
+    #[id = "user_4"]
+    #[name = "User 4"]
+    User4,
+}
+
+impl Waveform {
+    const ALL: [Self; 12 + NUM_USER_WAVETABLES] = [
+        Self::Sine,
+        Self::HalfSine,
+        Self::AbsoluteSine,
+        Self::PulseSine,
+        Self::AlternatingSine,
+        Self::CamelSine,
+        Self::Square,
+        Self::SquaredSine,
+        Self::HalfSquaredSine,
+        Self::AlternatingSquaredSine,
+        Self::CamelSquaredSine,
+        Self::DerivedSquare,
+        Self::User1,
+        Self::User2,
+        Self::User3,
+        Self::User4,
+    ];
+
+    /// The index of the table of the waveform in `Wavetables`.
+    const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The value of a built-in waveform at `phase`, in `[0, 1)`, made of the sines computed by
+    /// `sine`. The user waveforms only exist as tables.
+    fn value(self, phase: f32, sine: fn(f32) -> f32) -> Option<f32> {
+        let squared_sine = |phase| sine(phase) * sine(phase).abs();
+        let value = match self {
+            Self::Sine => sine(phase),
+            Self::HalfSine => sine(phase).max(0.0),
+            Self::AbsoluteSine => sine(phase).abs(),
+            Self::PulseSine => {
+                if phase % 0.5 < 0.25 {
+                    sine(phase).abs()
+                } else {
+                    0.0
+                }
+            }
+            Self::AlternatingSine => {
+                if phase < 0.5 {
+                    sine(2.0 * phase)
+                } else {
+                    0.0
+                }
+            }
+            Self::CamelSine => {
+                if phase < 0.5 {
+                    sine(2.0 * phase).abs()
+                } else {
+                    0.0
+                }
+            }
+            Self::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Self::SquaredSine => squared_sine(phase),
+            Self::HalfSquaredSine => squared_sine(phase).max(0.0),
+            Self::AlternatingSquaredSine => {
+                if phase < 0.5 {
+                    squared_sine(2.0 * phase)
+                } else {
+                    0.0
+                }
+            }
+            Self::CamelSquaredSine => {
+                if phase < 0.5 {
+                    squared_sine(2.0 * phase).abs()
+                } else {
+                    0.0
+                }
+            }
+            Self::DerivedSquare => {
+                if phase < 0.5 {
+                    (-DERIVED_SQUARE_DECAY * phase).exp2()
+                } else {
+                    -(-DERIVED_SQUARE_DECAY * (1.0 - phase)).exp2()
+                }
+            }
+            Self::User1 | Self::User2 | Self::User3 | Self::User4 => return None,
+        };
+        Some(value)
+    }
+
+    /// The waveform a user wavetable is loaded into.
+    const fn user(slot: usize) -> Self {
+        match slot {
+            0 => Self::User1,
+            1 => Self::User2,
+            2 => Self::User3,
+            _ => Self::User4,
+        }
+    }
+}
+
+/// How the oscillator computes the waveform between the samples of its table. The better ones
+/// lower the noise floor, which matters most under deep phase modulation, and cost more CPU.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Interpolation {
+    #[default]
+    #[id = "linear"]
+    #[name = "Linear"]
+    Linear,
+    /// A Catmull-Rom spline through four samples
+    #[id = "cubic"]
+    #[name = "Cubic"]
+    Cubic,
+    /// The built-in waveforms are computed with a polynomial sine instead of the table. The user
+    /// waveforms use cubic interpolation.
+    #[id = "polynomial"]
+    #[name = "Polynomial"]
+    Polynomial,
+    /// The built-in waveforms are computed with `sin`. The user waveforms use cubic
+    /// interpolation.
+    #[id = "exact"]
+    #[name = "Exact"]
+    Exact,
+}
+
+/// The number of samples of a cycle the oscillator reads. Smaller tables are noisier.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TableSize {
+    #[id = "256"]
+    #[name = "256"]
+    Size256,
+    #[default]
+    #[id = "1024"]
+    #[name = "1024"]
+    Size1024,
+    #[id = "4096"]
+    #[name = "4096"]
+    Size4096,
+    #[id = "16384"]
+    #[name = "16384"]
+    Size16384,
+}
+
+impl TableSize {
+    pub const fn len(self) -> usize {
+        match self {
+            Self::Size256 => 256,
+            Self::Size1024 => 1024,
+            Self::Size4096 => 4096,
+            Self::Size16384 => MAX_TABLE_SIZE,
+        }
+    }
+}
+
+/// The tables of all the waveforms. The bank is built when the plugin is initialized or a user
+/// wavetable is loaded, and shared by all the oscillators, so switching the waveform of an
+/// operator never allocates.
+///
+/// The tables hold `MAX_TABLE_SIZE` samples. A smaller table size reads every n-th sample, which
+/// is exactly the smaller table, so the size can change while playing too.
+#[derive(Debug, PartialEq)]
+pub struct Wavetables {
+    samples: Box<[f32]>,
+}
+
+/// The built-in waveforms, used until the plugin is initialized.
+static DEFAULT_WAVETABLES: LazyLock<Arc<Wavetables>> =
+    LazyLock::new(|| Arc::new(Wavetables::new(&[])));
+
+impl Wavetables {
+    /// Builds the waveforms, with the first `NUM_USER_WAVETABLES` of `user_wavetables` loaded
+    /// into the user waveforms. A user wavetable is a single cycle of any length, which is
+    /// normalized to a peak of 1. The user waveforms are sines until they are loaded, and a
+    /// wavetable with samples that are not finite is not loaded.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(user_wavetables: &[Vec<f32>]) -> Self {
+        let mut samples = vec![0.0; MAX_TABLE_SIZE * Waveform::ALL.len()].into_boxed_slice();
+        for (table, waveform) in samples.chunks_exact_mut(MAX_TABLE_SIZE).zip(Waveform::ALL) {
+            for (i, value) in table.iter_mut().enumerate() {
+                let phase = i as f32 / MAX_TABLE_SIZE as f32;
+                *value = waveform
+                    .value(phase, exact_sine)
+                    .unwrap_or_else(|| exact_sine(phase));
+            }
+        }
+        for (slot, user_samples) in user_wavetables.iter().take(NUM_USER_WAVETABLES).enumerate() {
+            if user_samples.is_empty() {
+                continue;
+            }
+            if user_samples.iter().any(|sample| !sample.is_finite()) {
+                nih_warn!(
+                    "User wavetable {} has samples that are not finite, and was not loaded",
+                    slot + 1
+                );
+                continue;
+            }
+            let index = Waveform::user(slot).index();
+            let table = &mut samples[index * MAX_TABLE_SIZE..(index + 1) * MAX_TABLE_SIZE];
+            resample(user_samples, table);
+            normalize(table);
+        }
+        Self { samples }
+    }
+
+    /// The built-in waveforms, with sines for the user waveforms.
+    pub fn default_shared() -> Arc<Self> {
+        Arc::clone(&DEFAULT_WAVETABLES)
+    }
+
+    /// The table of `waveform`, with `MAX_TABLE_SIZE` samples.
+    pub fn table(&self, waveform: Waveform) -> &[f32] {
+        let start = waveform.index() * MAX_TABLE_SIZE;
+        &self.samples[start..start + MAX_TABLE_SIZE]
+    }
+}
+
+/// Scales `table` so its peak is 1. A silent table stays silent.
+fn normalize(table: &mut [f32]) {
+    let peak = table
+        .iter()
+        .fold(0.0_f32, |peak, value| peak.max(value.abs()));
+    if peak > 0.0 {
+        for value in table {
+            *value /= peak;
+        }
+    }
+}
+
+/// Stretches a single cycle of any length over `table`, with linear interpolation.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation
+)]
+fn resample(samples: &[f32], table: &mut [f32]) {
+    let table_size = table.len();
+    for (i, value) in table.iter_mut().enumerate() {
+        let position = i as f32 * samples.len() as f32 / table_size as f32;
+        let index = position as usize;
+        *value = linear_interpolation(
+            samples[index],
+            samples[(index + 1) % samples.len()],
+            position.fract(),
+        );
+    }
+}
+
+/// An oscillator that reads one of the shared wavetables.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WavetableOsc {
+    wavetables: Arc<Wavetables>,
+    waveform: Waveform,
+    interpolation: Interpolation,
+    table_size: TableSize,
+}
+
+#[allow(clippy::cast_precision_loss)]
+impl WavetableOsc {
+    /// Creates a sine oscillator that reads the built-in waveforms.
+    pub fn new() -> Self {
+        Self {
+            wavetables: Wavetables::default_shared(),
+            waveform: Waveform::Sine,
+            interpolation: Interpolation::default(),
+            table_size: TableSize::default(),
+        }
+    }
+
+    /// Replaces the bank of waveforms. This may drop the previous bank, so on the audio thread it
+    /// has to be kept alive elsewhere until the background thread drops it.
+    pub fn set_wavetables(&mut self, wavetables: &Arc<Wavetables>) {
+        self.wavetables = Arc::clone(wavetables);
+    }
+
+    /// Switches the waveform. This only picks another table of the bank.
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    /// Trades the accuracy of the oscillator against CPU.
+    pub fn set_quality(&mut self, interpolation: Interpolation, table_size: TableSize) {
+        self.interpolation = interpolation;
+        self.table_size = table_size;
+    }
+
+    /// Reads the oscillator and returns the current sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `normalized_phase_inc` - The normalized phase increment. Will be in the range [0.0, 1.0]
+    ///
+    /// # Returns
+    ///
+    /// The current sample value of the oscillator.
+    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    pub fn read_osc(&self, normalized_phase_inc: f32) -> f32 {
+        let phase = normalized_phase_inc.rem_euclid(1.0);
+        let sine: fn(f32) -> f32 = match self.interpolation {
+            Interpolation::Linear | Interpolation::Cubic => return self.read_table(phase),
+            Interpolation::Polynomial => polynomial_sine,
+            Interpolation::Exact => exact_sine,
+        };
+        self.waveform
+            .value(phase, sine)
+            .unwrap_or_else(|| self.read_table(phase))
+    }
+
+    /// Reads the table of the waveform at `phase`, in `[0, 1)`.
+    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    fn read_table(&self, phase: f32) -> f32 {
+        let table = self.wavetables.table(self.waveform);
+        let table_size = self.table_size.len();
+        let stride = MAX_TABLE_SIZE / table_size;
+        let table_index = phase * table_size as f32;
+        // The index of the sample `offset` samples after the phase, in the table of the size
+        let sample = |offset: usize| table[((table_index as usize + offset) % table_size) * stride];
+        let frac = table_index.fract();
+        match self.interpolation {
+            Interpolation::Linear => linear_interpolation(sample(0), sample(1), frac),
+            _ => cubic_interpolation(
+                sample(table_size - 1),
+                sample(0),
+                sample(1),
+                sample(2),
+                frac,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::{assert_relative_eq, assert_relative_ne};
+    use rstest::rstest;
+
+    #[test]
+    fn test_sin_vals() {
+        // Make sure the last value in the table is not 0.0 so we don't get pops
+        let wavetables = Wavetables::new(&[]);
+        assert_relative_ne!(wavetables.table(Waveform::Sine)[MAX_TABLE_SIZE - 1], 0.0);
+    }
+
+    #[test]
+    fn test_linear_interpolation() {
+        // Test case 1: value1 = 0.0, value2 = 1.0, fraction = 0.5
+        let result1 = linear_interpolation(0.0, 1.0, 0.5);
+        assert_relative_eq!(result1, 0.5);
+
+        // Test case 2: value1 = -1.0, value2 = 1.0, fraction = 0.25
+        let result2 = linear_interpolation(-1.0, 1.0, 0.25);
+        assert_relative_eq!(result2, -0.5);
+
+        // Test case 3: value1 = 10.0, value2 = 20.0, fraction = 0.75
+        let result3 = linear_interpolation(10.0, 20.0, 0.75);
+        assert_relative_eq!(result3, 17.5);
+    }
+
+    #[test]
+    fn read_osc_test() {
+        let osc = WavetableOsc::new();
+
+        // Test case 1: normalized_phase_inc = 0.0
+        let result1 = osc.read_osc(0.0);
+        assert_relative_eq!(result1, 0.0);
+
+        // Test case 2: normalized_phase_inc = 0.25
+        let result2 = osc.read_osc(0.25);
+        assert_relative_eq!(result2, 1.0);
+
+        // Test case 3: normalized_phase_inc = 0.5
+        let result3 = osc.read_osc(0.5);
+        assert_relative_eq!(result3, 0.0);
+
+        // Test case 4: normalized_phase_inc = 0.75
+        let result4 = osc.read_osc(0.75);
+        assert_relative_eq!(result4, -1.0);
+
+        // Test case 5: normalized_phase_inc = 1.0
+        let result5 = osc.read_osc(1.0);
+        assert_relative_eq!(result5, 0.0);
+    }
+
+    #[test]
+    fn test_waveforms() {
+        let mut osc = WavetableOsc::new();
+        let read =
+            |osc: &WavetableOsc| [0.125, 0.375, 0.625, 0.875].map(|phase| osc.read_osc(phase));
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        for (waveform, expected) in [
+            (Waveform::HalfSine, [half, half, 0.0, 0.0]),
+            (Waveform::AbsoluteSine, [half, half, half, half]),
+            (Waveform::PulseSine, [half, 0.0, half, 0.0]),
+            (Waveform::AlternatingSine, [1.0, -1.0, 0.0, 0.0]),
+            (Waveform::CamelSine, [1.0, 1.0, 0.0, 0.0]),
+            (Waveform::Square, [1.0, 1.0, -1.0, -1.0]),
+            (Waveform::SquaredSine, [0.5, 0.5, -0.5, -0.5]),
+            (Waveform::HalfSquaredSine, [0.5, 0.5, 0.0, 0.0]),
+            (Waveform::AlternatingSquaredSine, [1.0, -1.0, 0.0, 0.0]),
+            (Waveform::CamelSquaredSine, [1.0, 1.0, 0.0, 0.0]),
+            (Waveform::DerivedSquare, [0.0625, 0.0, 0.0, -0.0625]),
+        ] {
+            osc.set_waveform(waveform);
+            for (value, expected) in read(&osc).into_iter().zip(expected) {
+                assert_relative_eq!(value, expected, epsilon = 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_wavetable_is_resampled() {
+        // A four sample ramp, stretched over the table and normalized to a peak of 1
+        let wavetables = Arc::new(Wavetables::new(&[vec![], vec![0.0, 1.0, 2.0, 3.0]]));
+        let mut osc = WavetableOsc::new();
+        osc.set_wavetables(&wavetables);
+        // An empty wavetable leaves the sine
+        osc.set_waveform(Waveform::User1);
+        assert_relative_eq!(osc.read_osc(0.25), 1.0);
+        osc.set_waveform(Waveform::User2);
+        assert_relative_eq!(osc.read_osc(0.25), 1.0 / 3.0);
+        assert_relative_eq!(osc.read_osc(0.375), 0.5);
+        assert_relative_eq!(osc.read_osc(0.75), 1.0);
+    }
+
+    #[test]
+    fn test_user_wavetable_is_checked() {
+        let wavetables = Wavetables::new(&[
+            vec![0.0, f32::NAN, 0.5],
+            vec![0.0, f32::INFINITY],
+            vec![0.0, 0.0],
+            vec![0.0, 0.01, 0.0, -0.02],
+        ]);
+        // Samples that are not finite leave the sine
+        assert_eq!(
+            wavetables.table(Waveform::User1),
+            wavetables.table(Waveform::Sine)
+        );
+        assert_eq!(
+            wavetables.table(Waveform::User2),
+            wavetables.table(Waveform::Sine)
+        );
+        // A silent cycle stays silent, and a quiet one is brought up to a peak of 1
+        assert!(wavetables
+            .table(Waveform::User3)
+            .iter()
+            .all(|value| *value == 0.0));
+        let peak = wavetables
+            .table(Waveform::User4)
+            .iter()
+            .fold(0.0_f32, |peak, value| peak.max(value.abs()));
+        assert_relative_eq!(peak, 1.0);
+    }
+
+    /// The number of samples the quality is measured over.
+    const NUM_SAMPLES: usize = 8192;
+    /// The number of cycles of the measured sine. It is prime and not a multiple of any table
+    /// size, so every cycle reads different points of the table.
+    const CYCLES: usize = 1021;
+
+    /// Renders exactly `CYCLES` cycles of a sine over `NUM_SAMPLES` samples.
+    #[allow(clippy::cast_precision_loss)]
+    fn render_sine(interpolation: Interpolation, table_size: TableSize) -> Vec<f64> {
+        let mut osc = WavetableOsc::new();
+        osc.set_quality(interpolation, table_size);
+        (0..NUM_SAMPLES)
+            .map(|i| {
+                let phase = (i * CYCLES % NUM_SAMPLES) as f32 / NUM_SAMPLES as f32;
+                f64::from(osc.read_osc(phase))
+            })
+            .collect()
+    }
+
+    /// The power of the sine with `cycles` cycles in `output`, from a single bin of a DFT.
+    #[allow(clippy::cast_precision_loss)]
+    fn power_at(output: &[f64], cycles: usize) -> f64 {
+        let (re, im) = output
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, sample)| {
+                let angle = std::f64::consts::TAU * (i * cycles % output.len()) as f64
+                    / output.len() as f64;
+                (
+                    sample.mul_add(angle.cos(), re),
+                    sample.mul_add(angle.sin(), im),
+                )
+            });
+        2.0 * re.mul_add(re, im * im) / (output.len() * output.len()) as f64
+    }
+
+    /// The ratio of the sine to everything else in the output in dB, measured against the exact
+    /// sine.
+    #[allow(clippy::cast_precision_loss)]
+    fn snr(output: &[f64]) -> f64 {
+        let (signal, noise) =
+            output
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(signal, noise), (i, sample)| {
+                    let angle = std::f64::consts::TAU * (i * CYCLES % output.len()) as f64
+                        / output.len() as f64;
+                    let error = sample - angle.sin();
+                    (
+                        angle.sin().mul_add(angle.sin(), signal),
+                        error.mul_add(error, noise),
+                    )
+                });
+        10.0 * (signal / noise).log10()
+    }
+
+    /// The total harmonic distortion of the output in dB, from the 2nd to the 10th harmonic.
+    fn thd(output: &[f64]) -> f64 {
+        let harmonics: f64 = (2..=10)
+            .map(|harmonic| power_at(output, CYCLES * harmonic % NUM_SAMPLES))
+            .sum();
+        10.0 * (harmonics / power_at(output, CYCLES)).log10()
+    }
+
+    #[rstest]
+    #[case(Interpolation::Linear, TableSize::Size256, 80.0)]
+    #[case(Interpolation::Linear, TableSize::Size1024, 100.0)]
+    #[case(Interpolation::Linear, TableSize::Size4096, 120.0)]
+    #[case(Interpolation::Cubic, TableSize::Size256, 125.0)]
+    #[case(Interpolation::Cubic, TableSize::Size1024, 130.0)]
+    #[case(Interpolation::Polynomial, TableSize::Size256, 110.0)]
+    #[case(Interpolation::Exact, TableSize::Size256, 130.0)]
+    fn test_snr(
+        #[case] interpolation: Interpolation,
+        #[case] table_size: TableSize,
+        #[case] min_snr: f64,
+    ) {
+        let snr = snr(&render_sine(interpolation, table_size));
+        assert!(snr > min_snr, "{interpolation:?} {table_size:?}: {snr} dB");
+    }
+
+    #[rstest]
+    #[case(Interpolation::Linear, TableSize::Size1024, -135.0)]
+    #[case(Interpolation::Cubic, TableSize::Size1024, -135.0)]
+    #[case(Interpolation::Polynomial, TableSize::Size1024, -115.0)]
+    #[case(Interpolation::Exact, TableSize::Size1024, -135.0)]
+    fn test_thd(
+        #[case] interpolation: Interpolation,
+        #[case] table_size: TableSize,
+        #[case] max_thd: f64,
+    ) {
+        let thd = thd(&render_sine(interpolation, table_size));
+        assert!(thd < max_thd, "{interpolation:?} {table_size:?}: {thd} dB");
+    }
+
+    #[test]
+    fn test_quality_improves() {
+        let linear = snr(&render_sine(Interpolation::Linear, TableSize::Size1024));
+        for interpolation in [
+            Interpolation::Cubic,
+            Interpolation::Polynomial,
+            Interpolation::Exact,
+        ] {
+            assert!(snr(&render_sine(interpolation, TableSize::Size1024)) > linear);
+        }
+        assert!(snr(&render_sine(Interpolation::Linear, TableSize::Size16384)) > linear);
+    }
+
+    #[test]
+    fn test_user_wavetable_is_interpolated() {
+        // Without a built-in waveform to compute, the table is read with cubic interpolation
+        let wavetables = Arc::new(Wavetables::new(&[vec![0.0, 1.0, 2.0, 3.0]]));
+        let mut osc = WavetableOsc::new();
+        osc.set_wavetables(&wavetables);
+        osc.set_waveform(Waveform::User1);
+        osc.set_quality(Interpolation::Exact, TableSize::Size256);
+        assert_relative_eq!(osc.read_osc(0.375), 0.5, epsilon = 1e-5);
+    }
+}